@@ -0,0 +1,25 @@
+//! Feeds arbitrary text through the assembler and everything downstream of it, which must report
+//! errors rather than panic. Run with `cargo +nightly fuzz run assemble` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use lib::assembler::assemble;
+use lib::format::format_source;
+use lib::lint::{lint_program, LintConfig};
+use lib::output::build_output;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+    let assembly = assemble(source);
+    let _ = lint_program(
+        &assembly.source_lines,
+        &assembly.operations,
+        &LintConfig::default(),
+    );
+    let _ = build_output("fuzz", &assembly);
+    let _ = format_source(source);
+});
@@ -0,0 +1,366 @@
+use crate::instruction::Instruction;
+use crate::output::Launch;
+use crate::Register;
+
+/// Program Analysis
+/// ---
+/// Dataflow over the control flow graph of an assembled program, where each node is one
+/// instruction address and edges come from `control_flow`.
+///
+/// Marks every address reachable from address 0.
+pub fn reachable(program: &[Instruction]) -> Vec<bool> {
+    let successors = control_flow(program);
+    let mut seen = vec![false; program.len()];
+    let mut stack = vec![0];
+
+    while let Some(addr) = stack.pop() {
+        if addr >= program.len() || seen[addr] {
+            continue;
+        }
+        seen[addr] = true;
+        stack.extend(&successors[addr]);
+    }
+
+    seen
+}
+
+/// Reachable addresses whose execution can continue past the end of the program.
+pub fn falls_off_end(program: &[Instruction]) -> Vec<usize> {
+    let seen = reachable(program);
+    let successors = control_flow(program);
+    (0..program.len())
+        .filter(|&addr| seen[addr])
+        .filter(|&addr| successors[addr].iter().any(|&next| next >= program.len()))
+        .collect()
+}
+
+/// The successors of every address. A BRnzp only always branches where a CMP has set the
+/// flags on every path to it.
+pub fn control_flow(program: &[Instruction]) -> Vec<Vec<usize>> {
+    let flags = flags_set(program);
+    program
+        .iter()
+        .enumerate()
+        .map(|(addr, instruction)| instruction.successors(addr, flags[addr]))
+        .collect()
+}
+
+/// The predecessors of every address, given the successors of each.
+pub fn predecessors(successors: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut preds = vec![vec![]; successors.len()];
+    for (addr, nexts) in successors.iter().enumerate() {
+        for &next in nexts {
+            if next < successors.len() {
+                preds[next].push(addr);
+            }
+        }
+    }
+    preds
+}
+
+/// For each address, whether a CMP has executed on every path leading to it.
+/// Unreachable addresses are reported as set, so they do not produce findings.
+pub fn flags_set(program: &[Instruction]) -> Vec<bool> {
+    // every branch may fall through here: an edge that cannot be taken only leaves a BRnzp
+    // whose flags are set, so it never clears anything
+    let conservative: Vec<Vec<usize>> = program
+        .iter()
+        .enumerate()
+        .map(|(addr, instruction)| instruction.successors(addr, false))
+        .collect();
+    flags_set_on(&conservative, |addr| {
+        matches!(program[addr], Instruction::Cmp { .. })
+    })
+}
+
+/// `flags_set` over any control flow graph in which every branch may fall through, with
+/// `is_cmp` telling which addresses hold a CMP
+pub fn flags_set_on(successors: &[Vec<usize>], is_cmp: impl Fn(usize) -> bool) -> Vec<bool> {
+    // must-analysis: start optimistic everywhere but the entry and only ever clear
+    let mut set_in = vec![true; successors.len()];
+    if successors.is_empty() {
+        return set_in;
+    }
+    set_in[0] = false;
+
+    let preds = predecessors(successors);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for addr in 1..successors.len() {
+            let all_set = preds[addr].iter().all(|&pred| set_in[pred] || is_cmp(pred));
+            if set_in[addr] && !all_set {
+                set_in[addr] = false;
+                changed = true;
+            }
+        }
+    }
+
+    set_in
+}
+
+/// Value of a register known to constant propagation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegValue {
+    Unknown, // no path has reached this point yet
+    Const(u8),
+    Varying,
+}
+
+impl RegValue {
+    fn join(self, other: RegValue) -> RegValue {
+        match (self, other) {
+            (RegValue::Unknown, v) | (v, RegValue::Unknown) => v,
+            (RegValue::Const(a), RegValue::Const(b)) if a == b => RegValue::Const(a),
+            _ => RegValue::Varying,
+        }
+    }
+
+    pub fn as_const(self) -> Option<u8> {
+        match self {
+            RegValue::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+pub type RegState = [RegValue; 16];
+
+/// Applies one instruction to the register state, with 8-bit wrapping arithmetic like the ALU.
+pub fn transfer(instruction: &Instruction, state: &RegState) -> RegState {
+    let mut next = *state;
+    let get = |r: Register| state[r.index()];
+
+    let value = match *instruction {
+        Instruction::Const { imm, .. } => RegValue::Const(imm),
+        Instruction::Ldr { .. } => RegValue::Varying,
+        Instruction::Sub { rs, rt, .. } if rs == rt => RegValue::Const(0),
+        Instruction::Mul { rs, rt, .. }
+            if get(rs) == RegValue::Const(0) || get(rt) == RegValue::Const(0) =>
+        {
+            RegValue::Const(0)
+        }
+        Instruction::Add { rs, rt, .. }
+        | Instruction::Sub { rs, rt, .. }
+        | Instruction::Mul { rs, rt, .. }
+        | Instruction::Div { rs, rt, .. } => match (get(rs), get(rt)) {
+            (RegValue::Const(a), RegValue::Const(b)) => match instruction {
+                Instruction::Add { .. } => RegValue::Const(a.wrapping_add(b)),
+                Instruction::Sub { .. } => RegValue::Const(a.wrapping_sub(b)),
+                Instruction::Mul { .. } => RegValue::Const(a.wrapping_mul(b)),
+                _ if b == 0 => RegValue::Varying,
+                _ => RegValue::Const(a / b),
+            },
+            (RegValue::Unknown, _) | (_, RegValue::Unknown) => RegValue::Unknown,
+            _ => RegValue::Varying,
+        },
+        _ => return next,
+    };
+
+    if let Some(rd) = instruction.dest() {
+        // writes to the read-only registers have no effect
+        if !rd.is_special() {
+            next[rd.index()] = value;
+        }
+    }
+    next
+}
+
+/// The register state before each instruction, None for unreachable addresses.
+/// Registers start out Varying, as do the special registers throughout.
+pub fn constant_registers(program: &[Instruction]) -> Vec<Option<RegState>> {
+    constant_registers_with(program, |_| false)
+}
+
+/// `constant_registers` where the instructions at the addresses `opaque` tells write a value that
+/// is not known until link time, such as a CONST of a label in a relocatable object
+pub fn constant_registers_with(
+    program: &[Instruction],
+    opaque: impl Fn(usize) -> bool,
+) -> Vec<Option<RegState>> {
+    let mut states: Vec<Option<RegState>> = vec![None; program.len()];
+    if program.is_empty() {
+        return states;
+    }
+
+    states[0] = Some([RegValue::Varying; 16]);
+    let successors = control_flow(program);
+    let mut worklist = vec![0];
+
+    while let Some(addr) = worklist.pop() {
+        let Some(state) = states[addr] else { continue };
+        let mut out = transfer(&program[addr], &state);
+        if let Some(rd) = program[addr].dest().filter(|_| opaque(addr)) {
+            out[rd.index()] = RegValue::Varying;
+        }
+
+        for &next in &successors[addr] {
+            if next >= program.len() {
+                continue;
+            }
+            let joined = match states[next] {
+                Some(existing) => {
+                    let mut joined = existing;
+                    for (slot, value) in joined.iter_mut().zip(out.iter()) {
+                        *slot = slot.join(*value);
+                    }
+                    joined
+                }
+                None => out,
+            };
+            if states[next] != Some(joined) {
+                states[next] = Some(joined);
+                worklist.push(next);
+            }
+        }
+    }
+
+    states
+}
+
+/// Registers whose value may still be read after each instruction, as a bit mask by register
+/// index. Nothing is live after RET or past the end, since threads only communicate through
+/// memory.
+pub fn live_registers(program: &[Instruction]) -> Vec<u16> {
+    let bit = |r: Register| 1u16 << r.index();
+    let successors = control_flow(program);
+    let mut live_in = vec![0u16; program.len()];
+    let mut live_out = vec![0u16; program.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for addr in (0..program.len()).rev() {
+            let out = successors[addr]
+                .iter()
+                .filter(|&&next| next < program.len())
+                .fold(0, |acc, &next| acc | live_in[next]);
+
+            let defined = program[addr].dest().map_or(0, bit);
+            let used = program[addr]
+                .sources()
+                .into_iter()
+                .fold(0, |acc, r| acc | bit(r));
+            let inn = (out & !defined) | used;
+
+            if out != live_out[addr] || inn != live_in[addr] {
+                live_out[addr] = out;
+                live_in[addr] = inn;
+                changed = true;
+            }
+        }
+    }
+
+    live_out
+}
+
+/// The values a register can hold, as an inclusive range
+pub type ValueRange = (u8, u8);
+
+const ANY_VALUE: ValueRange = (0, u8::MAX);
+
+/// Times an address is revisited before the ranges there are widened to any value, so loops
+/// that keep growing a range still reach a fixpoint
+const WIDEN_AFTER: usize = 8;
+
+/// Applies one instruction to the register ranges. A result that can wrap around is any value.
+fn transfer_ranges(instruction: &Instruction, state: &[ValueRange; 16]) -> [ValueRange; 16] {
+    let mut next = *state;
+    let get = |r: Register| {
+        let (lo, hi) = state[r.index()];
+        (u32::from(lo), u32::from(hi))
+    };
+    let fits = |lo: u32, hi: u32| match hi <= u32::from(u8::MAX) {
+        true => (lo as u8, hi as u8),
+        false => ANY_VALUE,
+    };
+
+    let range = match *instruction {
+        Instruction::Const { imm, .. } => (imm, imm),
+        Instruction::Ldr { .. } => ANY_VALUE,
+        Instruction::Add { rs, rt, .. } => {
+            let ((a, b), (c, d)) = (get(rs), get(rt));
+            fits(a + c, b + d)
+        }
+        Instruction::Sub { rs, rt, .. } => {
+            let ((a, b), (c, d)) = (get(rs), get(rt));
+            match a >= d {
+                true => fits(a - d, b - c),
+                false => ANY_VALUE,
+            }
+        }
+        Instruction::Mul { rs, rt, .. } => {
+            let ((a, b), (c, d)) = (get(rs), get(rt));
+            fits(a * c, b * d)
+        }
+        Instruction::Div { rs, rt, .. } => {
+            let ((a, b), (c, d)) = (get(rs), get(rt));
+            match c > 0 {
+                true => fits(a / d, b / c),
+                false => ANY_VALUE,
+            }
+        }
+        _ => return next,
+    };
+
+    if let Some(rd) = instruction.dest() {
+        if !rd.is_special() {
+            next[rd.index()] = range;
+        }
+    }
+    next
+}
+
+/// The range of every register before each instruction when launched as `launch`, None for
+/// unreachable addresses. The special registers range over the blocks and threads launched,
+/// and the general purpose registers start out as any value.
+pub fn register_ranges(program: &[Instruction], launch: &Launch) -> Vec<Option<[ValueRange; 16]>> {
+    let mut states: Vec<Option<[ValueRange; 16]>> = vec![None; program.len()];
+    if program.is_empty() {
+        return states;
+    }
+
+    let clamp = |value: u32| value.min(u32::from(u8::MAX)) as u8;
+    let mut entry = [ANY_VALUE; 16];
+    entry[Register::BlockIdx.index()] = (0, clamp(launch.blocks.saturating_sub(1)));
+    entry[Register::BlockDim.index()] = (clamp(launch.block_dim), clamp(launch.block_dim));
+    entry[Register::ThreadIdx.index()] = (
+        0,
+        clamp(launch.block_dim.min(launch.threads).saturating_sub(1)),
+    );
+    states[0] = Some(entry);
+
+    let successors = control_flow(program);
+    let mut visits = vec![0; program.len()];
+    let mut worklist = vec![0];
+    while let Some(addr) = worklist.pop() {
+        let Some(state) = states[addr] else { continue };
+        let out = transfer_ranges(&program[addr], &state);
+
+        for &next in &successors[addr] {
+            if next >= program.len() {
+                continue;
+            }
+            let joined = match states[next] {
+                Some(mut joined) => {
+                    visits[next] += 1;
+                    for (slot, &(lo, hi)) in joined.iter_mut().zip(out.iter()) {
+                        let hull = (slot.0.min(lo), slot.1.max(hi));
+                        *slot = match hull != *slot && visits[next] > WIDEN_AFTER {
+                            true => ANY_VALUE,
+                            false => hull,
+                        };
+                    }
+                    joined
+                }
+                None => out,
+            };
+            if states[next] != Some(joined) {
+                states[next] = Some(joined);
+                worklist.push(next);
+            }
+        }
+    }
+
+    states
+}
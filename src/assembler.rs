@@ -0,0 +1,1013 @@
+use std::str::FromStr;
+
+use crate::conditional::conditional_assembly;
+use crate::immediate::parse_imm8;
+use crate::operation::Operation;
+use crate::operation::Operation::*;
+use crate::output::{Hardware, Launch};
+use crate::pseudo::{expand_line, pseudo_config};
+use crate::regalloc::{allocate_registers, placeholder_registers};
+use crate::unroll::unroll_loops;
+use crate::*;
+
+/// Assembly
+/// ---
+/// The result of assembling one source file. Errors are collected per line instead of stopping
+/// at the first, so editors can show all of them at once.
+#[derive(Debug)]
+pub struct Assembly {
+    pub source_lines: Vec<ParsedLine>, // before pseudoinstruction expansion, with lines left out by .if empty
+    pub operations: Vec<MachineLine>,
+    pub memories: Vec<MachineLine>,
+    pub label_addresses: Vec<(String, u16)>,
+    pub data_labels: Vec<(String, u16)>, // labels on .data lines, with their data memory address
+    pub imports: Vec<String>,            // symbols a relocatable object leaves to the linker
+    pub errors: Vec<LineError>,
+}
+
+impl Assembly {
+    /// The assembled instruction words, in address order
+    pub fn program(&self) -> Vec<u16> {
+        self.operations
+            .iter()
+            .filter_map(|line| line.bin.as_deref())
+            .map(|bin| u16::from_str_radix(bin, 2).unwrap())
+            .collect()
+    }
+
+    /// The launch configuration from the .threads, .blocks, .block_dim and .cores directives,
+    /// checked against the width of the special registers that hold it. Without .blocks or
+    /// .threads one thread is launched, and `.block_dim` and `.cores` default to the TinyGPU's
+    /// 4 threads per block and 2 cores.
+    pub fn launch(&self, hardware: &Hardware) -> Result<Launch, LineError> {
+        self.launch_with(hardware, None)
+    }
+
+    /// `launch`, with `threads` in place of the .threads directive, as a batch manifest sets it
+    pub fn launch_with(
+        &self,
+        hardware: &Hardware,
+        threads: Option<u32>,
+    ) -> Result<Launch, LineError> {
+        let mut values: [Option<(u32, u32)>; 4] = [None; 4]; // (value, line_num)
+        for line in &self.memories {
+            let tokens = &line.parsed_line.tokens;
+            let Some(index) = LAUNCH_DIRECTIVES.iter().position(|d| *d == tokens[0]) else {
+                continue;
+            };
+            let error = |error| LineError {
+                line_num: line.line_num,
+                error,
+            };
+            if let Some((_, first_line)) = values[index] {
+                return Err(error(LexError::InvalidArgument(format!(
+                    "{} is already set on line {}",
+                    tokens[0],
+                    first_line + 1
+                ))));
+            }
+            let value = match &tokens[1..] {
+                [value] => value.parse::<u32>().ok().filter(|&value| value > 0),
+                _ => None,
+            };
+            let Some(value) = value else {
+                return Err(error(LexError::InvalidArgument(format!(
+                    "{} expects one positive decimal count, found `{}`",
+                    tokens[0],
+                    tokens[1..].join(" ")
+                ))));
+            };
+            values[index] = Some((value, line.line_num));
+        }
+
+        if let Some(threads) = threads {
+            let line_num = values[0].map_or(0, |(_, line_num)| line_num);
+            if threads == 0 {
+                return Err(LineError {
+                    line_num,
+                    error: LexError::InvalidArgument(
+                        "the threads override is 0, expected a positive count".into(),
+                    ),
+                });
+            }
+            values[0] = Some((threads, line_num));
+        }
+
+        let [threads, blocks, block_dim, cores] = values;
+        let default = Launch::default();
+        let block_dim_value = block_dim.map_or(default.block_dim, |(value, _)| value);
+        let cores = cores.map_or(default.cores, |(value, _)| value);
+        let launch = match (threads, blocks) {
+            (Some((threads, _)), Some((blocks, line_num))) => {
+                let launch = Launch::new(threads, block_dim_value, cores);
+                if launch.blocks != blocks {
+                    return Err(LineError {
+                        line_num,
+                        error: LexError::InvalidArgument(format!(
+                            "{threads} threads of {block_dim_value} per block make {} blocks, not {blocks}",
+                            launch.blocks
+                        )),
+                    });
+                }
+                launch
+            }
+            (Some((threads, _)), None) => Launch::new(threads, block_dim_value, cores),
+            (None, Some((blocks, _))) => Launch::new(
+                blocks.saturating_mul(block_dim_value),
+                block_dim_value,
+                cores,
+            ),
+            (None, None) => Launch::new(default.threads, block_dim_value, cores),
+        };
+
+        // %blockIdx, %blockDim and %threadIdx are data words, as is the thread count the
+        // testbench writes to the device control register
+        let limit = 1u64 << hardware.data_data_bits.min(32);
+        let checks = [
+            (launch.threads, threads.or(blocks), "threads"),
+            (launch.block_dim, block_dim, "threads per block"),
+        ];
+        for (value, directive, what) in checks {
+            if u64::from(value) >= limit {
+                return Err(LineError {
+                    line_num: directive.map_or(0, |(_, line_num)| line_num),
+                    error: LexError::InvalidArgument(format!(
+                        "{value} {what} do not fit the {}-bit special registers, at most {} can be launched",
+                        hardware.data_data_bits,
+                        limit - 1
+                    )),
+                });
+            }
+        }
+        Ok(launch)
+    }
+
+    /// Initial data memory from the .data directives
+    pub fn initial_data(&self) -> Result<Vec<u8>, LineError> {
+        self.memories
+            .iter()
+            .filter(|m| m.parsed_line.tokens.first().map(|t| t.as_str()) == Some(".data"))
+            .flat_map(|m| {
+                m.parsed_line
+                    .tokens
+                    .iter()
+                    .skip(1) // skip ".data"
+                    .map(|tok| {
+                        parse_imm8(tok).map_err(|error| LineError {
+                            line_num: m.line_num,
+                            error,
+                        })
+                    })
+            })
+            .collect()
+    }
+}
+
+/// The directives setting the launch configuration, in the order `Assembly::launch` reads them
+const LAUNCH_DIRECTIVES: [&str; 4] = [".threads", ".blocks", ".block_dim", ".cores"];
+
+/// How to assemble a source, beyond its own directives
+#[derive(Debug, Clone, Default)]
+pub struct AssembleOptions {
+    pub relocatable: bool,
+    pub defines: Vec<(String, i64)>, // -D NAME=VALUE
+}
+
+pub fn assemble(contents: &str) -> Assembly {
+    assemble_with(contents, &AssembleOptions::default())
+}
+
+/// Assembles a relocatable object: symbols the source references but does not define are
+/// imports, encoded as address 0 for the linker to fill in, instead of errors.
+pub fn assemble_relocatable(contents: &str) -> Assembly {
+    assemble_with(
+        contents,
+        &AssembleOptions {
+            relocatable: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// The label a branch jumps to, or the data label a CONST loads the address of. Numeric labels
+/// count once `resolve_labels` has renamed them (`1@5`).
+pub fn symbol_reference(tokens: &[String]) -> Option<&str> {
+    let is_symbol = |token: &&String| {
+        let named = token
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.');
+        let numeric = token
+            .split_once('@')
+            .is_some_and(|(label, line)| is_numeric_label(label) && is_numeric_label(line));
+        named || numeric
+    };
+    match tokens.first()?.as_str() {
+        branch if branch.starts_with("BR") => tokens.get(1).filter(is_symbol),
+        "CONST" => tokens.get(2).filter(is_symbol),
+        _ => None,
+    }
+    .map(|symbol| symbol.as_str())
+}
+
+pub fn assemble_with(contents: &str, options: &AssembleOptions) -> Assembly {
+    let mut errors = vec![];
+
+    //start with list of lines (Label/operation/none/error)+comment?
+    //handle all cases with labels/memory
+    //handle all cases with operations
+    //print all lines, exluding those with no comments and no statements
+
+    let source_lines: Vec<ParsedLine> = contents
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| {
+            parse_line(line_num, line).unwrap_or_else(|error| {
+                errors.push(LineError {
+                    line_num: line_num as u32,
+                    error,
+                });
+                // keep the line numbering intact with an empty line in its place
+                ParsedLine {
+                    tokens: vec![],
+                    comment: None,
+                    line_num: line_num as u32,
+                }
+            })
+        })
+        .collect();
+
+    // .if/.define are resolved first, so every later stage only sees the lines assembled
+    let source_lines =
+        conditional_assembly(&source_lines, &options.defines).unwrap_or_else(|err| {
+            errors.push(err);
+            vec![]
+        });
+
+    // expand pseudoinstructions (LI) into real operations before identifying lines
+    let config = pseudo_config(&source_lines).unwrap_or_else(|err| {
+        errors.push(err);
+        Default::default()
+    });
+    let mut parsed_lines = Vec::with_capacity(source_lines.len());
+    for line in &source_lines {
+        match expand_line(line, &config) {
+            Ok(expanded) => parsed_lines.extend(expanded),
+            Err(error) => errors.push(LineError {
+                line_num: line.line_num,
+                error,
+            }),
+        }
+    }
+
+    let parsed_lines = resolve_labels(split_labels(parsed_lines));
+
+    let parsed_lines = allocate_registers(&parsed_lines).unwrap_or_else(|err| {
+        errors.push(err);
+        placeholder_registers(&parsed_lines)
+    });
+
+    let parsed_lines = unroll_loops(&parsed_lines).unwrap_or_else(|err| {
+        errors.push(err);
+        parsed_lines
+    });
+
+    let mut lexed_lines: Vec<Box<dyn LexedLine>> = parsed_lines
+        .into_iter()
+        .map(|item| identify_line(item))
+        .collect();
+
+    let (label_lines, data_labels, label_errors) = symbol_table(&lexed_lines);
+    errors.extend(label_errors);
+
+    //// now that the label list is generated, it's possible to statically point branch/jump instructs
+    // number the instructions based on the actual address (is there a better method? almost certainly!)
+    let mut i: u16 = 0;
+    for line in &mut lexed_lines {
+        if let Some(operation_line) = line.as_any_mut().downcast_mut::<OperationLine>() {
+            operation_line.instruct_num = Some(i);
+            i += 1;
+        }
+    }
+
+    //for u32 in label lines, do lexed_lines.get(u32) convert to operation line fmt and take its instruct number)
+
+    // a label at the end of the file points past the last instruction
+    let label_addresses: Vec<(String, u16)> = label_lines
+        .into_iter()
+        .map(|(label, index)| {
+            let address = lexed_lines
+                .get(index)
+                .and_then(|line| line.as_any().downcast_ref::<OperationLine>())
+                .and_then(|line| line.instruct_num)
+                .unwrap_or(i);
+            (label, address)
+        })
+        .collect();
+
+    // imports are encoded with address 0 until linking
+    let mut imports: Vec<String> = vec![];
+    let (mut code_symbols, mut data_symbols) = (label_addresses.clone(), data_labels.clone());
+    if options.relocatable {
+        for line in &lexed_lines {
+            let tokens = &line.parsed().tokens;
+            let Some(symbol) = symbol_reference(tokens) else {
+                continue;
+            };
+            let symbols = match tokens[0].as_str() {
+                "CONST" => &mut data_symbols,
+                _ => &mut code_symbols,
+            };
+            if !symbols.iter().any(|(name, _)| name == symbol) {
+                symbols.push((symbol.to_string(), 0));
+                if !imports.iter().any(|name| name == symbol) {
+                    imports.push(symbol.to_string());
+                }
+            }
+        }
+    }
+
+    let mut operations = Vec::new();
+    let mut memories = Vec::new();
+
+    for line in lexed_lines {
+        let line_num = line.parsed().line_num;
+        match operation_conv(line, code_symbols.clone(), &data_symbols) {
+            Ok(line) => match line.line_type {
+                LineType::Operation => operations.push(line),
+                LineType::Memory => memories.push(line),
+                _ => {}
+            },
+            Err(error) => errors.push(LineError { line_num, error }),
+        }
+    }
+
+    let mut assembly = Assembly {
+        source_lines,
+        operations,
+        memories,
+        label_addresses,
+        data_labels,
+        imports,
+        errors,
+    };
+    if let Err(err) = assembly.launch(&Hardware::default()) {
+        assembly.errors.push(err);
+    }
+    // bad .data bytes are reported on their line, not only when the output is built
+    for line in &assembly.memories {
+        let tokens = &line.parsed_line.tokens;
+        if tokens[0] != ".data" {
+            continue;
+        }
+        for token in &tokens[1..] {
+            if let Err(error) = parse_imm8(token) {
+                assembly.errors.push(LineError {
+                    line_num: line.line_num,
+                    error,
+                });
+            }
+        }
+    }
+    assembly.errors.sort_by_key(|err| err.line_num);
+    assembly
+}
+
+pub fn operation_conv(
+    mut lexed_line: Box<dyn LexedLine>,
+    label_addresses: Vec<(String, u16)>,
+    data_labels: &[(String, u16)],
+) -> Result<MachineLine, LexError> {
+    // operation_conv(a, &operation.parsed, label_numbers, &lexed_lines)
+    // OperationType, &parsedline, label_nums, &lexedlines
+
+    if lexed_line
+        .as_any_mut()
+        .downcast_mut::<OperationLine>()
+        .is_none()
+    {
+        // determine the correct type
+        let typ: LineType;
+        if lexed_line.as_any_mut().downcast_mut::<BadLine>().is_some() {
+            typ = LineType::Bad;
+        } else if lexed_line
+            .as_any_mut()
+            .downcast_mut::<HumanLine>()
+            .is_some()
+        {
+            typ = LineType::Human;
+        } else if lexed_line
+            .as_any_mut()
+            .downcast_mut::<OperationLine>()
+            .is_some()
+        {
+            typ = LineType::Operation;
+        } else if lexed_line
+            .as_any_mut()
+            .downcast_mut::<MemoryLine>()
+            .is_some()
+        {
+            typ = LineType::Memory;
+        } else if lexed_line
+            .as_any_mut()
+            .downcast_mut::<LabelLine>()
+            .is_some()
+        {
+            typ = LineType::Label;
+        } else {
+            return Err(LexError::InvalidArgument("terrible, man".to_owned()));
+        }
+        return {
+            Ok(MachineLine {
+                line_type: typ,
+                parsed_line: lexed_line.parsed().clone(),
+                bin: None,
+                comment: lexed_line.parsed().comment.clone(),
+                line_num: lexed_line.parsed().line_num,
+            })
+        };
+
+        // {Ok (MachineLine {
+        //     bin: None,
+        //     comment: parsed_line.comment,
+        //     line_num: parsed_line.line_num,
+        //     parsed_line: parsed_line,
+        //     } ) };
+
+        // return Err(LexError::InvalidArgument("".to_string()));
+    }
+
+    let operation_line = lexed_line.as_any().downcast_ref::<OperationLine>().unwrap();
+    let op: Operation = Operation::from_str(operation_line.parsed.tokens.first().unwrap()).unwrap();
+    let parsed_line = operation_line.parsed.clone();
+
+    if op.num_args() as usize != parsed_line.tokens.len() - 1 {
+        return Err(LexError::WrongOperandCount {
+            operation: parsed_line.tokens[0].clone(),
+            expected: op.num_args(),
+            found: parsed_line.tokens.len() - 1,
+        });
+    }
+
+    let operands = &parsed_line.tokens; // this is actually the operator AND the operands. anyway...
+
+    // define closure that (semi)gracefully handles errors that should never occur, rather than making the below match statement unreadable
+    let get_operand_from_ind = |index: u16| {
+        operands
+            .get(index as usize)
+            .unwrap_or_else(|| panic!("should have {} args", op.num_args()))
+    };
+
+    // parse a register operand, naming its position if it is not one
+    let register_from_ind = |index: u16| {
+        let operand = get_operand_from_ind(index);
+        Register::from_str(operand).map_err(|_| LexError::MalformedOperand {
+            position: index as usize,
+            operand: operand.clone(),
+            reason: "is not a register (R0-R12, %blockIdx, %blockDim, %threadIdx)".into(),
+        })
+    };
+
+    let bin = match op {
+        NOP => Ok(op.as_opcode().to_owned() + "000000000000"),
+
+        BRnzp => {
+            //// NZP Shenanigans
+
+            let branch_instr_string = get_operand_from_ind(0);
+            // Extract the "nzp" flags
+            let nzp_flags: String = branch_instr_string
+                .chars()
+                .filter(|&c| c == 'n' || c == 'z' || c == 'p')
+                .collect();
+
+            // dbg!(nzp_flags);
+            // panic!();
+            // Default to "1000" if no flags are found, as per the spec
+            let nzp = if nzp_flags.is_empty() {
+                "0000".to_string() // Default value if no flags are present
+            } else {
+                // Each flag owns one bit of the 4-bit field (n=8, z=4, p=2), so or them together
+                format!(
+                    "{:04b}",
+                    nzp_flags.chars().fold(0, |acc, flag| {
+                        acc | match flag {
+                            'n' => 8,
+                            'z' => 4,
+                            'p' => 2,
+                            _ => 0,
+                        }
+                    })
+                )
+            };
+
+            if nzp == "0000" {
+                return Err(LexError::InvalidArgument("Branch instruction with no NZP flags - will never branch. Did you mean to branch in all cases? (BRnzp)".into()));
+            }
+
+            //// end of nzp shenanigans
+
+            // Get the operand (the label or flags part)
+            let req_label = get_operand_from_ind(1); // WARN:
+
+            if let Some(jump_addr) = label_addresses
+                .iter()
+                .filter_map(|(label, line_num)| {
+                    if *req_label == *label {
+                        Some(*line_num)
+                    } else {
+                        None
+                    }
+                })
+                .next()
+            {
+                if jump_addr > u8::MAX as u16 {
+                    return Err(LexError::InvalidArgument(format!(
+                        "{req_label} is at address {jump_addr}, past the 8-bit branch target range (0-255)"
+                    )));
+                }
+                let code = op.as_opcode().to_owned() + &nzp + format!("{:08b}", jump_addr).as_str();
+                Ok(code)
+            } else {
+                Err(LexError::UndefinedLabel(req_label.clone()))
+            }
+        }
+
+        CMP => {
+            // CMP Rs, Rt
+            let rs = register_from_ind(1)?;
+            let rt = register_from_ind(2)?;
+            let code = "00100000".to_owned() + rs.bits() + rt.bits();
+            Ok(code)
+        }
+
+        ADD | SUB | MUL | DIV => {
+            // ADD Rd, Rs, Rt
+            let rd = register_from_ind(1)?;
+            let rs = register_from_ind(2)?;
+            let rt = register_from_ind(3)?;
+            let code = op.as_opcode().to_owned() + rd.bits() + rs.bits() + rt.bits();
+            Ok(code)
+        }
+
+        Operation::LDR => {
+            let rd = register_from_ind(1)?;
+            let rs = register_from_ind(2)?;
+            let code = op.as_opcode().to_owned() + rd.bits() + rs.bits() + "0000";
+            Ok(code)
+        }
+
+        Operation::STR => {
+            let rs = register_from_ind(1)?;
+            let rt = register_from_ind(2)?;
+            let code = op.as_opcode().to_owned() + "0000" + rs.bits() + rt.bits();
+            Ok(code)
+        }
+        Operation::CONST => {
+            let rd = register_from_ind(1)?;
+            let operand = get_operand_from_ind(2);
+            // a data label loads its address
+            let imm8 = match symbol_reference(operands) {
+                Some(symbol) => {
+                    let address = data_labels
+                        .iter()
+                        .find(|(label, _)| label == symbol)
+                        .map(|(_, address)| *address)
+                        .ok_or_else(|| LexError::UndefinedLabel(operand.clone()))?;
+                    u8::try_from(address).map_err(|_| {
+                        LexError::InvalidArgument(format!(
+                            "{symbol} is at data address {address}, past the 8-bit immediate range (0-255)"
+                        ))
+                    })?
+                }
+                None => parse_imm8(operand)?,
+            };
+            let code = op.as_opcode().to_owned() + rd.bits() + format!("{:08b}", imm8).as_str();
+            Ok(code)
+        }
+        Operation::RET => Ok(op.as_opcode().to_owned() + "000000000000"),
+    };
+
+    //// test assertions that produced binary is accurate
+    assert!(bin.as_ref().map_or(true, |bin| bin.len() == 16)); //maybe correct?
+
+    if bin.is_ok() {
+        Ok(MachineLine {
+            line_type: LineType::Operation,
+            bin: bin.ok(),
+            comment: parsed_line.comment.clone(),
+            line_num: parsed_line.line_num,
+            parsed_line,
+        })
+    } else {
+        Err(bin.err().unwrap())
+    }
+}
+
+/// Splits a label sharing its line with an instruction (`LOOP: ADD R1, R1, R2`) onto a line of
+/// its own, keeping the line number and giving the comment to the instruction.
+pub fn split_labels(lines: Vec<ParsedLine>) -> Vec<ParsedLine> {
+    let mut split = Vec::with_capacity(lines.len());
+    for mut line in lines {
+        if line.tokens.len() > 1 && line.tokens[0].ends_with(':') {
+            let instruction = line.tokens.split_off(1);
+            split.push(ParsedLine {
+                tokens: line.tokens,
+                comment: None,
+                line_num: line.line_num,
+            });
+            line.tokens = instruction;
+        }
+        split.push(line);
+    }
+    split
+}
+
+fn is_numeric_label(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Local and Numeric Labels
+/// ---
+/// Renames scoped labels to names unique in the program, on lines split by `split_labels`:
+///
+/// - a local label `.inner` belongs to the global label before it, and becomes `OUTER.inner`
+///   both where it is defined and where a branch in the same scope refers to it
+/// - a numeric label `1:` may be defined any number of times; a branch to `1b` goes to the
+///   nearest definition at or before it and `1f` to the nearest one after it. Each definition
+///   becomes `1@N`, with N its source line.
+///
+/// References that resolve to nothing are left as written, for the symbol lookup to report.
+pub fn resolve_labels(mut lines: Vec<ParsedLine>) -> Vec<ParsedLine> {
+    let label_of = |line: &ParsedLine| {
+        line.tokens
+            .first()
+            .and_then(|t| t.strip_suffix(':'))
+            .map(String::from)
+    };
+
+    // the global label in scope at every line, and every numeric label definition
+    let mut scopes = Vec::with_capacity(lines.len());
+    let mut numeric: Vec<(String, usize)> = vec![];
+    let mut scope = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        match label_of(line) {
+            Some(label) if is_numeric_label(&label) => numeric.push((label, index)),
+            Some(label) if !label.starts_with('.') => scope = label,
+            _ => {}
+        }
+        scopes.push(scope.clone());
+    }
+    let numeric_name = |label: &str, index: usize, lines: &[ParsedLine]| {
+        format!("{label}@{}", lines[index].line_num + 1)
+    };
+
+    for index in 0..lines.len() {
+        if let Some(label) = label_of(&lines[index]) {
+            if label.starts_with('.') {
+                lines[index].tokens[0] = format!("{}{label}:", scopes[index]);
+            } else if is_numeric_label(&label) {
+                lines[index].tokens[0] = numeric_name(&label, index, &lines) + ":";
+            }
+            continue;
+        }
+
+        let is_branch = lines[index]
+            .tokens
+            .first()
+            .is_some_and(|t| t.starts_with("BR"));
+        let Some(target) = lines[index].tokens.last().filter(|_| is_branch).cloned() else {
+            continue;
+        };
+
+        let resolved = if target.starts_with('.') {
+            Some(format!("{}{target}", scopes[index]))
+        } else if let Some((label, direction)) = target
+            .split_at_checked(target.len().saturating_sub(1))
+            .filter(|(label, direction)| is_numeric_label(label) && ["b", "f"].contains(direction))
+        {
+            let definition = if direction == "b" {
+                numeric
+                    .iter()
+                    .rev()
+                    .find(|(n, at)| n == label && *at <= index)
+            } else {
+                numeric.iter().find(|(n, at)| n == label && *at > index)
+            };
+            definition.map(|(_, at)| numeric_name(label, *at, &lines))
+        } else {
+            None
+        };
+        if let Some(resolved) = resolved {
+            *lines[index].tokens.last_mut().unwrap() = resolved;
+        }
+    }
+
+    lines
+}
+
+/// Symbol Table
+/// ---
+/// Maps every label to the index of the first operation line after it, or to the end of
+/// `lexed_lines` when no operation follows. A label whose next line is `.data` instead labels
+/// that data, and maps to its data memory address. A label defined twice keeps its first
+/// definition, and both that and a label at the end of the file are reported as errors.
+#[allow(clippy::type_complexity)]
+pub fn symbol_table(
+    lexed_lines: &[Box<dyn LexedLine>],
+) -> (Vec<(String, usize)>, Vec<(String, u16)>, Vec<LineError>) {
+    let mut labels: Vec<(String, usize)> = vec![];
+    let mut data_labels: Vec<(String, u16)> = vec![];
+    let mut defined_on: Vec<(String, u32)> = vec![];
+    let mut errors = vec![];
+
+    let is_data = |line: &dyn LexedLine| {
+        line.as_any().downcast_ref::<MemoryLine>().is_some()
+            && line.parsed().tokens.first().is_some_and(|t| t == ".data")
+    };
+    let mut data_address = 0;
+
+    for (index, line) in lexed_lines.iter().enumerate() {
+        if is_data(line.as_ref()) {
+            data_address += line.parsed().tokens.len() - 1;
+        }
+        let Some(label_line) = line.as_any().downcast_ref::<LabelLine>() else {
+            continue;
+        };
+        let line_num = label_line.parsed.line_num;
+        let label = label_line.parsed.tokens[0]
+            .trim_end_matches(':')
+            .to_string();
+
+        if label.is_empty() {
+            errors.push(LineError {
+                line_num,
+                error: LexError::InvalidSyntax("a label needs a name before the ':'".into()),
+            });
+            continue;
+        }
+        if let Some((_, first_line)) = defined_on.iter().find(|(name, _)| *name == label) {
+            errors.push(LineError {
+                line_num,
+                error: LexError::DuplicateLabel {
+                    label,
+                    first_line: *first_line,
+                },
+            });
+            continue;
+        }
+        defined_on.push((label.clone(), line_num));
+
+        // (indexes, not source line numbers, since pseudoinstructions expand to several lines)
+        let target = lexed_lines[index..].iter().position(|line| {
+            line.as_any().downcast_ref::<OperationLine>().is_some() || is_data(line.as_ref())
+        });
+        match target.map(|offset| index + offset) {
+            Some(target) if is_data(lexed_lines[target].as_ref()) => {
+                data_labels.push((label, data_address as u16));
+            }
+            Some(target) => labels.push((label, target)),
+            None => {
+                errors.push(LineError {
+                    line_num,
+                    error: LexError::LabelAtEnd(label.clone()),
+                });
+                labels.push((label, lexed_lines.len()));
+            }
+        }
+    }
+
+    (labels, data_labels, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction;
+
+    /// Assembles one instruction after `LOOP:` at address 0, so branches have a target
+    fn encode(instruction: &str) -> u16 {
+        let assembly = assemble(&format!("LOOP:\n{instruction}\n"));
+        assert!(
+            assembly.errors.is_empty(),
+            "{instruction}: {:?}",
+            assembly.errors
+        );
+        assert_eq!(assembly.program().len(), 1, "{instruction}");
+        assembly.program()[0]
+    }
+
+    // The TinyGPU ISA: opcode in the top 4 bits, then Rd (or nzp), Rs and Rt or an 8-bit
+    // immediate. R0-R12 are registers 0-12, %blockIdx 13, %blockDim 14 and %threadIdx 15.
+    //
+    //   NOP    0000 xxxx xxxx xxxx      ADD    0011 dddd ssss tttt
+    //   BRnzp  0001 nzpx iiii iiii      SUB    0100 dddd ssss tttt
+    //   CMP    0010 xxxx ssss tttt      MUL    0101 dddd ssss tttt
+    //   LDR    0111 dddd ssss xxxx      DIV    0110 dddd ssss tttt
+    //   STR    1000 xxxx ssss tttt      CONST  1001 dddd iiii iiii
+    //   RET    1111 xxxx xxxx xxxx
+    #[test]
+    fn encodes_every_instruction() {
+        let table = [
+            ("NOP", 0b0000_0000_0000_0000),
+            ("BRnzp LOOP", 0b0001_1110_0000_0000),
+            ("BRn LOOP", 0b0001_1000_0000_0000),
+            ("BRz LOOP", 0b0001_0100_0000_0000),
+            ("BRp LOOP", 0b0001_0010_0000_0000),
+            ("BRnp LOOP", 0b0001_1010_0000_0000),
+            ("CMP R1, R2", 0b0010_0000_0001_0010),
+            ("ADD R1, R2, R3", 0b0011_0001_0010_0011),
+            ("SUB R4, R5, R6", 0b0100_0100_0101_0110),
+            ("MUL R7, R8, R9", 0b0101_0111_1000_1001),
+            ("DIV R10, R11, R12", 0b0110_1010_1011_1100),
+            ("LDR R3, R4", 0b0111_0011_0100_0000),
+            ("STR R5, R6", 0b1000_0000_0101_0110),
+            ("CONST R12, #171", 0b1001_1100_1010_1011),
+            ("RET", 0b1111_0000_0000_0000),
+        ];
+        for (instruction, expected) in table {
+            assert_eq!(
+                encode(instruction),
+                expected,
+                "{instruction}: {:016b}",
+                encode(instruction)
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_special_registers() {
+        assert_eq!(
+            encode("MUL R0, %blockIdx, %blockDim"),
+            0b0101_0000_1101_1110
+        );
+        assert_eq!(encode("ADD R0, R0, %threadIdx"), 0b0011_0000_0000_1111);
+    }
+
+    #[test]
+    fn encodes_immediate_forms() {
+        for (immediate, expected) in [
+            ("#42", 42),
+            ("#0x2A", 42),
+            ("#0b101010", 42),
+            ("#-3", 0xFD),
+            ("#'A'", 65),
+        ] {
+            assert_eq!(
+                encode(&format!("CONST R0, {immediate}")),
+                0b1001_0000_0000_0000 | expected,
+                "{immediate}"
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_every_branch_flag_combination() {
+        for (flags, nzp) in [
+            ("n", 0b100),
+            ("z", 0b010),
+            ("p", 0b001),
+            ("nz", 0b110),
+            ("np", 0b101),
+            ("zp", 0b011),
+            ("nzp", 0b111),
+        ] {
+            let word = encode(&format!("BR{flags} LOOP"));
+            assert_eq!(word, 0b0001_0000_0000_0000 | (nzp << 9), "BR{flags}");
+            assert_eq!(
+                Instruction::decode(word),
+                Some(Instruction::Branch {
+                    nzp: nzp as u8,
+                    target: 0
+                }),
+                "BR{flags}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_data_bytes_are_reported_on_their_line() {
+        let assembly = assemble("RET\n.data 1 2\n.data 3 300\n");
+        let err = assembly.initial_data().unwrap_err();
+        assert_eq!(err.line_num, 2);
+        assert_eq!(err.error.code(), "immediate-out-of-range");
+    }
+
+    /// The errors assembling `source` gives, with their 1-based line
+    fn errors(source: &str) -> Vec<String> {
+        assemble(source)
+            .errors
+            .iter()
+            .map(|err| err.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn labels_share_a_line_with_their_instruction() {
+        let assembly =
+            assemble("CONST R0, #1\nLOOP: SUB R0, R0, R0 ; head\nCMP R0, R0\nBRp LOOP\nRET\n");
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        assert_eq!(assembly.label_addresses, [("LOOP".to_string(), 1)]);
+        assert_eq!(assembly.program()[3], 0b0001_0010_0000_0001);
+    }
+
+    #[test]
+    fn reports_invalid_labels_by_name() {
+        assert_eq!(
+            errors("LOOP: NOP\nNOP\nLOOP: NOP\nBRnzp LOOP\nRET\n"),
+            ["line 3: Duplicate label: LOOP is already defined on line 1"]
+        );
+        assert_eq!(
+            errors("CMP R0, R0\nBRz DONE\nRET\n"),
+            ["line 2: Undefined label: DONE is not defined anywhere"]
+        );
+        assert_eq!(
+            errors("NOP\nRET\nEND:\n"),
+            ["line 3: Label END is not followed by an instruction, end the program with RET after it"]
+        );
+        assert_eq!(
+            errors(": NOP\nRET\n"),
+            ["line 1: Invalid syntax: a label needs a name before the ':'"]
+        );
+    }
+
+    fn launch(directives: &str) -> Result<Launch, String> {
+        let assembly = assemble(&format!("{directives}\nRET\n"));
+        assembly
+            .launch(&Hardware::default())
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn launch_directives_fill_in_each_other() {
+        let launch = |directives| launch(directives).unwrap();
+        assert_eq!(launch(""), Launch::new(1, 4, 2));
+        assert_eq!(launch(".threads 10"), Launch::new(10, 4, 2));
+        assert_eq!(launch(".blocks 3"), Launch::new(12, 4, 2));
+        assert_eq!(
+            launch(".threads 6\n.blocks 3\n.block_dim 2\n.cores 1"),
+            Launch {
+                threads: 6,
+                blocks: 3,
+                block_dim: 2,
+                cores: 1
+            }
+        );
+    }
+
+    #[test]
+    fn launch_directives_are_checked() {
+        for (directives, expected) in [
+            (
+                ".threads 8\n.blocks 3",
+                "line 2: Invalid argument: 8 threads of 4 per block make 2 blocks, not 3",
+            ),
+            (
+                ".cores 2\n.cores 2",
+                "line 2: Invalid argument: .cores is already set on line 1",
+            ),
+            (
+                ".block_dim 0",
+                "line 1: Invalid argument: .block_dim expects one positive decimal count, found `0`",
+            ),
+            (
+                ".threads 256",
+                "line 1: Invalid argument: 256 threads do not fit the 8-bit special registers, at most 255 can be launched",
+            ),
+        ] {
+            assert_eq!(launch(directives).unwrap_err(), expected, "{directives}");
+        }
+
+        // wider data words make room for more threads
+        let hardware = Hardware {
+            data_data_bits: 16,
+            ..Hardware::default()
+        };
+        let assembly = assemble(".threads 256\nRET\n");
+        assert_eq!(assembly.launch(&hardware).unwrap().blocks, 64);
+    }
+
+    #[test]
+    fn the_threads_override_replaces_the_directive() {
+        let assembly = assemble(".threads 4\n.block_dim 2\nRET\n");
+        let hardware = Hardware::default();
+        assert_eq!(
+            assembly.launch_with(&hardware, Some(6)).unwrap(),
+            Launch::new(6, 2, 2)
+        );
+        assert_eq!(
+            assembly
+                .launch_with(&hardware, Some(0))
+                .unwrap_err()
+                .line_num,
+            0
+        );
+    }
+
+    #[test]
+    fn branch_targets_are_label_addresses() {
+        let assembly = assemble("CONST R0, #1\nCMP R0, R0\nEND:\nBRz END\nRET\n");
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        assert_eq!(assembly.program()[2], 0b0001_0100_0000_0010);
+    }
+}
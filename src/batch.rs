@@ -0,0 +1,357 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::{assemble_with, AssembleOptions};
+use crate::lint::{lint_program, LintConfig, LintLevel};
+use crate::output::{
+    build_output, ExpectedData, Hardware, HardwareOverrides, Output, DEFAULT_MEMORY_DELAY,
+};
+use crate::schedule::{schedule, LatencyModel};
+use crate::simulator::{Simulator, DATA_MEMORY_SIZE};
+
+/// Batch Assembly
+/// ---
+/// Assembles a whole test suite at once, from every `.asm` file in a directory or from a TOML
+/// manifest of kernels:
+///
+/// ```toml
+/// [hardware]            # suite-wide overrides, optional
+/// data_channels = 2
+///
+/// [[kernel]]
+/// path = "test_matadd.asm"   # relative to the manifest
+/// name = "matadd"            # defaults to the file stem
+/// threads = 8                # overrides .threads
+/// memory_delay = 4
+/// hardware = { data_channels = 4 }
+/// expected = { address = 16, data = [0, 2, 4, 6, 8, 10, 12, 14] }
+/// schedule = true            # reorder for this memory_delay and hardware
+/// defines = { THREADS = 8 }  # as with -D THREADS=8
+/// ```
+///
+/// Kernels are assembled in parallel. A kernel with `expected` data is also run in the
+/// functional simulator and fails if data memory does not end up holding it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub hardware: HardwareOverrides,
+    #[serde(default, rename = "kernel")]
+    pub kernels: Vec<KernelSpec>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KernelSpec {
+    pub path: PathBuf,
+    pub name: Option<String>,
+    pub threads: Option<u32>,
+    pub memory_delay: Option<u32>,
+    #[serde(default)]
+    pub hardware: HardwareOverrides,
+    pub expected: Option<ExpectedData>,
+    #[serde(default)]
+    pub schedule: bool,
+    #[serde(default)]
+    pub defines: BTreeMap<String, i64>,
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    Io(PathBuf, io::Error),
+    Manifest(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::Io(path, err) => write!(f, "could not read '{}': {}", path.display(), err),
+            BatchError::Manifest(path, err) => {
+                write!(f, "invalid manifest '{}': {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// Reads the kernels of a suite from a manifest file or a directory of `.asm` files.
+pub fn load_suite(path: &Path) -> Result<(HardwareOverrides, Vec<KernelSpec>), BatchError> {
+    let io_error = |err| BatchError::Io(path.to_path_buf(), err);
+
+    if path.is_dir() {
+        let mut paths: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(io_error)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+            .collect();
+        paths.sort();
+
+        let kernels = paths
+            .into_iter()
+            .map(|path| KernelSpec {
+                path,
+                ..Default::default()
+            })
+            .collect();
+        return Ok((HardwareOverrides::default(), kernels));
+    }
+
+    let contents = fs::read_to_string(path).map_err(io_error)?;
+    let mut manifest: Manifest =
+        toml::from_str(&contents).map_err(|err| BatchError::Manifest(path.to_path_buf(), err))?;
+
+    // kernel paths are relative to the manifest
+    let base = path.parent().unwrap_or(Path::new(""));
+    for kernel in &mut manifest.kernels {
+        kernel.path = base.join(&kernel.path);
+    }
+    Ok((manifest.hardware, manifest.kernels))
+}
+
+#[derive(Debug)]
+pub struct KernelResult {
+    pub name: String,
+    pub outcome: Result<Output, String>,
+}
+
+/// Assembles every kernel, in parallel on as many threads as the machine runs at once, returning
+/// the results in manifest order. A kernel whose assembly panics fails on its own.
+pub fn assemble_suite(
+    suite_hardware: &HardwareOverrides,
+    kernels: &[KernelSpec],
+) -> Vec<KernelResult> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    kernels
+        .chunks(workers)
+        .flat_map(|chunk| {
+            thread::scope(|scope| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .map(|kernel| scope.spawn(move || assemble_kernel(suite_hardware, kernel)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .zip(chunk)
+                    .map(|(handle, kernel)| {
+                        handle.join().unwrap_or_else(|panic| KernelResult {
+                            name: kernel_name(kernel),
+                            outcome: Err(format!("assembler panicked: {}", panic_message(&*panic))),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect()
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+/// The kernel's name in the manifest, or its file name without the extension
+fn kernel_name(kernel: &KernelSpec) -> String {
+    kernel.name.clone().unwrap_or_else(|| {
+        kernel
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    })
+}
+
+fn assemble_kernel(suite_hardware: &HardwareOverrides, kernel: &KernelSpec) -> KernelResult {
+    let name = kernel_name(kernel);
+
+    let outcome = fs::read_to_string(&kernel.path)
+        .map_err(|err| format!("could not read '{}': {}", kernel.path.display(), err))
+        .and_then(|contents| build_kernel(&name, &contents, suite_hardware, kernel));
+
+    KernelResult { name, outcome }
+}
+
+fn build_kernel(
+    name: &str,
+    contents: &str,
+    suite_hardware: &HardwareOverrides,
+    kernel: &KernelSpec,
+) -> Result<Output, String> {
+    let options = AssembleOptions {
+        relocatable: false,
+        defines: kernel.defines.clone().into_iter().collect(),
+    };
+    let mut assembly = assemble_with(contents, &options);
+    if let Some(err) = assembly.errors.first() {
+        return Err(match assembly.errors.len() {
+            1 => err.to_string(),
+            count => format!("{} (and {} more errors)", err, count - 1),
+        });
+    }
+
+    let diagnostics = lint_program(
+        &assembly.source_lines,
+        &assembly.operations,
+        &LintConfig::default(),
+    );
+    if let Some(denied) = diagnostics.iter().find(|d| d.level == LintLevel::Deny) {
+        return Err(denied.to_string());
+    }
+
+    let mut hardware = Hardware::default();
+    hardware.apply(suite_hardware);
+    hardware.apply(&kernel.hardware);
+    let memory_delay = kernel.memory_delay.unwrap_or(DEFAULT_MEMORY_DELAY);
+    if kernel.schedule {
+        schedule(&mut assembly, &LatencyModel::new(memory_delay, &hardware));
+    }
+
+    let mut output = build_output(name, &assembly).map_err(|err| err.to_string())?;
+    // the manifest's thread count goes through the same checks as .threads
+    output.launch = assembly
+        .launch_with(&hardware, kernel.threads)
+        .map_err(|err| err.to_string())?;
+    output.hardware = hardware;
+    output.memory_delay = memory_delay;
+
+    if let Some(expected) = &kernel.expected {
+        check_expected(&output, &assembly.program(), expected)?;
+        output.expected_data = Some(expected.clone());
+    }
+    Ok(output)
+}
+
+/// Runs the kernel in the functional simulator and compares data memory with `expected`
+fn check_expected(output: &Output, program: &[u16], expected: &ExpectedData) -> Result<(), String> {
+    let start = expected.address as usize;
+    if start + expected.data.len() > DATA_MEMORY_SIZE {
+        return Err(format!(
+            "expected data runs past the end of data memory at address {}",
+            DATA_MEMORY_SIZE
+        ));
+    }
+
+    let mut sim = Simulator::new(program.to_vec(), &output.initial_data, output.launch);
+    sim.run()
+        .map_err(|err| format!("simulation failed: {}", err))?;
+
+    let actual = &sim.memory[start..start + expected.data.len()];
+    match actual.iter().zip(&expected.data).position(|(a, e)| a != e) {
+        Some(offset) => Err(format!(
+            "data memory differs at address {}: expected {}, simulator has {}",
+            start + offset,
+            expected.data[offset],
+            actual[offset]
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The combined test suite JSON, with the kernels that assembled
+#[derive(Debug, Serialize)]
+pub struct Suite<'a> {
+    pub tests: Vec<&'a Output>,
+}
+
+/// A table of every kernel with its status and size
+pub fn summary(results: &[KernelResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("kernel".len());
+
+    let mut table = format!(
+        "{:width$}  {:6}  {:>12}  {:>10}  {:>7}\n",
+        "kernel", "status", "instructions", "data bytes", "threads"
+    );
+    for result in results {
+        let row = match &result.outcome {
+            Ok(output) => format!(
+                "{:width$}  {:6}  {:>12}  {:>10}  {:>7}",
+                result.name,
+                "ok",
+                output.program_memory.len(),
+                output.initial_data.len(),
+                output.launch.threads
+            ),
+            Err(reason) => format!("{:width$}  {:6}  {}", result.name, "FAILED", reason),
+        };
+        table += &row;
+        table.push('\n');
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| result.outcome.is_err())
+        .count();
+    table += &format!(
+        "{} kernels: {} ok, {} failed\n",
+        results.len(),
+        results.len() - failed,
+        failed
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL: &str = "MUL R0, %blockIdx, %blockDim\nADD R0, R0, %threadIdx\nSTR R0, R0\nRET\n";
+
+    fn build(contents: &str, threads: Option<u32>) -> Result<Output, String> {
+        let kernel = KernelSpec {
+            threads,
+            ..Default::default()
+        };
+        build_kernel("test", contents, &HardwareOverrides::default(), &kernel)
+    }
+
+    #[test]
+    fn the_threads_override_replaces_the_directive() {
+        let output = build(&format!(".threads 4\n{KERNEL}"), Some(8)).unwrap();
+        assert_eq!((output.launch.threads, output.launch.blocks), (8, 2));
+    }
+
+    #[test]
+    fn the_threads_override_is_checked_like_the_directive() {
+        let zero = build(KERNEL, Some(0)).unwrap_err();
+        assert!(zero.contains("expected a positive count"), "{zero}");
+        let wide = build(KERNEL, Some(300)).unwrap_err();
+        assert!(
+            wide.contains("do not fit the 8-bit special registers"),
+            "{wide}"
+        );
+        let blocks = build(&format!(".blocks 1\n{KERNEL}"), Some(8)).unwrap_err();
+        assert!(blocks.contains("make 2 blocks, not 1"), "{blocks}");
+    }
+
+    #[test]
+    fn expected_data_is_checked_in_the_simulator() {
+        let kernel = |data: Vec<u8>| KernelSpec {
+            threads: Some(4),
+            expected: Some(ExpectedData { address: 0, data }),
+            ..Default::default()
+        };
+        let build =
+            |spec: &KernelSpec| build_kernel("test", KERNEL, &HardwareOverrides::default(), spec);
+        assert!(build(&kernel(vec![0, 1, 2, 3])).is_ok());
+        assert_eq!(
+            build(&kernel(vec![0, 1, 5, 3])).unwrap_err(),
+            "data memory differs at address 2: expected 5, simulator has 2"
+        );
+    }
+}
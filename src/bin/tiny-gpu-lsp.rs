@@ -0,0 +1,9 @@
+use std::io;
+
+/// Language server for TinyGPU assembly, speaking LSP over stdin and stdout.
+fn main() {
+    if let Err(err) = lib::lsp::run(io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
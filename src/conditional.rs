@@ -0,0 +1,568 @@
+use std::str::FromStr;
+
+use crate::operation::Operation;
+use crate::{LexError, LineError, ParsedLine, Register};
+
+/// Conditional Assembly
+/// ---
+/// Symbolic constants come from `.define NAME VALUE` in the source or `-D NAME=VALUE` on the
+/// command line, and select which lines are assembled:
+///
+/// ```text
+/// .ifndef THREADS
+/// .define THREADS 4       ; a default, unless -D THREADS=... was given
+/// .endif
+/// .threads THREADS
+/// .if THREADS >= 8 && SPREAD != 1
+///     CONST R1, #THREADS
+/// .elif THREADS == 4
+///     ...
+/// .else
+///     ...
+/// .endif
+/// ```
+///
+/// `.if` and `.elif` take an integer expression (`+ - * / %`, comparisons, `&& || !` and
+/// parentheses) where any value but 0 is true, and `.ifdef`/`.ifndef` test whether a name is
+/// defined. In the lines that are assembled, an operand `NAME` or `#NAME` is replaced with the
+/// value. Lines that are left out, and the directives themselves, become empty lines so line
+/// numbers stay the same.
+pub fn conditional_assembly(
+    lines: &[ParsedLine],
+    defines: &[(String, i64)],
+) -> Result<Vec<ParsedLine>, LineError> {
+    // (name, value, line defined on, or None for the command line)
+    let mut symbols: Vec<(String, i64, Option<u32>)> = defines
+        .iter()
+        .map(|(name, value)| (name.clone(), *value, None))
+        .collect();
+    let mut stack: Vec<Conditional> = vec![];
+    let mut output = Vec::with_capacity(lines.len());
+
+    for line in lines {
+        let error = |error| LineError {
+            line_num: line.line_num,
+            error,
+        };
+        let active = stack.iter().all(|conditional| conditional.active);
+        let directive = line.tokens.first().map(|t| t.as_str()).unwrap_or("");
+        let arguments = line.tokens.get(1..).unwrap_or_default();
+        let values = |symbols: &[(String, i64, Option<u32>)]| {
+            symbols
+                .iter()
+                .map(|(name, value, _)| (name.clone(), *value))
+                .collect::<Vec<_>>()
+        };
+
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                // a .if inside lines that are left out is not evaluated
+                let taken = active
+                    && match directive {
+                        ".if" => evaluate(arguments, &values(&symbols)).map_err(error)? != 0,
+                        _ => {
+                            let [name] = arguments else {
+                                return Err(error(LexError::WrongOperandCount {
+                                    operation: directive.to_string(),
+                                    expected: 1,
+                                    found: arguments.len(),
+                                }));
+                            };
+                            symbols.iter().any(|(n, _, _)| n == name) == (directive == ".ifdef")
+                        }
+                    };
+                stack.push(Conditional {
+                    line_num: line.line_num,
+                    outer_active: active,
+                    active: taken,
+                    taken,
+                    seen_else: false,
+                });
+            }
+            ".elif" | ".else" => {
+                let Some(conditional) = stack.last_mut() else {
+                    return Err(error(LexError::InvalidSyntax(format!(
+                        "{directive} without a matching .if"
+                    ))));
+                };
+                if conditional.seen_else {
+                    return Err(error(LexError::InvalidSyntax(format!(
+                        "{directive} after the .else of the .if on line {}",
+                        conditional.line_num + 1
+                    ))));
+                }
+                conditional.active = conditional.outer_active
+                    && !conditional.taken
+                    && match directive {
+                        ".elif" => evaluate(arguments, &values(&symbols)).map_err(error)? != 0,
+                        _ => true,
+                    };
+                conditional.taken |= conditional.active;
+                conditional.seen_else = directive == ".else";
+            }
+            ".endif" => {
+                let Some(_) = stack.pop() else {
+                    return Err(error(LexError::InvalidSyntax(
+                        ".endif without a matching .if".into(),
+                    )));
+                };
+            }
+            ".define" if active => {
+                let (name, value) = match arguments {
+                    [name] => (name, 1),
+                    [name, expression @ ..] => (
+                        name,
+                        evaluate(expression, &values(&symbols)).map_err(error)?,
+                    ),
+                    [] => {
+                        return Err(error(LexError::WrongOperandCount {
+                            operation: ".define".into(),
+                            expected: 2,
+                            found: 0,
+                        }))
+                    }
+                };
+                check_name(name).map_err(error)?;
+                if let Some((_, _, defined)) = symbols.iter().find(|(n, _, _)| n == name) {
+                    let place = match defined {
+                        Some(line_num) => format!("on line {}", line_num + 1),
+                        None => "on the command line".to_string(),
+                    };
+                    return Err(error(LexError::InvalidArgument(format!(
+                        "{name} is already defined {place}"
+                    ))));
+                }
+                symbols.push((name.clone(), value, Some(line.line_num)));
+            }
+            _ if active => {
+                output.push(substitute(line, &values(&symbols)));
+                continue;
+            }
+            _ => {}
+        }
+
+        output.push(ParsedLine {
+            tokens: vec![],
+            comment: line.comment.clone(),
+            line_num: line.line_num,
+        });
+    }
+
+    match stack.last() {
+        Some(conditional) => Err(LineError {
+            line_num: conditional.line_num,
+            error: LexError::InvalidSyntax(".if has no matching .endif".into()),
+        }),
+        None => Ok(output),
+    }
+}
+
+struct Conditional {
+    line_num: u32,
+    outer_active: bool, // whether the lines around the .if are assembled
+    active: bool,       // whether the current branch is assembled
+    taken: bool,        // whether any branch so far was assembled
+    seen_else: bool,
+}
+
+/// Parses a `-D NAME=VALUE` or `-D NAME` (defined as 1) command line define.
+pub fn parse_define(define: &str) -> Result<(String, i64), LexError> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => (name, parse_number(value)?),
+        None => (define, 1),
+    };
+    check_name(name)?;
+    Ok((name.to_string(), value))
+}
+
+fn check_name(name: &str) -> Result<(), LexError> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(LexError::InvalidArgument(format!(
+            "{name} is not a valid name, use letters, digits and '_'"
+        )));
+    }
+    if Register::from_str(name).is_ok() || Operation::from_str(name).is_ok() {
+        return Err(LexError::InvalidArgument(format!(
+            "{name} cannot be defined, it is a register or instruction"
+        )));
+    }
+    Ok(())
+}
+
+/// Replaces every operand naming a symbol with its value
+fn substitute(line: &ParsedLine, symbols: &[(String, i64)]) -> ParsedLine {
+    let value_of = |name: &str| {
+        symbols
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| *value)
+    };
+    // the label and mnemonic are kept as written
+    let skip = match line.tokens.first() {
+        Some(label) if label.ends_with(':') => 2,
+        _ => 1,
+    };
+    let tokens = line
+        .tokens
+        .iter()
+        .enumerate()
+        .map(|(index, token)| {
+            let (prefix, name) = match token.strip_prefix('#') {
+                Some(name) => ("#", name),
+                None => ("", token.as_str()),
+            };
+            match value_of(name) {
+                Some(value) if index >= skip => format!("{prefix}{value}"),
+                _ => token.clone(),
+            }
+        })
+        .collect();
+    ParsedLine {
+        tokens,
+        ..line.clone()
+    }
+}
+
+fn parse_number(literal: &str) -> Result<i64, LexError> {
+    let (negative, digits) = match literal.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, literal),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        digits.parse()
+    }
+    .map_err(|_| LexError::InvalidImmediate(literal.to_string()))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Evaluates the expression of a `.if`, `.elif` or `.define`, split into whitespace separated
+/// arguments by the parser.
+fn evaluate(arguments: &[String], symbols: &[(String, i64)]) -> Result<i64, LexError> {
+    let expression = arguments.join(" ");
+    let tokens = tokenize(&expression)?;
+    if tokens.is_empty() {
+        return Err(LexError::InvalidSyntax("expected an expression".into()));
+    }
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        symbols,
+    };
+    let value = parser.or()?;
+    match tokens.get(parser.position) {
+        Some(token) => Err(LexError::InvalidSyntax(format!(
+            "unexpected `{token}` in `{expression}`"
+        ))),
+        None => Ok(value),
+    }
+}
+
+const OPERATORS: [&str; 16] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")",
+];
+
+fn tokenize(expression: &str) -> Result<Vec<String>, LexError> {
+    let mut tokens = vec![];
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let length = if let Some(operator) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            operator.len()
+        } else {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len())
+        };
+        if length == 0 {
+            return Err(LexError::InvalidSyntax(format!(
+                "unexpected `{}` in `{expression}`",
+                rest.chars().next().unwrap()
+            )));
+        }
+        tokens.push(rest[..length].to_string());
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Precedence climbing over `||`, `&&`, comparisons, `+ -`, `* / %` and unary `! -`
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    symbols: &'a [(String, i64)],
+}
+
+impl Parser<'_> {
+    fn next_if(&mut self, operators: &[&'static str]) -> Option<&'static str> {
+        let token = self.tokens.get(self.position)?;
+        let operator = operators.iter().find(|op| **op == token)?;
+        self.position += 1;
+        Some(operator)
+    }
+
+    fn or(&mut self) -> Result<i64, LexError> {
+        let mut value = self.and()?;
+        while self.next_if(&["||"]).is_some() {
+            let right = self.and()?;
+            value = i64::from(value != 0 || right != 0);
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, LexError> {
+        let mut value = self.comparison()?;
+        while self.next_if(&["&&"]).is_some() {
+            let right = self.comparison()?;
+            value = i64::from(value != 0 && right != 0);
+        }
+        Ok(value)
+    }
+
+    fn comparison(&mut self) -> Result<i64, LexError> {
+        let left = self.sum()?;
+        let Some(operator) = self.next_if(&["==", "!=", "<=", ">=", "<", ">"]) else {
+            return Ok(left);
+        };
+        let right = self.sum()?;
+        let result = match operator {
+            "==" => left == right,
+            "!=" => left != right,
+            "<=" => left <= right,
+            ">=" => left >= right,
+            "<" => left < right,
+            _ => left > right,
+        };
+        Ok(i64::from(result))
+    }
+
+    fn sum(&mut self) -> Result<i64, LexError> {
+        let mut value = self.product()?;
+        while let Some(operator) = self.next_if(&["+", "-"]) {
+            let subtract = operator == "-";
+            let right = self.product()?;
+            value = match subtract {
+                true => value.wrapping_sub(right),
+                false => value.wrapping_add(right),
+            };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, LexError> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.next_if(&["*", "/", "%"]) {
+            let right = self.unary()?;
+            value = match operator {
+                "*" => value.wrapping_mul(right),
+                _ if right == 0 => {
+                    return Err(LexError::InvalidArgument(format!(
+                        "division by zero in `{}`",
+                        self.tokens.join(" ")
+                    )))
+                }
+                "/" => value.wrapping_div(right),
+                _ => value.wrapping_rem(right),
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, LexError> {
+        match self.next_if(&["!", "-", "("]) {
+            Some("!") => Ok(i64::from(self.unary()? == 0)),
+            Some("-") => Ok(self.unary()?.wrapping_neg()),
+            Some(_) => {
+                let value = self.or()?;
+                match self.next_if(&[")"]) {
+                    Some(_) => Ok(value),
+                    None => Err(LexError::InvalidSyntax(format!(
+                        "missing `)` in `{}`",
+                        self.tokens.join(" ")
+                    ))),
+                }
+            }
+            None => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<i64, LexError> {
+        let Some(token) = self.tokens.get(self.position) else {
+            return Err(LexError::InvalidSyntax(format!(
+                "`{}` ends before its last operand",
+                self.tokens.join(" ")
+            )));
+        };
+        self.position += 1;
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(token);
+        }
+        match self.symbols.iter().find(|(name, _)| name == token) {
+            Some((_, value)) => Ok(*value),
+            None if OPERATORS.contains(&token.as_str()) => Err(LexError::InvalidSyntax(format!(
+                "unexpected `{token}` in `{}`",
+                self.tokens.join(" ")
+            ))),
+            None => Err(LexError::InvalidArgument(format!(
+                "{token} is not defined, test it with .ifdef first"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_line;
+
+    /// The tokens of the lines `source` assembles with the -D `defines`, one line per string
+    fn assembled(source: &str, defines: &[(&str, i64)]) -> Result<Vec<String>, LineError> {
+        let lines: Vec<ParsedLine> = source
+            .lines()
+            .enumerate()
+            .map(|(line_num, line)| parse_line(line_num, line).unwrap())
+            .collect();
+        let defines: Vec<(String, i64)> = defines
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect();
+        let output = conditional_assembly(&lines, &defines)?;
+        assert_eq!(output.len(), lines.len(), "line numbers must be kept");
+        Ok(output
+            .iter()
+            .filter(|line| !line.tokens.is_empty())
+            .map(|line| line.tokens.join(" "))
+            .collect())
+    }
+
+    fn value(expression: &str, symbols: &[(&str, i64)]) -> Result<i64, LexError> {
+        let symbols: Vec<(String, i64)> = symbols
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect();
+        evaluate(&[expression.to_string()], &symbols)
+    }
+
+    #[test]
+    fn evaluates_with_precedence() {
+        for (expression, expected) in [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("17 / 5 % 2", 1),
+            ("-2 * -3", 6),
+            ("0x10 + 0b11", 19),
+            ("1 + 1 == 2 && 3 < 2 || !0", 1),
+            ("N >= 8 && N != 9", 1),
+            ("!(N > 4)", 0),
+        ] {
+            assert_eq!(
+                value(expression, &[("N", 8)]).unwrap(),
+                expected,
+                "{expression}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for (expression, expected) in [
+            ("1 / 0", "division by zero"),
+            ("(1 + 2", "missing `)`"),
+            ("1 +", "ends before its last operand"),
+            ("1 2", "unexpected `2`"),
+            ("1 $ 2", "unexpected `$`"),
+            ("UNSET + 1", "UNSET is not defined"),
+        ] {
+            let err = value(expression, &[]).unwrap_err();
+            assert!(err.to_string().contains(expected), "{expression}: {err}");
+        }
+    }
+
+    #[test]
+    fn takes_the_first_true_branch() {
+        let source = ".if N == 1\nCONST R0, #1\n.elif N > 1\nCONST R0, #2\n.elif N > 0\nCONST R0, #3\n.else\nCONST R0, #4\n.endif\nRET";
+        let branch = |n| assembled(source, &[("N", n)]).unwrap();
+        assert_eq!(branch(1), ["CONST R0 #1", "RET"]);
+        // the second .elif is also true, but only the first true branch is assembled
+        assert_eq!(branch(5), ["CONST R0 #2", "RET"]);
+        assert_eq!(branch(0), ["CONST R0 #4", "RET"]);
+    }
+
+    #[test]
+    fn nested_conditionals_follow_the_outer_branch() {
+        let source = ".ifdef OUTER\n.if INNER\nNOP\n.else\nCONST R0, #1\n.endif\n.else\n.if 1\nCONST R0, #2\n.endif\n.endif";
+        assert_eq!(
+            assembled(source, &[("OUTER", 1), ("INNER", 1)]).unwrap(),
+            ["NOP"]
+        );
+        assert_eq!(
+            assembled(source, &[("OUTER", 1), ("INNER", 0)]).unwrap(),
+            ["CONST R0 #1"]
+        );
+        // INNER is never evaluated when the outer branch is left out
+        assert_eq!(assembled(source, &[]).unwrap(), ["CONST R0 #2"]);
+    }
+
+    #[test]
+    fn defines_are_substituted_in_operands() {
+        let source = ".ifndef SIZE\n.define SIZE 4\n.endif\n.define TOTAL SIZE * 2\nLOOP: CONST R0, #TOTAL\nBRnzp LOOP";
+        assert_eq!(
+            assembled(source, &[]).unwrap(),
+            ["LOOP: CONST R0 #8", "BRnzp LOOP"]
+        );
+        assert_eq!(
+            assembled(source, &[("SIZE", 16)]).unwrap(),
+            ["LOOP: CONST R0 #32", "BRnzp LOOP"]
+        );
+    }
+
+    #[test]
+    fn reports_undefined_and_redefined_symbols() {
+        let err = assembled("NOP\n.if MISSING\n.endif", &[]).unwrap_err();
+        assert_eq!(err.line_num, 1);
+        assert!(err.to_string().contains("MISSING is not defined"), "{err}");
+
+        let err = assembled(".define N 2", &[("N", 1)]).unwrap_err();
+        assert!(err.to_string().contains("on the command line"), "{err}");
+        let err = assembled(".define N 1\n.define N 2", &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("already defined on line 1"),
+            "{err}"
+        );
+        assert!(assembled(".define R1 2", &[]).is_err());
+    }
+
+    #[test]
+    fn reports_unmatched_directives() {
+        for (source, line_num, expected) in [
+            (".if 1\nNOP", 0, ".if has no matching .endif"),
+            ("NOP\n.endif", 1, ".endif without a matching .if"),
+            (".else", 0, ".else without a matching .if"),
+            (
+                ".if 0\n.else\n.elif 1\n.endif",
+                2,
+                ".elif after the .else of the .if on line 1",
+            ),
+            (".ifdef\n.endif", 0, ".ifdef takes 1 operand(s), found 0"),
+        ] {
+            let err = assembled(source, &[]).unwrap_err();
+            assert_eq!(err.line_num, line_num, "{source}");
+            assert!(err.to_string().contains(expected), "{source}: {err}");
+        }
+    }
+
+    #[test]
+    fn parses_command_line_defines() {
+        assert_eq!(parse_define("N=0x10").unwrap(), ("N".to_string(), 16));
+        assert_eq!(parse_define("DEBUG").unwrap(), ("DEBUG".to_string(), 1));
+        assert!(parse_define("2N=1").is_err());
+        assert!(parse_define("N=abc").is_err());
+    }
+}
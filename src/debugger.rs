@@ -0,0 +1,476 @@
+use std::io::{self, BufRead, Write};
+
+use crate::assembler::Assembly;
+use crate::immediate::parse_immediate;
+use crate::instruction::{NZP_N, NZP_P, NZP_Z};
+use crate::simulator::{Event, Simulator, Step, MAX_ROUNDS};
+use crate::Register;
+
+/// Step Debugger
+/// ---
+/// A command line debugger over the functional simulator. Each thread runs on its own, so the
+/// debugger follows one "current" thread for stepping and printing, while `continue` runs every
+/// thread one instruction per round until a breakpoint, a watched write, an error or the end.
+const HELP: &str = "\
+commands:
+  step, s [N]           execute N instructions (default 1) on the current thread
+  block, sb [N]         execute N instructions on every thread of the current block
+  continue, c           run all threads until a breakpoint, watched write or error
+  thread, t N           switch to thread N
+  threads               list all threads with their PC
+  break, b LABEL|LINE   set a breakpoint, or list breakpoints without an argument
+  delete, d LABEL|LINE  remove a breakpoint
+  watch, w ADDR         stop when data memory at ADDR is written, or list watches
+  unwatch ADDR          remove a watch
+  regs, r               print the registers and NZP flags of the current thread
+  mem, x ADDR [COUNT]   print COUNT bytes of data memory (default 16)
+  list, l               show the source around the current PC
+  quit, q               exit the debugger";
+
+pub struct Debugger<'a> {
+    source: Vec<&'a str>,
+    assembly: &'a Assembly,
+    sim: Simulator,
+    current: usize,
+    breakpoints: Vec<usize>,
+    watches: Vec<u8>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(source: &'a str, assembly: &'a Assembly, sim: Simulator) -> Debugger<'a> {
+        Debugger {
+            source: source.lines().collect(),
+            assembly,
+            sim,
+            current: 0,
+            breakpoints: vec![],
+            watches: vec![],
+        }
+    }
+
+    /// Reads commands from `input` until `quit` or end of input.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(
+            output,
+            "{} threads in {} blocks, {} instructions. Type `help` for commands.",
+            self.sim.threads.len(),
+            self.sim.blocks(),
+            self.sim.program.len()
+        )?;
+        self.show_location(&mut output)?;
+
+        loop {
+            write!(output, "(debug) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, args)) = words.split_first() else {
+                continue;
+            };
+
+            match command {
+                "quit" | "q" => return Ok(()),
+                "help" | "h" => writeln!(output, "{HELP}")?,
+                "step" | "s" => self.step(&mut output, args, false)?,
+                "block" | "sb" => self.step(&mut output, args, true)?,
+                "continue" | "c" => self.continue_all(&mut output)?,
+                "thread" | "t" => self.switch_thread(&mut output, args)?,
+                "threads" => self.list_threads(&mut output)?,
+                "break" | "b" => self.set_breakpoint(&mut output, args)?,
+                "delete" | "d" => self.delete_breakpoint(&mut output, args)?,
+                "watch" | "w" => self.watch(&mut output, args)?,
+                "unwatch" => self.unwatch(&mut output, args)?,
+                "regs" | "r" => self.print_registers(&mut output)?,
+                "mem" | "x" => self.print_memory(&mut output, args)?,
+                "list" | "l" => self.list(&mut output)?,
+                _ => writeln!(output, "unknown command `{command}`, try `help`")?,
+            }
+        }
+    }
+
+    /// The source line that assembled to `addr`, with its 1-based number
+    fn source_line(&self, addr: usize) -> Option<(usize, &str)> {
+        let line_num = self.assembly.operations.get(addr)?.line_num as usize;
+        Some((line_num + 1, self.source.get(line_num)?.trim()))
+    }
+
+    fn show_location(&self, output: &mut impl Write) -> io::Result<()> {
+        let thread = &self.sim.threads[self.current];
+        let who = format!(
+            "thread {} (block {}, thread {})",
+            self.current, thread.block, thread.thread
+        );
+
+        if thread.done {
+            return writeln!(output, "{who} has returned");
+        }
+        match self.source_line(thread.pc) {
+            Some((line, text)) => writeln!(output, "{who} at {}: line {line} | {text}", thread.pc),
+            None => writeln!(
+                output,
+                "{who} at {}: past the end of the program",
+                thread.pc
+            ),
+        }
+    }
+
+    fn count(output: &mut impl Write, args: &[&str]) -> io::Result<Option<usize>> {
+        match args.first() {
+            None => Ok(Some(1)),
+            Some(arg) => match arg.parse() {
+                Ok(count) => Ok(Some(count)),
+                Err(_) => {
+                    writeln!(output, "expected a count, found `{arg}`")?;
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    fn step(&mut self, output: &mut impl Write, args: &[&str], block: bool) -> io::Result<()> {
+        let Some(count) = Self::count(output, args)? else {
+            return Ok(());
+        };
+
+        for _ in 0..count {
+            let steps = if block {
+                let block = self.sim.threads[self.current].block;
+                self.sim.step_block(block)
+            } else {
+                match self.sim.step_thread(self.current) {
+                    Some(step) => step.map(|step| vec![step]),
+                    None => break,
+                }
+            };
+
+            match steps {
+                Ok(steps) => {
+                    if self.report_watches(output, &steps)? {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    writeln!(output, "error: {err}")?;
+                    break;
+                }
+            }
+        }
+
+        self.show_location(output)
+    }
+
+    fn continue_all(&mut self, output: &mut impl Write) -> io::Result<()> {
+        for _ in 0..MAX_ROUNDS {
+            let steps = match self.sim.step_all() {
+                Ok(steps) => steps,
+                Err(err) => {
+                    writeln!(output, "error: {err}")?;
+                    return self.show_location(output);
+                }
+            };
+
+            if self.report_watches(output, &steps)? {
+                return self.show_location(output);
+            }
+            if self.sim.finished() {
+                return writeln!(output, "all threads have returned");
+            }
+
+            let hit = (0..self.sim.threads.len()).find(|&index| {
+                let thread = &self.sim.threads[index];
+                !thread.done && self.breakpoints.contains(&thread.pc)
+            });
+            if let Some(index) = hit {
+                self.current = index;
+                writeln!(output, "breakpoint at {}", self.sim.threads[index].pc)?;
+                return self.show_location(output);
+            }
+        }
+
+        writeln!(output, "still running after {MAX_ROUNDS} rounds, stopping")?;
+        self.show_location(output)
+    }
+
+    /// Prints the writes to watched addresses, true if there were any
+    fn report_watches(&self, output: &mut impl Write, steps: &[Step]) -> io::Result<bool> {
+        let mut hit = false;
+        for step in steps {
+            for event in &step.events {
+                if let Event::MemoryWrite { addr, value } = *event {
+                    if self.watches.contains(&addr) {
+                        hit = true;
+                        writeln!(
+                            output,
+                            "watch: thread {} wrote {value} to address {addr} at {}",
+                            step.thread, step.pc
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(hit)
+    }
+
+    fn switch_thread(&mut self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        match args.first().and_then(|arg| arg.parse::<usize>().ok()) {
+            Some(index) if index < self.sim.threads.len() => {
+                self.current = index;
+                self.show_location(output)
+            }
+            _ => writeln!(
+                output,
+                "expected a thread between 0 and {}",
+                self.sim.threads.len() - 1
+            ),
+        }
+    }
+
+    fn list_threads(&self, output: &mut impl Write) -> io::Result<()> {
+        for (index, thread) in self.sim.threads.iter().enumerate() {
+            let marker = if index == self.current { '*' } else { ' ' };
+            let state = if thread.done {
+                "returned".to_string()
+            } else {
+                format!("at {}", thread.pc)
+            };
+            writeln!(
+                output,
+                "{marker} thread {index} (block {}, thread {}) {state}",
+                thread.block, thread.thread
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Resolves a label, or a 1-based source line to the first instruction at or after it
+    fn resolve_address(&self, target: &str) -> Option<usize> {
+        if let Some((_, addr)) = self
+            .assembly
+            .label_addresses
+            .iter()
+            .find(|(label, _)| label == target)
+        {
+            return Some(*addr as usize);
+        }
+
+        let line: u32 = target.parse().ok()?;
+        self.assembly
+            .operations
+            .iter()
+            .position(|op| op.line_num + 1 >= line)
+    }
+
+    fn set_breakpoint(&mut self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        let Some(target) = args.first() else {
+            for addr in &self.breakpoints {
+                match self.source_line(*addr) {
+                    Some((line, text)) => writeln!(output, "{addr}: line {line} | {text}")?,
+                    None => writeln!(output, "{addr}")?,
+                }
+            }
+            return Ok(());
+        };
+
+        match self.resolve_address(target) {
+            Some(addr) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+                writeln!(output, "breakpoint at {addr}")
+            }
+            None => writeln!(output, "no label or instruction line `{target}`"),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        match args.first().and_then(|target| self.resolve_address(target)) {
+            Some(addr) if self.breakpoints.contains(&addr) => {
+                self.breakpoints.retain(|&bp| bp != addr);
+                writeln!(output, "deleted breakpoint at {addr}")
+            }
+            _ => writeln!(output, "no such breakpoint"),
+        }
+    }
+
+    fn parse_addr(output: &mut impl Write, arg: Option<&&str>) -> io::Result<Option<u8>> {
+        let value = arg.and_then(|arg| parse_immediate(arg).ok());
+        match value.and_then(|value| u8::try_from(value).ok()) {
+            Some(addr) => Ok(Some(addr)),
+            None => {
+                writeln!(output, "expected a data memory address between 0 and 255")?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn watch(&mut self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        if args.is_empty() {
+            for addr in &self.watches {
+                writeln!(output, "{addr} = {}", self.sim.memory[*addr as usize])?;
+            }
+            return Ok(());
+        }
+
+        if let Some(addr) = Self::parse_addr(output, args.first())? {
+            if !self.watches.contains(&addr) {
+                self.watches.push(addr);
+            }
+            writeln!(output, "watching address {addr}")?;
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        if let Some(addr) = Self::parse_addr(output, args.first())? {
+            self.watches.retain(|&watch| watch != addr);
+        }
+        Ok(())
+    }
+
+    fn print_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        let thread = &self.sim.threads[self.current];
+        for chunk in Register::ALL.chunks(4) {
+            let line: Vec<String> = chunk
+                .iter()
+                .map(|r| format!("{:>10} = {:<3}", r.name(), thread.register(*r)))
+                .collect();
+            writeln!(output, "{}", line.join(" "))?;
+        }
+
+        let flag = |bit: u8, c: char| if thread.nzp & bit != 0 { c } else { '-' };
+        writeln!(
+            output,
+            "       NZP = {}{}{}",
+            flag(NZP_N, 'n'),
+            flag(NZP_Z, 'z'),
+            flag(NZP_P, 'p')
+        )
+    }
+
+    fn print_memory(&self, output: &mut impl Write, args: &[&str]) -> io::Result<()> {
+        let Some(start) = Self::parse_addr(output, args.first())? else {
+            return Ok(());
+        };
+        let count = args
+            .get(1)
+            .and_then(|arg| arg.parse::<usize>().ok())
+            .unwrap_or(16);
+
+        let end = (start as usize)
+            .saturating_add(count)
+            .min(self.sim.memory.len());
+        for row in (start as usize..end).step_by(8) {
+            let bytes: Vec<String> = self.sim.memory[row..(row + 8).min(end)]
+                .iter()
+                .map(|byte| format!("{byte:3}"))
+                .collect();
+            writeln!(output, "{row:3}: {}", bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, output: &mut impl Write) -> io::Result<()> {
+        let pc = self.sim.threads[self.current].pc;
+        let Some(current) = self
+            .assembly
+            .operations
+            .get(pc)
+            .map(|op| op.line_num as usize)
+        else {
+            return self.show_location(output);
+        };
+
+        let first = current.saturating_sub(5);
+        let last = (current + 5).min(self.source.len().saturating_sub(1));
+        for line in first..=last {
+            let marker = if line == current { "=>" } else { "  " };
+            writeln!(output, "{marker} {:4} | {}", line + 1, self.source[line])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::output::Hardware;
+
+    const KERNEL: &str = "\
+.threads 4
+ADD R0, R0, %threadIdx
+CONST R1, #100
+ADD R2, R1, R0
+STORE:
+STR R0, R2
+RET
+";
+
+    /// Runs the debugger over KERNEL with `commands`, returning everything it printed
+    fn session(commands: &str) -> String {
+        let assembly = assemble(KERNEL);
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        let sim = Simulator::new(assembly.program(), &[], launch);
+        let mut output = vec![];
+        Debugger::new(KERNEL, &assembly, sim)
+            .run(commands.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn steps_the_current_thread() {
+        let output = session("s 3\nr\nt 2\nthreads\n");
+        assert!(output.contains("thread 0 (block 0, thread 0) at 3: line 6 | STR R0, R2"));
+        assert!(output.contains("        R2 = 100"), "{output}");
+        assert!(
+            output.contains("* thread 2 (block 0, thread 2) at 0"),
+            "{output}"
+        );
+        assert!(
+            output.contains("  thread 0 (block 0, thread 0) at 3"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints_and_watches() {
+        let output = session("b STORE\nc\nd 5\nw 2\nc\nx 0 4\nc\n");
+        assert!(output.contains("breakpoint at 3\nthread 0"), "{output}");
+        assert!(output.contains("deleted breakpoint at 3"), "{output}");
+        assert!(
+            output.contains("watch: thread 2 wrote 102 to address 2 at 3"),
+            "{output}"
+        );
+        assert!(output.contains("  0: 100 101 102 103"), "{output}");
+        assert!(output.contains("all threads have returned"), "{output}");
+    }
+
+    #[test]
+    fn memory_dumps_stop_at_the_end_of_memory() {
+        let output = session(&format!("x 250 {}\n", usize::MAX));
+        assert!(
+            output.contains("250:   0   0   0   0   0   0\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn reports_bad_arguments() {
+        let output = session("t 4\nb NOWHERE\nd STORE\nw 300\ns many\njump\n");
+        for expected in [
+            "expected a thread between 0 and 3",
+            "no label or instruction line `NOWHERE`",
+            "no such breakpoint",
+            "expected a data memory address between 0 and 255",
+            "expected a count, found `many`",
+            "unknown command `jump`, try `help`",
+        ] {
+            assert!(output.contains(expected), "{expected}\n{output}");
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use serde::Serialize;
+
+use crate::assembler::Assembly;
+use crate::find_unquoted;
+use crate::lint::{
+    LintDiagnostic, LintLevel, DIV_BY_ZERO, FLAGS_NOT_SET, MISSING_RET, SPECIAL_REGISTER_WRITE,
+    UNREACHABLE_CODE, UNUSED_LABEL,
+};
+use crate::{LexError, LineError};
+
+/// Structured Diagnostics
+/// ---
+/// Assembly errors and lint findings as data, for editors and CI to read instead of parsing
+/// `Error: line N: ...`. Each diagnostic carries a stable `code` (the lint name, or the kind of
+/// `LexError`), the columns of the offending token on its line, other locations that explain
+/// it, such as where a duplicate label was first defined, and a suggested fix when there is an
+/// obvious one.
+///
+/// Lines and columns count from 1, and a span ends one column past its last character.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub file: String,
+    pub line: u32,
+    pub span: Span,
+    pub related: Vec<Related>,
+    pub fix: Option<Fix>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Another location a diagnostic refers to
+#[derive(Debug, Clone, Serialize)]
+pub struct Related {
+    pub message: String,
+    pub line: u32,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fix {
+    pub message: String,
+    pub replacement: Option<String>, // for the diagnostic's span
+}
+
+/// The columns of `token` on `line`, or of the whole line without its comment when `token` is
+/// not on it
+pub fn span(line: &str, token: Option<&str>) -> Span {
+    let code = &line[..find_unquoted(line, |c| c == ';').unwrap_or(line.len())];
+    let column = |byte: usize| line[..byte].chars().count() + 1;
+
+    let found = token.filter(|token| !token.is_empty()).and_then(|token| {
+        code.match_indices(token).map(|(at, _)| at).find(|&at| {
+            let before = code[..at].chars().next_back();
+            let after = code[at + token.len()..].chars().next();
+            before.is_none_or(|c| c.is_whitespace() || c == ',')
+                && after.is_none_or(|c| c.is_whitespace() || c == ',' || c == ':')
+        })
+    });
+    match (found, token) {
+        (Some(at), Some(token)) => Span {
+            start: column(at),
+            end: column(at + token.len()),
+        },
+        _ => {
+            let start = code.len() - code.trim_start().len();
+            Span {
+                start: column(start),
+                end: column(code.trim_end().len().max(start)),
+            }
+        }
+    }
+}
+
+/// A label as the source writes it: local labels without the global label they belong to,
+/// numeric labels without the line they are defined on
+fn written(label: &str) -> &str {
+    let label = label.split('@').next().unwrap_or(label);
+    match label.find('.') {
+        Some(at) if at > 0 => &label[at..],
+        _ => label,
+    }
+}
+
+/// The token an error is about, as written in the source
+fn token(error: &LexError) -> Option<&str> {
+    match error {
+        LexError::InvalidImmediate(literal) => Some(literal),
+        LexError::MalformedOperand { operand, .. } => Some(operand),
+        LexError::WrongOperandCount { operation, .. } => Some(operation),
+        LexError::ImmediateOutOfRange { literal, .. } => Some(literal),
+        LexError::UnsupportedLoop { label, .. }
+        | LexError::UndefinedLabel(label)
+        | LexError::DuplicateLabel { label, .. }
+        | LexError::LabelAtEnd(label) => Some(written(label)),
+        _ => None,
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn error_fix(error: &LexError, assembly: &Assembly) -> Option<Fix> {
+    match error {
+        LexError::UndefinedLabel(label) => {
+            // the closest label the source does define, if it is close enough to be a typo
+            let (closest, distance) = assembly
+                .label_addresses
+                .iter()
+                .chain(&assembly.data_labels)
+                .map(|(defined, _)| defined)
+                .filter(|defined| !defined.contains('@'))
+                .map(|defined| (defined, edit_distance(label, defined)))
+                .min_by_key(|(_, distance)| *distance)?;
+            (distance <= (label.len() / 3).max(1)).then(|| Fix {
+                message: format!("did you mean {}?", written(closest)),
+                replacement: Some(written(closest).to_string()),
+            })
+        }
+        LexError::LabelAtEnd(label) => Some(Fix {
+            message: format!("add RET after {}", written(label)),
+            replacement: None,
+        }),
+        _ => None,
+    }
+}
+
+/// The diagnostic of an assembly error in `source`, the file at `file`. Fixes that name a label
+/// come from `assembly`, which is None for errors found before assembling, such as compiling a
+/// kernel.
+pub fn error_diagnostic(
+    file: &str,
+    source: &str,
+    assembly: Option<&Assembly>,
+    err: &LineError,
+) -> Diagnostic {
+    let lines: Vec<&str> = source.lines().collect();
+    let line_span = |line_num: u32, token: Option<&str>| {
+        span(
+            lines.get(line_num as usize).copied().unwrap_or_default(),
+            token,
+        )
+    };
+
+    let related = match &err.error {
+        LexError::DuplicateLabel { label, first_line } => vec![Related {
+            message: format!("{} is first defined here", written(label)),
+            line: first_line + 1,
+            span: line_span(*first_line, Some(written(label))),
+        }],
+        _ => vec![],
+    };
+
+    Diagnostic {
+        severity: Severity::Error,
+        code: err.error.code(),
+        message: err.error.to_string(),
+        file: file.to_string(),
+        line: err.line_num + 1,
+        span: line_span(err.line_num, token(&err.error)),
+        related,
+        fix: assembly.and_then(|assembly| error_fix(&err.error, assembly)),
+    }
+}
+
+/// The diagnostic of a problem with the file at `file` as a whole, such as it not being
+/// readable. It is reported at the start of the file, on line 1 and column 1.
+pub fn file_diagnostic(file: &str, code: &'static str, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        code,
+        message,
+        file: file.to_string(),
+        line: 1,
+        span: Span { start: 1, end: 1 },
+        related: vec![],
+        fix: None,
+    }
+}
+
+/// The diagnostic of a lint finding in `source`, the file at `file`
+pub fn lint_diagnostic(file: &str, source: &str, lint: &LintDiagnostic) -> Diagnostic {
+    let line = source
+        .lines()
+        .nth(lint.line_num as usize)
+        .unwrap_or_default();
+    // an unused label is reported on its definition, the first thing on the line
+    let token = match lint.lint {
+        UNUSED_LABEL => line
+            .split_whitespace()
+            .next()
+            .map(|t| t.trim_end_matches(':')),
+        _ => None,
+    };
+
+    let fix = match lint.lint {
+        FLAGS_NOT_SET => Some("set the flags with a CMP before the branch"),
+        SPECIAL_REGISTER_WRITE => Some("write to one of R0-R12 instead"),
+        DIV_BY_ZERO => Some("check the divisor with a CMP and branch around the DIV"),
+        UNREACHABLE_CODE => Some("remove the instructions, or branch to them"),
+        MISSING_RET => Some("end the program with RET"),
+        UNUSED_LABEL => Some("remove the label"),
+        _ => None,
+    };
+
+    Diagnostic {
+        severity: match lint.level {
+            LintLevel::Deny => Severity::Error,
+            _ => Severity::Warning,
+        },
+        code: lint.lint,
+        message: lint.message.clone(),
+        file: file.to_string(),
+        line: lint.line_num + 1,
+        span: span(line, token),
+        related: vec![],
+        fix: fix.map(|message| Fix {
+            message: message.to_string(),
+            replacement: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        let assembly = assemble(source);
+        assembly
+            .errors
+            .iter()
+            .map(|err| error_diagnostic("test.asm", source, Some(&assembly), err))
+            .collect()
+    }
+
+    #[test]
+    fn spans_cover_the_offending_token() {
+        let [diagnostic] = &diagnostics("CONST R0, #300 ; too big\nRET\n")[..] else {
+            panic!("expected one diagnostic");
+        };
+        assert_eq!(diagnostic.code, "immediate-out-of-range");
+        assert_eq!(
+            (diagnostic.line, diagnostic.span),
+            (1, Span { start: 11, end: 15 })
+        );
+    }
+
+    #[test]
+    fn duplicate_labels_point_at_the_first_definition() {
+        let [diagnostic] = &diagnostics("LOOP: NOP\n  LOOP: RET\n")[..] else {
+            panic!("expected one diagnostic");
+        };
+        assert_eq!(diagnostic.span, Span { start: 3, end: 7 });
+        assert_eq!(diagnostic.related[0].line, 1);
+        assert_eq!(diagnostic.related[0].span, Span { start: 1, end: 5 });
+    }
+
+    #[test]
+    fn undefined_labels_suggest_a_close_one() {
+        let diagnostics = diagnostics("LOOP: NOP\nBRnzp LOPP\nBRnzp ELSEWHERE\nRET\n");
+        let fixes: Vec<Option<String>> = diagnostics
+            .iter()
+            .map(|d| d.fix.as_ref().and_then(|fix| fix.replacement.clone()))
+            .collect();
+        assert_eq!(fixes, [Some("LOOP".to_string()), None]);
+    }
+
+    #[test]
+    fn spans_skip_comments_and_partial_matches() {
+        assert_eq!(
+            span("  ADD R10, R1, R1 ; R1", Some("R1")),
+            Span { start: 12, end: 14 }
+        );
+        assert_eq!(span("  NOP ; note", None), Span { start: 3, end: 6 });
+    }
+}
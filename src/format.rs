@@ -0,0 +1,181 @@
+use crate::{parse_source, LineError};
+
+/// Canonical Source Formatting
+/// ---
+/// - directives and labels start at column 0
+/// - instructions (and comment-only lines) under a label are indented by `INDENT`
+/// - mnemonics and registers are uppercase, branch flags lowercase (`BRnz`)
+/// - operands are separated by `, `, directive arguments by a single space
+/// - trailing comments of a blank-line separated group share one column, at least `COMMENT_COLUMN`
+/// - runs of blank lines collapse to one, and the file ends with a single newline
+pub const INDENT: &str = "    ";
+pub const COMMENT_COLUMN: usize = 32;
+
+pub fn format_source(contents: &str) -> Result<String, LineError> {
+    let parsed_lines = parse_source(contents)?;
+
+    // (code, comment) per output line, None for a blank line
+    let mut lines: Vec<Option<(String, Option<String>)>> = vec![];
+    let mut in_label = false;
+
+    for parsed in &parsed_lines {
+        let comment = parsed
+            .comment
+            .as_ref()
+            .map(|comment| comment.trim_end().to_string());
+
+        let Some(first) = parsed.tokens.first() else {
+            match comment {
+                Some(comment) => {
+                    let indent = if in_label { INDENT } else { "" };
+                    lines.push(Some((indent.to_string(), Some(comment))));
+                }
+                None => lines.push(None),
+            }
+            continue;
+        };
+
+        if first.starts_with('.') && !first.ends_with(':') {
+            lines.push(Some((format_directive(&parsed.tokens), comment)));
+        } else if first.ends_with(':') {
+            in_label = true;
+            if parsed.tokens.len() == 1 {
+                lines.push(Some((first.clone(), comment)));
+            } else if parsed.tokens[1].starts_with('.') {
+                // a directive sharing the label's line (`A: .data 1 2`) moves under it
+                lines.push(Some((first.clone(), None)));
+                lines.push(Some((format_directive(&parsed.tokens[1..]), comment)));
+            } else {
+                // an instruction sharing the label's line moves under it
+                lines.push(Some((first.clone(), None)));
+                let instruction = INDENT.to_string() + &format_instruction(&parsed.tokens[1..]);
+                lines.push(Some((instruction, comment)));
+            }
+        } else {
+            let indent = if in_label { INDENT } else { "" };
+            lines.push(Some((
+                indent.to_string() + &format_instruction(&parsed.tokens),
+                comment,
+            )));
+        }
+    }
+
+    // collapse blank runs, drop leading and trailing blanks
+    let mut collapsed: Vec<Option<(String, Option<String>)>> = vec![];
+    for line in lines {
+        if line.is_none() && collapsed.last().is_none_or(|last| last.is_none()) {
+            continue;
+        }
+        collapsed.push(line);
+    }
+    while collapsed.last().is_some_and(|last| last.is_none()) {
+        collapsed.pop();
+    }
+
+    let mut output = String::new();
+    for group in collapsed.split(|line| line.is_none()) {
+        let column = group
+            .iter()
+            .flatten()
+            .filter(|(code, comment)| comment.is_some() && !code.trim().is_empty())
+            .map(|(code, _)| code.len() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(COMMENT_COLUMN);
+
+        for (code, comment) in group.iter().flatten() {
+            match comment {
+                Some(comment) if code.trim().is_empty() => {
+                    output.push_str(&format!("{code};{comment}"));
+                }
+                Some(comment) => {
+                    output.push_str(&format!("{code:<column$};{comment}"));
+                }
+                None => output.push_str(code),
+            }
+            output.push('\n');
+        }
+        output.push('\n');
+    }
+    output.pop();
+
+    Ok(output)
+}
+
+fn format_directive(tokens: &[String]) -> String {
+    let mut formatted = tokens[0].to_lowercase();
+    for arg in &tokens[1..] {
+        formatted.push(' ');
+        formatted.push_str(arg);
+    }
+    formatted
+}
+
+fn format_instruction(tokens: &[String]) -> String {
+    let mnemonic = format_mnemonic(&tokens[0]);
+
+    let operands: Vec<String> = tokens[1..].iter().map(|op| format_operand(op)).collect();
+
+    if operands.is_empty() {
+        mnemonic
+    } else {
+        mnemonic + " " + &operands.join(", ")
+    }
+}
+
+fn format_mnemonic(token: &str) -> String {
+    let upper = token.to_uppercase();
+    // branch flags stay lowercase (BRnzp)
+    match upper.strip_prefix("BR") {
+        Some(flags) if flags.chars().all(|c| "NZP".contains(c)) => {
+            "BR".to_string() + &flags.to_lowercase()
+        }
+        _ => upper,
+    }
+}
+
+fn format_operand(operand: &str) -> String {
+    let lower = operand.to_lowercase();
+    for special in ["%blockIdx", "%blockDim", "%threadIdx"] {
+        if lower == special.to_lowercase() {
+            return special.to_string();
+        }
+    }
+
+    let is_register =
+        lower.starts_with('r') && lower.len() > 1 && lower[1..].chars().all(|c| c.is_ascii_digit());
+    if is_register {
+        lower.to_uppercase()
+    } else {
+        operand.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_to_canonical_form() {
+        let source = "start: const r1,#1   ;one\n\n\n  brNZP start\n.THREADS 4\nret\n";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "start:\n    CONST R1, #1                ;one\n\n    BRnzp start\n.threads 4\n    RET\n"
+        );
+    }
+
+    #[test]
+    fn directives_after_a_label_stay_directives() {
+        assert_eq!(
+            format_source("A: .data 1 2 ; bytes\nRET\n").unwrap(),
+            "A:\n.data 1 2                       ; bytes\n    RET\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let source = "LOOP: ADD R1, R1, R2 ; step\nA: .data 1\n; note\n.x: BRn .x\nRET\n";
+        let once = format_source(source).unwrap();
+        assert_eq!(format_source(&once).unwrap(), once);
+    }
+}
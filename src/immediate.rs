@@ -0,0 +1,173 @@
+use crate::LexError;
+
+/// Immediate Literals
+/// ---
+/// Accepted forms (an optional leading `#` is stripped first):
+/// - decimal: `42`, `-3`
+/// - hex: `0x2A`, `-0x03`
+/// - binary: `0b101010`
+/// - character: `'A'`, `'\n'`, `'\''`
+///
+/// Negative values are lowered to two's complement when fitted to a field.
+pub fn parse_immediate(s: &str) -> Result<i64, LexError> {
+    let literal = s.strip_prefix('#').unwrap_or(s);
+
+    if literal.starts_with('\'') {
+        return parse_char_literal(literal);
+    }
+
+    let (negative, digits) = match literal.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, literal),
+    };
+
+    let (radix, digits) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (16, hex)
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (2, bin)
+    } else {
+        (10, digits)
+    };
+
+    // from_str_radix would otherwise accept a second sign ("--3", "-+3")
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(LexError::InvalidImmediate(s.to_string()));
+    }
+
+    let magnitude = i64::from_str_radix(digits, radix)
+        .map_err(|_| LexError::InvalidImmediate(s.to_string()))?;
+
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+fn parse_char_literal(literal: &str) -> Result<i64, LexError> {
+    let bad = || LexError::InvalidImmediate(literal.to_string());
+
+    let inner = literal
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+        .ok_or_else(bad)?;
+
+    let mut chars = inner.chars();
+    let c = match (chars.next(), chars.next(), chars.next()) {
+        (Some('\\'), Some(escaped), None) => match escaped {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            _ => return Err(bad()),
+        },
+        (Some(c), None, None) => c,
+        _ => return Err(bad()),
+    };
+
+    if c.is_ascii() {
+        Ok(c as i64)
+    } else {
+        Err(bad())
+    }
+}
+
+/// Checks that `value` fits a `bits` wide field, either as a signed or an unsigned number,
+/// and returns its two's complement encoding in the low `bits` bits.
+pub fn fit_immediate(literal: &str, value: i64, bits: u32) -> Result<u64, LexError> {
+    let (min, max) = immediate_range(bits);
+
+    if value < min || value > max {
+        return Err(LexError::ImmediateOutOfRange {
+            literal: literal.to_string(),
+            value,
+            min,
+            max,
+        });
+    }
+
+    let mask = if bits >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    };
+    Ok(value as u64 & mask)
+}
+
+/// The accepted range of a `bits` wide field: the most negative signed value up to the largest unsigned value.
+pub fn immediate_range(bits: u32) -> (i64, i64) {
+    let bits = bits.clamp(1, 63);
+    (-(1i64 << (bits - 1)), (1i64 << bits) - 1)
+}
+
+/// Parses an immediate operand for an 8-bit field (CONST, .data).
+pub fn parse_imm8(s: &str) -> Result<u8, LexError> {
+    let value = parse_immediate(s)?;
+    Ok(fit_immediate(s, value, 8)? as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_literal_form() {
+        for (literal, expected) in [
+            ("42", 42),
+            ("#42", 42),
+            ("-3", -3),
+            ("#0x2A", 42),
+            ("0X2a", 42),
+            ("-0x03", -3),
+            ("0b101010", 42),
+            ("#'A'", 65),
+            ("'\\n'", 10),
+            ("'\\''", 39),
+            ("'\\\\'", 92),
+        ] {
+            assert_eq!(parse_immediate(literal).unwrap(), expected, "{literal}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_literals() {
+        for literal in [
+            "", "#", "--3", "-+3", "+3", "0x", "0b102", "12a", "'AB'", "''", "'\\q'", "'é'", "'A",
+        ] {
+            assert!(
+                matches!(parse_immediate(literal), Err(LexError::InvalidImmediate(_))),
+                "{literal}"
+            );
+        }
+    }
+
+    #[test]
+    fn negative_values_lower_to_twos_complement() {
+        assert_eq!(parse_imm8("#-1").unwrap(), 0xFF);
+        assert_eq!(parse_imm8("#-128").unwrap(), 0x80);
+        assert_eq!(parse_imm8("#255").unwrap(), 0xFF);
+        assert_eq!(fit_immediate("-2", -2, 16).unwrap(), 0xFFFE);
+    }
+
+    #[test]
+    fn values_outside_the_field_are_out_of_range() {
+        assert_eq!(immediate_range(8), (-128, 255));
+        for literal in ["#256", "#-129"] {
+            assert!(
+                matches!(
+                    parse_imm8(literal),
+                    Err(LexError::ImmediateOutOfRange {
+                        min: -128,
+                        max: 255,
+                        ..
+                    })
+                ),
+                "{literal}"
+            );
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+
+use crate::operation::Operation;
+use crate::Register;
+
+/// Decoded Instructions
+/// ---
+/// The fields of a 16-bit TinyGPU instruction word, for passes that analyse the
+/// assembled program rather than its source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Branch {
+        nzp: u8,
+        target: u8,
+    },
+    Cmp {
+        rs: Register,
+        rt: Register,
+    },
+    Add {
+        rd: Register,
+        rs: Register,
+        rt: Register,
+    },
+    Sub {
+        rd: Register,
+        rs: Register,
+        rt: Register,
+    },
+    Mul {
+        rd: Register,
+        rs: Register,
+        rt: Register,
+    },
+    Div {
+        rd: Register,
+        rs: Register,
+        rt: Register,
+    },
+    Ldr {
+        rd: Register,
+        rs: Register,
+    },
+    Str {
+        rs: Register,
+        rt: Register,
+    },
+    Const {
+        rd: Register,
+        imm: u8,
+    },
+    Ret,
+}
+
+pub const NZP_N: u8 = 0b100;
+pub const NZP_Z: u8 = 0b010;
+pub const NZP_P: u8 = 0b001;
+
+impl Instruction {
+    /// Decodes an instruction word, None for an unused opcode.
+    pub fn decode(word: u16) -> Option<Instruction> {
+        let reg = |shift: u16| Register::from_bits(((word >> shift) & 0xF) as u8);
+        let (rd, rs, rt) = (reg(8), reg(4), reg(0));
+
+        let instruction = match word >> 12 {
+            0b0000 => Instruction::Nop,
+            0b0001 => Instruction::Branch {
+                nzp: ((word >> 9) & 0b111) as u8,
+                target: (word & 0xFF) as u8,
+            },
+            0b0010 => Instruction::Cmp { rs, rt },
+            0b0011 => Instruction::Add { rd, rs, rt },
+            0b0100 => Instruction::Sub { rd, rs, rt },
+            0b0101 => Instruction::Mul { rd, rs, rt },
+            0b0110 => Instruction::Div { rd, rs, rt },
+            0b0111 => Instruction::Ldr { rd, rs },
+            0b1000 => Instruction::Str { rs, rt },
+            0b1001 => Instruction::Const {
+                rd,
+                imm: (word & 0xFF) as u8,
+            },
+            0b1111 => Instruction::Ret,
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /// Decodes the binary string of an assembled MachineLine.
+    pub fn from_bin(bin: &str) -> Option<Instruction> {
+        u16::from_str_radix(bin, 2)
+            .ok()
+            .and_then(Instruction::decode)
+    }
+
+    pub fn encode(&self) -> u16 {
+        let opcode = (u16::from_str_radix(self.operation().as_opcode(), 2).unwrap()) << 12;
+        let reg = |r: &Register, shift: u16| (r.index() as u16) << shift;
+
+        opcode
+            | match self {
+                Instruction::Nop | Instruction::Ret => 0,
+                Instruction::Branch { nzp, target } => ((*nzp as u16) << 9) | *target as u16,
+                Instruction::Cmp { rs, rt } | Instruction::Str { rs, rt } => {
+                    reg(rs, 4) | reg(rt, 0)
+                }
+                Instruction::Add { rd, rs, rt }
+                | Instruction::Sub { rd, rs, rt }
+                | Instruction::Mul { rd, rs, rt }
+                | Instruction::Div { rd, rs, rt } => reg(rd, 8) | reg(rs, 4) | reg(rt, 0),
+                Instruction::Ldr { rd, rs } => reg(rd, 8) | reg(rs, 4),
+                Instruction::Const { rd, imm } => reg(rd, 8) | *imm as u16,
+            }
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            Instruction::Nop => Operation::NOP,
+            Instruction::Branch { .. } => Operation::BRnzp,
+            Instruction::Cmp { .. } => Operation::CMP,
+            Instruction::Add { .. } => Operation::ADD,
+            Instruction::Sub { .. } => Operation::SUB,
+            Instruction::Mul { .. } => Operation::MUL,
+            Instruction::Div { .. } => Operation::DIV,
+            Instruction::Ldr { .. } => Operation::LDR,
+            Instruction::Str { .. } => Operation::STR,
+            Instruction::Const { .. } => Operation::CONST,
+            Instruction::Ret => Operation::RET,
+        }
+    }
+
+    /// The register this instruction writes, if any.
+    pub fn dest(&self) -> Option<Register> {
+        match *self {
+            Instruction::Add { rd, .. }
+            | Instruction::Sub { rd, .. }
+            | Instruction::Mul { rd, .. }
+            | Instruction::Div { rd, .. }
+            | Instruction::Ldr { rd, .. }
+            | Instruction::Const { rd, .. } => Some(rd),
+            _ => None,
+        }
+    }
+
+    /// The registers this instruction reads.
+    pub fn sources(&self) -> Vec<Register> {
+        match *self {
+            Instruction::Cmp { rs, rt } | Instruction::Str { rs, rt } => vec![rs, rt],
+            Instruction::Add { rs, rt, .. }
+            | Instruction::Sub { rs, rt, .. }
+            | Instruction::Mul { rs, rt, .. }
+            | Instruction::Div { rs, rt, .. } => vec![rs, rt],
+            Instruction::Ldr { rs, .. } => vec![rs],
+            _ => vec![],
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Instruction::Ldr { .. } | Instruction::Str { .. })
+    }
+
+    /// Addresses execution may continue at after the instruction at `addr`. A BRnzp with all
+    /// three flags always branches once a CMP has run (`flags_set`), since CMP sets exactly one
+    /// of them; before any CMP the NZP register is 0 and it falls through.
+    pub fn successors(&self, addr: usize, flags_set: bool) -> Vec<usize> {
+        match *self {
+            Instruction::Ret => vec![],
+            Instruction::Branch { nzp: 0b111, target } if flags_set => vec![target as usize],
+            Instruction::Branch { target, .. } => vec![addr + 1, target as usize],
+            _ => vec![addr + 1],
+        }
+    }
+}
+
+/// Formats the instruction back into assembly, with branch targets as addresses
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.operation().name();
+        match *self {
+            Instruction::Nop | Instruction::Ret => write!(f, "{name}"),
+            Instruction::Branch { nzp, target } => {
+                let flags: String = [(NZP_N, 'n'), (NZP_Z, 'z'), (NZP_P, 'p')]
+                    .iter()
+                    .filter(|(bit, _)| nzp & bit != 0)
+                    .map(|(_, c)| *c)
+                    .collect();
+                write!(f, "BR{flags} #{target}")
+            }
+            Instruction::Cmp { rs, rt } | Instruction::Str { rs, rt } => {
+                write!(f, "{name} {}, {}", rs.name(), rt.name())
+            }
+            Instruction::Add { rd, rs, rt }
+            | Instruction::Sub { rd, rs, rt }
+            | Instruction::Mul { rd, rs, rt }
+            | Instruction::Div { rd, rs, rt } => {
+                write!(f, "{name} {}, {}, {}", rd.name(), rs.name(), rt.name())
+            }
+            Instruction::Ldr { rd, rs } => write!(f, "{name} {}, {}", rd.name(), rs.name()),
+            Instruction::Const { rd, imm } => write!(f, "{name} {}, #{imm}", rd.name()),
+        }
+    }
+}
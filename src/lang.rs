@@ -0,0 +1,768 @@
+use std::collections::BTreeSet;
+
+use crate::format::format_source;
+use crate::simulator::DATA_MEMORY_SIZE;
+use crate::{LexError, LineError, Register};
+
+/// Kernel Language
+/// ---
+/// A small structured front end that compiles to TinyGPU assembly, so index arithmetic does
+/// not have to be written out by hand:
+///
+/// ```text
+/// threads 8;
+/// data A = [0, 1, 2, 3, 4, 5, 6, 7];   // initial data, laid out in declaration order
+/// data B = [0, 1, 2, 3, 4, 5, 6, 7];
+/// data C[8];                           // zeroed
+///
+/// let i = blockIdx * blockDim + threadIdx;
+/// for k in 0..4 unroll 2 {             // constant bounds, optional .unroll factor
+///     C[i] = C[i] + B[i];
+/// }
+/// if A[i] < 4 { C[i] = C[i] + 1; } else { C[i] = 0; }
+/// ```
+///
+/// Each array is a `.data` block labeled with its name, and element addresses are computed
+/// from `CONST Rd, NAME`. An unrolled loop body may not contain `if` or `for`, which compile to
+/// labels and branches the assembler cannot copy.
+///
+/// Expressions are 8-bit: `+ - * /` with the usual precedence, parentheses, integer literals,
+/// variables, array elements and the `blockIdx`, `blockDim` and `threadIdx` builtins. Conditions
+/// compare two expressions unsigned with `< <= > >= == !=`. Variables become virtual registers,
+/// so the assembler allocates them; the output is assembly source for the usual assembler.
+pub fn compile(source: &str) -> Result<String, LineError> {
+    let tokens = tokenize(source)?;
+    let program = Parser {
+        tokens: &tokens,
+        position: 0,
+    }
+    .program()?;
+
+    let mut compiler = Compiler::default();
+    compiler.program(&program)?;
+    let assembly = compiler.finish(&program);
+    Ok(format_source(&assembly).unwrap_or(assembly))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u32),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 20] = [
+    "..", "<=", ">=", "==", "!=", "(", ")", "[", "]", "{", "}", ";", ",", "=", "+", "-", "*", "/",
+    "<", ">",
+];
+
+type Spanned = (Token, u32); // with its 0-based line
+
+fn syntax_error(line: u32, message: String) -> LineError {
+    LineError {
+        line_num: line,
+        error: LexError::InvalidSyntax(message),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Spanned>, LineError> {
+    let mut tokens = vec![];
+    for (line_num, line) in source.lines().enumerate() {
+        let line_num = line_num as u32;
+        let code = line.split("//").next().unwrap();
+        let mut rest = code.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let length = if c.is_ascii_alphabetic() || c == '_' {
+                let length = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                tokens.push((Token::Ident(rest[..length].to_string()), line_num));
+                length
+            } else if c.is_ascii_digit() {
+                let length = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                let literal = &rest[..length];
+                let value = match literal.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => literal.parse(),
+                }
+                .map_err(|_| syntax_error(line_num, format!("`{literal}` is not a number")))?;
+                tokens.push((Token::Number(value), line_num));
+                length
+            } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                tokens.push((Token::Symbol(symbol), line_num));
+                symbol.len()
+            } else {
+                return Err(syntax_error(
+                    line_num,
+                    format!("unexpected character `{c}`"),
+                ));
+            };
+            rest = rest[length..].trim_start();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(u8),
+    Var(String),
+    Builtin(Register),
+    Index(String, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    op: &'static str,
+    left: Expr,
+    right: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Let(String, Expr),
+    Assign(String, Expr),
+    Store(String, Expr, Expr),
+    For {
+        var: String,
+        start: u8,
+        end: u8,
+        unroll: Option<u32>,
+        body: Vec<(Stmt, u32)>,
+    },
+    If(Condition, Vec<(Stmt, u32)>, Vec<(Stmt, u32)>),
+}
+
+#[derive(Debug, Default)]
+struct Program {
+    threads: Option<u32>,
+    arrays: Vec<(String, Vec<u8>)>,
+    body: Vec<(Stmt, u32)>,
+}
+
+const KEYWORDS: [&str; 11] = [
+    "let",
+    "for",
+    "in",
+    "if",
+    "else",
+    "threads",
+    "data",
+    "unroll",
+    "blockIdx",
+    "blockDim",
+    "threadIdx",
+];
+
+struct Parser<'a> {
+    tokens: &'a [Spanned],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> u32 {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(0, |(_, line)| *line)
+    }
+
+    fn error(&self, expected: &str) -> LineError {
+        let found = match self.peek() {
+            Some(Token::Ident(name)) => format!("`{name}`"),
+            Some(Token::Number(value)) => format!("`{value}`"),
+            Some(Token::Symbol(symbol)) => format!("`{symbol}`"),
+            None => "the end of the kernel".into(),
+        };
+        syntax_error(self.line(), format!("expected {expected}, found {found}"))
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let matches = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+            || matches!(self.peek(), Some(Token::Ident(s)) if s == symbol);
+        if matches {
+            self.position += 1;
+        }
+        matches
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), LineError> {
+        match self.eat(symbol) {
+            true => Ok(()),
+            false => Err(self.error(&format!("`{symbol}`"))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, LineError> {
+        match self.peek() {
+            Some(Token::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32, LineError> {
+        match self.peek() {
+            Some(&Token::Number(value)) if value <= max => {
+                self.position += 1;
+                Ok(value)
+            }
+            Some(Token::Number(value)) => Err(syntax_error(
+                self.line(),
+                format!("{value} is larger than {max}"),
+            )),
+            _ => Err(self.error("a number")),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program, LineError> {
+        let mut program = Program::default();
+        while self.peek().is_some() {
+            let line = self.line();
+            if self.eat("threads") {
+                program.threads = Some(self.number(u32::MAX)?);
+                self.expect(";")?;
+            } else if self.eat("data") {
+                let name = self.ident()?;
+                let values = if self.eat("[") {
+                    let length = self.number(DATA_MEMORY_SIZE as u32)?;
+                    self.expect("]")?;
+                    vec![0; length as usize]
+                } else {
+                    self.expect("=")?;
+                    self.expect("[")?;
+                    let mut values = vec![];
+                    while !self.eat("]") {
+                        if !values.is_empty() {
+                            self.expect(",")?;
+                        }
+                        values.push(self.number(u8::MAX as u32)? as u8);
+                    }
+                    values
+                };
+                self.expect(";")?;
+                if program.arrays.iter().any(|(other, _)| *other == name) {
+                    return Err(syntax_error(
+                        line,
+                        format!("array {name} is declared twice"),
+                    ));
+                }
+                program.arrays.push((name, values));
+            } else {
+                program.body.push(self.statement()?);
+            }
+        }
+        Ok(program)
+    }
+
+    fn block(&mut self) -> Result<Vec<(Stmt, u32)>, LineError> {
+        self.expect("{")?;
+        let mut body = vec![];
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return Err(self.error("`}`"));
+            }
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<(Stmt, u32), LineError> {
+        let line = self.line();
+        let statement = if self.eat("let") {
+            let name = self.ident()?;
+            self.expect("=")?;
+            let value = self.expr()?;
+            self.expect(";")?;
+            Stmt::Let(name, value)
+        } else if self.eat("for") {
+            let var = self.ident()?;
+            self.expect("in")?;
+            let start = self.number(u8::MAX as u32)? as u8;
+            self.expect("..")?;
+            let end = self.number(u8::MAX as u32)? as u8;
+            let unroll = match self.eat("unroll") {
+                true => Some(self.number(u8::MAX as u32)?),
+                false => None,
+            };
+            let body = self.block()?;
+            Stmt::For {
+                var,
+                start,
+                end,
+                unroll,
+                body,
+            }
+        } else if self.eat("if") {
+            let left = self.expr()?;
+            let op = ["<=", ">=", "==", "!=", "<", ">"]
+                .into_iter()
+                .find(|op| self.eat(op))
+                .ok_or_else(|| self.error("a comparison"))?;
+            let right = self.expr()?;
+            let then = self.block()?;
+            let otherwise = match self.eat("else") {
+                true if matches!(self.peek(), Some(Token::Ident(s)) if s == "if") => {
+                    vec![self.statement()?]
+                }
+                true => self.block()?,
+                false => vec![],
+            };
+            Stmt::If(Condition { op, left, right }, then, otherwise)
+        } else {
+            let name = self.ident().map_err(|_| self.error("a statement"))?;
+            let statement = if self.eat("[") {
+                let index = self.expr()?;
+                self.expect("]")?;
+                self.expect("=")?;
+                Stmt::Store(name, index, self.expr()?)
+            } else {
+                self.expect("=")?;
+                Stmt::Assign(name, self.expr()?)
+            };
+            self.expect(";")?;
+            statement
+        };
+        Ok((statement, line))
+    }
+
+    fn expr(&mut self) -> Result<Expr, LineError> {
+        let mut left = self.term()?;
+        loop {
+            let op = match () {
+                _ if self.eat("+") => BinaryOp::Add,
+                _ if self.eat("-") => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, LineError> {
+        let mut left = self.atom()?;
+        loop {
+            let op = match () {
+                _ if self.eat("*") => BinaryOp::Mul,
+                _ if self.eat("/") => BinaryOp::Div,
+                _ => return Ok(left),
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.atom()?));
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, LineError> {
+        if self.eat("(") {
+            let inner = self.expr()?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        for (name, register) in [
+            ("blockIdx", Register::BlockIdx),
+            ("blockDim", Register::BlockDim),
+            ("threadIdx", Register::ThreadIdx),
+        ] {
+            if self.eat(name) {
+                return Ok(Expr::Builtin(register));
+            }
+        }
+        if let Some(Token::Number(_)) = self.peek() {
+            return Ok(Expr::Number(self.number(u8::MAX as u32)? as u8));
+        }
+
+        let name = self.ident().map_err(|_| self.error("an expression"))?;
+        if self.eat("[") {
+            let index = self.expr()?;
+            self.expect("]")?;
+            return Ok(Expr::Index(name, Box::new(index)));
+        }
+        Ok(Expr::Var(name))
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    lines: Vec<String>,
+    arrays: Vec<(String, usize)>, // with their length
+    scopes: Vec<BTreeSet<String>>,
+    loop_vars: Vec<String>,
+    temps: usize,
+    labels: usize,
+    needs_zero: bool,
+    comment: Option<String>,
+}
+
+/// Holds 0 for copies. Compiler registers start with `_`, which variable names cannot.
+const ZERO: &str = "%_zero";
+
+impl Compiler {
+    fn emit(&mut self, line: String) {
+        let line = match self.comment.take() {
+            Some(comment) => format!("{line} ; {comment}"),
+            None => line,
+        };
+        self.lines.push(line);
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%_t{}", self.temps - 1)
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("{kind}_{}", self.labels - 1)
+    }
+
+    fn error(line: u32, message: String) -> LineError {
+        LineError {
+            line_num: line,
+            error: LexError::InvalidArgument(message),
+        }
+    }
+
+    fn variable(&self, name: &str, line: u32) -> Result<String, LineError> {
+        match self.scopes.iter().any(|scope| scope.contains(name)) {
+            true => Ok(format!("%{name}")),
+            false => Err(Compiler::error(line, format!("{name} is not declared"))),
+        }
+    }
+
+    fn declare(&mut self, name: &str, line: u32) -> Result<String, LineError> {
+        if self.scopes.iter().any(|scope| scope.contains(name))
+            || self.arrays.iter().any(|(array, _)| array == name)
+        {
+            return Err(Compiler::error(line, format!("{name} is already declared")));
+        }
+        self.scopes.last_mut().unwrap().insert(name.to_string());
+        Ok(format!("%{name}"))
+    }
+
+    fn program(&mut self, program: &Program) -> Result<(), LineError> {
+        let mut address = 0;
+        for (name, values) in &program.arrays {
+            if address + values.len() > DATA_MEMORY_SIZE {
+                return Err(Compiler::error(
+                    0,
+                    format!("array {name} does not fit in {DATA_MEMORY_SIZE} bytes of data memory"),
+                ));
+            }
+            self.arrays.push((name.clone(), values.len()));
+            address += values.len();
+        }
+        self.block(&program.body)
+    }
+
+    fn block(&mut self, body: &[(Stmt, u32)]) -> Result<(), LineError> {
+        self.scopes.push(BTreeSet::new());
+        for (statement, line) in body {
+            self.statement(statement, *line)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Stmt, line: u32) -> Result<(), LineError> {
+        self.comment = Some(format!("line {}", line + 1));
+        match statement {
+            Stmt::Let(name, value) => {
+                // declared after its value, which may not refer to it
+                self.expr(value, Some(&format!("%{name}")), line)?;
+                self.declare(name, line)?;
+            }
+            Stmt::Assign(name, value) => {
+                let dest = self.variable(name, line)?;
+                if self.loop_vars.contains(name) {
+                    return Err(Compiler::error(
+                        line,
+                        format!("loop variable {name} cannot be assigned"),
+                    ));
+                }
+                self.expr(value, Some(&dest), line)?;
+            }
+            Stmt::Store(array, index, value) => {
+                let address = self.address(array, index, line)?;
+                let value = self.expr(value, None, line)?;
+                self.emit(format!("STR {address}, {value}"));
+            }
+            Stmt::For {
+                var,
+                start,
+                end,
+                unroll,
+                body,
+            } => {
+                if start >= end {
+                    return Ok(());
+                }
+                let branches = |(statement, _): &(Stmt, u32)| {
+                    matches!(statement, Stmt::If(..) | Stmt::For { .. })
+                };
+                if unroll.is_some() && body.iter().any(branches) {
+                    return Err(Compiler::error(
+                        line,
+                        format!("loop over {var} cannot be unrolled: its body contains an if or a for, which compile to branches"),
+                    ));
+                }
+                self.scopes.push(BTreeSet::new());
+                let counter = self.declare(var, line)?;
+                let (bound, step) = (self.temp(), self.temp());
+                let head = self.label("LOOP");
+                self.emit(format!("CONST {bound}, #{end}"));
+                self.emit(format!("CONST {step}, #1"));
+                self.emit(format!("CONST {counter}, #{start}"));
+                if let Some(factor) = unroll {
+                    self.emit(format!(".unroll {factor}"));
+                }
+                self.emit(format!("{head}:"));
+
+                self.loop_vars.push(var.clone());
+                self.block(body)?;
+                self.loop_vars.pop();
+
+                self.emit(format!("ADD {counter}, {counter}, {step}"));
+                self.emit(format!("CMP {counter}, {bound}"));
+                self.emit(format!("BRn {head}"));
+                self.scopes.pop();
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let left = self.expr(&condition.left, None, line)?;
+                let right = self.expr(&condition.right, None, line)?;
+                // branch past the then block when the condition does not hold
+                let skip = match condition.op {
+                    "<" => "BRzp",
+                    "<=" => "BRp",
+                    ">" => "BRnz",
+                    ">=" => "BRn",
+                    "==" => "BRnp",
+                    _ => "BRz",
+                };
+                let (otherwise_label, end_label) = (self.label("ELSE"), self.label("ENDIF"));
+                self.emit(format!("CMP {left}, {right}"));
+                self.emit(format!("{skip} {otherwise_label}"));
+                self.block(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(format!("BRnzp {end_label}"));
+                }
+                self.emit(format!("{otherwise_label}:"));
+                if !otherwise.is_empty() {
+                    self.block(otherwise)?;
+                    self.emit(format!("{end_label}:"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `value` into `dest` by adding zero, there being no move instruction
+    fn copy(&mut self, dest: &str, value: &str) {
+        if dest != value {
+            self.needs_zero = true;
+            self.emit(format!("ADD {dest}, {value}, {ZERO}"));
+        }
+    }
+
+    /// The register holding the address of `array[index]`
+    fn address(&mut self, array: &str, index: &Expr, line: u32) -> Result<String, LineError> {
+        match self.arrays.iter().find(|(name, _)| name == array) {
+            None => return Err(Compiler::error(line, format!("{array} is not an array"))),
+            // an empty array has no .data block to label
+            Some((_, 0)) => return Err(Compiler::error(line, format!("array {array} is empty"))),
+            Some(_) => {}
+        }
+        let index = self.expr(index, None, line)?;
+        let (base, address) = (self.temp(), self.temp());
+        self.emit(format!("CONST {base}, {array}"));
+        self.emit(format!("ADD {address}, {base}, {index}"));
+        Ok(address)
+    }
+
+    /// Evaluates `expr` into `dest`, or a register of its own if None, and returns the register
+    fn expr(&mut self, expr: &Expr, dest: Option<&str>, line: u32) -> Result<String, LineError> {
+        let result = match expr {
+            Expr::Number(value) => {
+                let register = dest.map_or_else(|| self.temp(), String::from);
+                self.emit(format!("CONST {register}, #{value}"));
+                return Ok(register);
+            }
+            Expr::Var(name) => self.variable(name, line)?,
+            Expr::Builtin(register) => register.name().to_string(),
+            Expr::Index(array, index) => {
+                let address = self.address(array, index, line)?;
+                let register = dest.map_or_else(|| self.temp(), String::from);
+                self.emit(format!("LDR {register}, {address}"));
+                return Ok(register);
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.expr(left, None, line)?;
+                let right = self.expr(right, None, line)?;
+                let register = dest.map_or_else(|| self.temp(), String::from);
+                let mnemonic = match op {
+                    BinaryOp::Add => "ADD",
+                    BinaryOp::Sub => "SUB",
+                    BinaryOp::Mul => "MUL",
+                    BinaryOp::Div => "DIV",
+                };
+                self.emit(format!("{mnemonic} {register}, {left}, {right}"));
+                return Ok(register);
+            }
+        };
+
+        match dest {
+            Some(dest) => {
+                self.copy(dest, &result);
+                Ok(dest.to_string())
+            }
+            None => Ok(result),
+        }
+    }
+
+    /// The assembly source: directives, then the code, ending with RET
+    fn finish(self, program: &Program) -> String {
+        let mut source = vec![];
+        if let Some(threads) = program.threads {
+            source.push(format!(".threads {threads}"));
+        }
+        for (name, values) in &program.arrays {
+            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            // .data with no values is rejected, so an empty array emits nothing
+            if !values.is_empty() {
+                source.push(format!("{name}: .data {}", values.join(" ")));
+            }
+        }
+        if self.needs_zero {
+            source.push(format!("CONST {ZERO}, #0"));
+        }
+        source.extend(self.lines);
+        source.push("RET".into());
+        source.join("\n") + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::output::Hardware;
+    use crate::simulator::Simulator;
+
+    /// Compiles, assembles and runs a kernel, returning data memory afterwards
+    fn run(kernel: &str) -> Vec<u8> {
+        let source = compile(kernel).unwrap();
+        let assembly = assemble(&source);
+        assert!(
+            assembly.errors.is_empty(),
+            "{:?}\n{source}",
+            assembly.errors
+        );
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        let data = assembly.initial_data().unwrap();
+        let mut sim = Simulator::new(assembly.program(), &data, launch);
+        sim.run().unwrap();
+        sim.memory
+    }
+
+    fn error(kernel: &str) -> (u32, String) {
+        let err = compile(kernel).unwrap_err();
+        (err.line_num, err.error.to_string())
+    }
+
+    const EXAMPLE: &str = "threads 8;
+data A = [0, 1, 2, 3, 4, 5, 6, 7];
+data B = [0, 1, 2, 3, 4, 5, 6, 7];
+data C[8];
+
+let i = blockIdx * blockDim + threadIdx;
+for k in 0..4 unroll 2 {
+    C[i] = C[i] + B[i];
+}
+if A[i] < 4 { C[i] = C[i] + 1; } else { C[i] = 0; }
+";
+
+    #[test]
+    fn the_module_example_runs() {
+        assert_eq!(run(EXAMPLE)[16..24], [1, 5, 9, 13, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn arrays_are_labeled_data() {
+        let source = compile(EXAMPLE).unwrap();
+        assert!(source.contains("A:\n.data 0 1 2 3 4 5 6 7\n"), "{source}");
+        assert!(source.contains(", C "), "{source}");
+        assert!(!source.contains("#16"), "{source}");
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let memory = run("data R[4];\nR[0] = 2 + 3 * 4;\nR[1] = (2 + 3) * 4;\nR[2] = 9 - 4 - 2;\nR[3] = 12 / 2 / 3;\n");
+        assert_eq!(memory[..4], [14, 20, 3, 2]);
+    }
+
+    #[test]
+    fn loops_nest_and_else_if_chains() {
+        let memory = run("data R[3];
+for a in 0..3 {
+    for b in 0..4 {
+        R[a] = R[a] + b;
+    }
+    if a == 0 { R[a] = R[a] + 10; } else if a == 1 { R[a] = R[a] + 20; } else { R[a] = 0; }
+}
+");
+        assert_eq!(memory[..3], [16, 26, 0]);
+    }
+
+    #[test]
+    fn unrolled_loops_may_not_branch() {
+        let (line, message) =
+            error("let x = 0;\nfor k in 0..4 unroll 2 {\n    if x < 1 { x = 1; }\n}\n");
+        assert_eq!(line, 1);
+        assert!(message.contains("cannot be unrolled"), "{message}");
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        assert_eq!(
+            error("let x = 1;\nlet y = ;\n"),
+            (
+                1,
+                "Invalid syntax: expected an expression, found `;`".to_string()
+            )
+        );
+        assert_eq!(
+            error("let x = 1;\n\ny = x;\n"),
+            (2, "Invalid argument: y is not declared".to_string())
+        );
+        assert_eq!(
+            error("data A[2];\ndata A[3];\n"),
+            (1, "Invalid syntax: array A is declared twice".to_string())
+        );
+        assert_eq!(
+            error("for k in 0..2 { k = 1; }\n"),
+            (
+                0,
+                "Invalid argument: loop variable k cannot be assigned".to_string()
+            )
+        );
+        assert_eq!(
+            error("data E[0];\nE[0] = 1;\n").1,
+            "Invalid argument: array E is empty"
+        );
+    }
+}
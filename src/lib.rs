@@ -3,7 +3,31 @@ use std::error::Error;
 use std::fmt;
 use std::str::FromStr;
 
+pub mod analysis;
+pub mod assembler;
+pub mod batch;
+pub mod conditional;
+pub mod debugger;
+pub mod diagnostic;
+pub mod format;
+pub mod immediate;
+pub mod instruction;
+pub mod lang;
+pub mod link;
+pub mod lint;
+pub mod lsp;
+pub mod memmap;
 pub mod operation;
+pub mod optimize;
+pub mod output;
+pub mod pseudo;
+pub mod regalloc;
+pub mod report;
+pub mod schedule;
+pub mod simulator;
+pub mod sweep;
+pub mod trace;
+pub mod unroll;
 use crate::operation::Operation;
 
 #[derive(Debug)]
@@ -22,17 +46,213 @@ pub struct ParsedLine {
     pub line_num: u32,
 }
 
+pub fn parse_line(line_num: usize, line: &str) -> Result<ParsedLine, LexError> {
+    // remove trailing/leading whitespace
+    let line = line.trim();
+
+    // Split the string into two parts: before and after the semicolon (outside of character literals)
+    let (rest, comment) = match find_unquoted(line, |c| c == ';') {
+        Some(pos) => (&line[..pos], Some(String::from(&line[pos + 1..]))),
+        None => (line, None), // If no semicolon, return no comment
+    };
+
+    let mut tokens: Vec<String> = vec![];
+    let mut rest = rest.trim();
+
+    // a label may share its line with an instruction (LOOP: ADD R1, R1, R2)
+    if let Some(label) = rest
+        .split_whitespace()
+        .next()
+        .filter(|word| word.ends_with(':'))
+    {
+        tokens.push(label.to_owned());
+        rest = rest[label.len()..].trim_start();
+    }
+
+    let (head, operands) = match rest.find(char::is_whitespace) {
+        Some(pos) => (&rest[..pos], rest[pos..].trim()),
+        None => (rest, ""),
+    };
+
+    if head.is_empty() {
+        return Ok(ParsedLine {
+            tokens,
+            comment,
+            line_num: (line_num as u32),
+        });
+    }
+
+    if let Some(pos) = head.find(',') {
+        return Err(LexError::InvalidSyntax(format!(
+            "unexpected ',' after {}, operands are separated from the mnemonic by whitespace",
+            &head[..pos]
+        )));
+    }
+
+    tokens.push(head.to_owned());
+
+    if head.starts_with('.') {
+        // directive arguments are whitespace separated (.data 1 2 3)
+        tokens.extend(operands.split_whitespace().map(|a| a.to_owned()));
+    } else {
+        tokens.extend(tokenize_operands(operands)?);
+    }
+
+    Ok(ParsedLine {
+        tokens,
+        comment,
+        line_num: (line_num as u32),
+    })
+}
+
+/// Parses every line of a source file, stopping at the first syntax error.
+pub fn parse_source(contents: &str) -> Result<Vec<ParsedLine>, LineError> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| {
+            parse_line(line_num, line).map_err(|error| LineError {
+                line_num: line_num as u32,
+                error,
+            })
+        })
+        .collect()
+}
+
+/// Splits comma separated operands (`R1, R2,R3`), rejecting empty operands and missing commas.
+/// Operand positions in errors count from 1.
+pub fn tokenize_operands(operands: &str) -> Result<Vec<String>, LexError> {
+    if operands.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut tokens = vec![];
+    let mut rest = operands;
+    loop {
+        let (operand, next) = match find_unquoted(rest, |c| c == ',') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+        let operand = operand.trim();
+        let position = tokens.len() + 1;
+
+        if operand.is_empty() {
+            return Err(LexError::MalformedOperand {
+                position,
+                operand: operand.to_owned(),
+                reason: "is empty (stray ',')".into(),
+            });
+        }
+        if find_unquoted(operand, char::is_whitespace).is_some() {
+            return Err(LexError::MalformedOperand {
+                position,
+                operand: operand.to_owned(),
+                reason: "is missing a ',' before the next operand".into(),
+            });
+        }
+
+        tokens.push(operand.to_owned());
+
+        match next {
+            Some(next) => rest = next,
+            None => break,
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Finds the first char matching `is_target` outside of character literals ('A', ';', '\'')
+pub(crate) fn find_unquoted(s: &str, is_target: impl Fn(char) -> bool) -> Option<usize> {
+    let mut in_quote = false;
+    let mut escaped = false;
+
+    for (pos, c) in s.char_indices() {
+        if in_quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '\'' => in_quote = false,
+                _ => {}
+            }
+        } else if c == '\'' {
+            in_quote = true;
+        } else if is_target(c) {
+            return Some(pos);
+        }
+    }
+
+    None
+}
+
+/// Directives the assembler understands, with a short description of each
+pub const DIRECTIVES: [(&str, &str); 16] = [
+    (".threads", ".threads N - number of threads to launch"),
+    (
+        ".blocks",
+        ".blocks N - number of blocks to launch, the threads if .threads is left out",
+    ),
+    (
+        ".block_dim",
+        ".block_dim N - threads per block, the value of %blockDim (default 4)",
+    ),
+    (
+        ".cores",
+        ".cores N - cores the blocks are dealt out to (default 2)",
+    ),
+    (
+        ".data",
+        ".data v0 v1 ... - appends bytes to the initial data memory",
+    ),
+    (
+        ".scratch",
+        ".scratch Rn - register pseudoinstructions may clobber",
+    ),
+    (
+        ".register_width",
+        ".register_width N - register width LI synthesizes constants for (default 8)",
+    ),
+    (
+        ".unroll",
+        ".unroll N - unrolls the loop at the next label N times",
+    ),
+    (
+        ".global",
+        ".global LABEL... - exports labels from a relocatable object to the linker",
+    ),
+    (
+        ".define",
+        ".define NAME VALUE - defines a symbolic constant, usable as an operand or #NAME",
+    ),
+    (
+        ".if",
+        ".if EXPR - assembles the lines up to the next .elif/.else/.endif if EXPR is not 0",
+    ),
+    (".elif", ".elif EXPR - alternative to the .if before it"),
+    (".else", ".else - assembled if no .if/.elif before it was"),
+    (".endif", ".endif - ends a .if"),
+    (
+        ".ifdef",
+        ".ifdef NAME - assembles the lines up to .else/.endif if NAME is defined",
+    ),
+    (
+        ".ifndef",
+        ".ifndef NAME - assembles the lines up to .else/.endif if NAME is not defined",
+    ),
+];
+
 pub fn identify_line(line: ParsedLine) -> Box<dyn LexedLine> {
     match line.tokens.first() {
         Some(first_token) => {
             let is_memory_dir = |a: &String| a.chars().next().is_some_and(|char_1| char_1 == '.'); //checks if the first token is a memory directive
             let is_label = |a: &String| a.chars().last().is_some_and(|char_1| char_1 == ':'); //checks if the first token is a label
 
-            if is_memory_dir(first_token) {
-                Box::new(MemoryLine { parsed: line })
-            } else if is_label(first_token) {
+            // labels first, since local labels start with '.' too
+            if is_label(first_token) {
                 Box::new(LabelLine { parsed: line })
-            } else if Operation::from_str(&first_token).is_ok() {
+            } else if is_memory_dir(first_token) {
+                Box::new(MemoryLine { parsed: line })
+            } else if Operation::from_str(first_token).is_ok() {
                 Box::new(OperationLine {
                     parsed: line,
                     instruct_num: None,
@@ -47,9 +267,7 @@ pub fn identify_line(line: ParsedLine) -> Box<dyn LexedLine> {
 
 /// Types of Lexed and Parsed Lines
 /// ---
-///
-
-// Define a trait that the struct will implement
+/// Define a trait that the struct will implement
 pub trait LexedLine: std::fmt::Debug + std::any::Any {
     // fn parsed(&self) -> &ParsedLine {
     //     self.parsed
@@ -160,6 +378,38 @@ pub struct LabelLine {
 pub enum LexError {
     InvalidOperation(String),
     InvalidArgument(String), // Error with additional info
+    InvalidImmediate(String),
+    InvalidSyntax(String),
+    MalformedOperand {
+        position: usize,
+        operand: String,
+        reason: String,
+    },
+    WrongOperandCount {
+        operation: String,
+        expected: u8,
+        found: usize,
+    },
+    ImmediateOutOfRange {
+        literal: String,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    UnsupportedLoop {
+        label: String,
+        reason: String,
+    },
+    UndefinedLabel(String),
+    DuplicateLabel {
+        label: String,
+        first_line: u32, // 0-based
+    },
+    LabelAtEnd(String),
+    RegisterPressure {
+        available: usize,
+        live: Vec<String>, // virtual registers live at once, with their live ranges
+    },
 }
 
 impl fmt::Display for LexError {
@@ -168,7 +418,66 @@ impl fmt::Display for LexError {
             LexError::InvalidOperation(ref msg) => {
                 write!(f, "Invalid input provided: {msg} is not an operator")
             }
-            LexError::InvalidArgument(ref msg) => write!(f, "Is not an: {}", msg),
+            LexError::InvalidArgument(ref msg) => write!(f, "Invalid argument: {}", msg),
+            LexError::InvalidImmediate(ref literal) => write!(
+                f,
+                "Invalid immediate: {literal} is not a decimal, hex (0x), binary (0b) or character ('A') literal"
+            ),
+            LexError::InvalidSyntax(ref msg) => write!(f, "Invalid syntax: {msg}"),
+            LexError::MalformedOperand {
+                position,
+                ref operand,
+                ref reason,
+            } => write!(f, "Malformed operand {position}: `{operand}` {reason}"),
+            LexError::WrongOperandCount {
+                ref operation,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{operation} takes {expected} operand(s), found {found}"
+            ),
+            LexError::ImmediateOutOfRange {
+                ref literal,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "Immediate out of range: {literal} ({value}) does not fit, allowed range is {min}..={max}"
+            ),
+            LexError::UnsupportedLoop {
+                ref label,
+                ref reason,
+            } => write!(f, "Cannot unroll loop {label}: {reason}"),
+            LexError::UndefinedLabel(ref label) => match label.strip_suffix(['b', 'f']) {
+                Some(number) if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) => {
+                    let side = if label.ends_with('b') { "before" } else { "after" };
+                    write!(f, "Undefined label: {label} refers to the nearest label {number} {side} it, but there is none")
+                }
+                _ => write!(f, "Undefined label: {label} is not defined anywhere"),
+            },
+            LexError::DuplicateLabel {
+                ref label,
+                first_line,
+            } => write!(
+                f,
+                "Duplicate label: {label} is already defined on line {}",
+                first_line + 1
+            ),
+            LexError::LabelAtEnd(ref label) => write!(
+                f,
+                "Label {label} is not followed by an instruction, end the program with RET after it"
+            ),
+            LexError::RegisterPressure {
+                available,
+                ref live,
+            } => write!(
+                f,
+                "{} virtual registers are live at once but only {available} registers are free: {}",
+                live.len(),
+                live.join(", ")
+            ),
         }
     }
 }
@@ -177,9 +486,44 @@ impl Error for LexError {
     // You can also add additional methods if needed, such as for logging
 }
 
+impl LexError {
+    /// A stable name for the kind of error, for tools reading structured diagnostics
+    pub fn code(&self) -> &'static str {
+        match *self {
+            LexError::InvalidOperation(_) => "invalid-operation",
+            LexError::InvalidArgument(_) => "invalid-argument",
+            LexError::InvalidImmediate(_) => "invalid-immediate",
+            LexError::InvalidSyntax(_) => "invalid-syntax",
+            LexError::MalformedOperand { .. } => "malformed-operand",
+            LexError::WrongOperandCount { .. } => "wrong-operand-count",
+            LexError::ImmediateOutOfRange { .. } => "immediate-out-of-range",
+            LexError::UnsupportedLoop { .. } => "unsupported-loop",
+            LexError::UndefinedLabel(_) => "undefined-label",
+            LexError::DuplicateLabel { .. } => "duplicate-label",
+            LexError::LabelAtEnd(_) => "label-at-end",
+            LexError::RegisterPressure { .. } => "register-pressure",
+        }
+    }
+}
+
+/// A LexError together with the (0-based) source line it was found on
+#[derive(Debug)]
+pub struct LineError {
+    pub line_num: u32,
+    pub error: LexError,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_num + 1, self.error)
+    }
+}
+
+impl Error for LineError {}
+
 /// Register Definitions
 /// ---
-
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     // General-purpose read/write registers
     R0,
@@ -225,6 +569,14 @@ impl Register {
         }
     }
 
+    // The special registers are read-only and hold the launch coordinates of the thread
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            Register::BlockIdx | Register::BlockDim | Register::ThreadIdx
+        )
+    }
+
     pub fn bits(&self) -> &'static str {
         match self {
             Register::R0 => "0000",
@@ -246,9 +598,37 @@ impl Register {
         }
     }
 
+    // Inverse of bits(), from the 4-bit register field of an instruction
+    pub fn from_bits(bits: u8) -> Register {
+        Register::ALL[(bits & 0xF) as usize]
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub const ALL: [Register; 16] = [
+        Register::R0,
+        Register::R1,
+        Register::R2,
+        Register::R3,
+        Register::R4,
+        Register::R5,
+        Register::R6,
+        Register::R7,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::BlockIdx,
+        Register::BlockDim,
+        Register::ThreadIdx,
+    ];
+
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Self, LexError> {
-        let no_commas = s.replace(",", ""); //remove commas (allows for comma or no commas) should probably sophisticate later
-        match no_commas.as_str() {
+        match s {
             "R0" => Ok(Register::R0),
             "R1" => Ok(Register::R1),
             "R2" => Ok(Register::R2),
@@ -271,3 +651,73 @@ impl Register {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<String> {
+        parse_line(0, line).unwrap().tokens
+    }
+
+    fn malformed(line: &str) -> (usize, String) {
+        match parse_line(0, line) {
+            Err(LexError::MalformedOperand {
+                position, operand, ..
+            }) => (position, operand),
+            other => panic!("{line}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn commas_separate_operands_with_or_without_spaces() {
+        for line in ["ADD R1,R2,R3", "ADD R1, R2, R3", "ADD   R1 ,R2 ,  R3"] {
+            assert_eq!(tokens(line), ["ADD", "R1", "R2", "R3"], "{line}");
+        }
+        assert_eq!(
+            tokens("LOOP: CONST R0,#';' ; a semicolon"),
+            ["LOOP:", "CONST", "R0", "#';'"]
+        );
+        assert_eq!(
+            parse_line(0, "CONST R0, #',' ; comma").unwrap().comment,
+            Some(" comma".into())
+        );
+        // directive arguments stay whitespace separated
+        assert_eq!(tokens(".data 1 2 3"), [".data", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn stray_separators_report_their_operand_position() {
+        assert_eq!(malformed("ADD ,R1, R2"), (1, "".into()));
+        assert_eq!(malformed("ADD R1,, R2"), (2, "".into()));
+        assert_eq!(malformed("ADD R1, R2,"), (3, "".into()));
+        assert_eq!(malformed("ADD R1, R2 R3"), (2, "R2 R3".into()));
+        assert!(matches!(
+            parse_line(0, "ADD, R1, R2"),
+            Err(LexError::InvalidSyntax(_))
+        ));
+    }
+
+    #[test]
+    fn bad_registers_report_their_operand_position() {
+        let assembly = crate::assembler::assemble("ADD R1, R2, R13\nRET\n");
+        match &assembly.errors[..] {
+            [LineError {
+                line_num: 0,
+                error:
+                    LexError::MalformedOperand {
+                        position, operand, ..
+                    },
+            }] => assert_eq!((*position, operand.as_str()), (3, "R13")),
+            errors => panic!("{errors:?}"),
+        }
+    }
+
+    #[test]
+    fn registers_are_matched_exactly() {
+        assert_eq!(Register::from_str("R12").unwrap(), Register::R12);
+        for register in ["R1,", ",R1", "r1", "R13", "%threadidx"] {
+            assert!(Register::from_str(register).is_err(), "{register}");
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::{symbol_reference, Assembly};
+use crate::output::{Hardware, Launch, Output, DEFAULT_MEMORY_DELAY};
+use crate::{LexError, LineError};
+
+/// Relocatable Objects
+/// ---
+/// An object is one source assembled on its own, with every address left relocatable: its
+/// code and data, the labels it defines, and the instructions whose 8-bit immediate holds the
+/// address of a symbol (branch targets, and data labels loaded with `CONST Rd, LABEL`).
+/// Labels listed in a `.global` directive are exported for other objects to use, and symbols
+/// the source does not define are imports, left for the linker.
+///
+/// `link` places objects in program memory and their data in data memory in the order given,
+/// resolves every relocation against the object's own labels first and then the exported ones,
+/// and produces the same `Output` as assembling a single source.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Object {
+    pub name: String,
+    pub launch: Option<Launch>, // if the source sets any of it
+    pub program_memory: Vec<String>,
+    pub initial_data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    Code,
+    Data,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u16, // from the start of the object's section
+    pub global: bool,
+}
+
+/// An instruction whose low 8 bits are the address of `symbol`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Relocation {
+    pub offset: u16,
+    pub symbol: String,
+}
+
+/// Builds the object of a source assembled with `assemble_relocatable`.
+pub fn build_object(name: &str, assembly: &Assembly) -> Result<Object, LineError> {
+    let directive = |name: &'static str| {
+        assembly
+            .memories
+            .iter()
+            .filter(move |m| m.parsed_line.tokens.first().is_some_and(|t| t == name))
+    };
+
+    let mut globals: Vec<&String> = vec![];
+    for line in directive(".global") {
+        for symbol in &line.parsed_line.tokens[1..] {
+            let defined = assembly
+                .label_addresses
+                .iter()
+                .chain(&assembly.data_labels)
+                .any(|(label, _)| label == symbol);
+            if !defined {
+                return Err(LineError {
+                    line_num: line.line_num,
+                    error: LexError::UndefinedLabel(symbol.clone()),
+                });
+            }
+            globals.push(symbol);
+        }
+    }
+
+    let globals = &globals;
+    let symbol = |section: Section| {
+        move |(name, offset): &(String, u16)| Symbol {
+            name: name.clone(),
+            section,
+            offset: *offset,
+            global: globals.contains(&name),
+        }
+    };
+    let symbols = (assembly.label_addresses.iter().map(symbol(Section::Code)))
+        .chain(assembly.data_labels.iter().map(symbol(Section::Data)))
+        .collect();
+
+    let relocations = assembly
+        .operations
+        .iter()
+        .enumerate()
+        .filter_map(|(offset, line)| {
+            symbol_reference(&line.parsed_line.tokens).map(|symbol| Relocation {
+                offset: offset as u16,
+                symbol: symbol.to_string(),
+            })
+        })
+        .collect();
+
+    let launch = [".threads", ".blocks", ".block_dim", ".cores"]
+        .into_iter()
+        .any(|name| directive(name).next().is_some())
+        .then(|| assembly.launch(&Hardware::default()))
+        .transpose()?;
+    let initial_data = assembly.initial_data()?;
+
+    Ok(Object {
+        name: name.to_string(),
+        launch,
+        program_memory: assembly
+            .program()
+            .into_iter()
+            .map(|word| format!("0x{:04x}", word))
+            .collect(),
+        initial_data,
+        symbols,
+        relocations,
+    })
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    InvalidWord {
+        object: String,
+        word: String,
+    },
+    DuplicateSymbol {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    UndefinedSymbol {
+        symbol: String,
+        object: String,
+    },
+    OutOfRange {
+        section: Section,
+        size: usize,
+    },
+    ConflictingLaunch {
+        first: (String, Launch),
+        second: (String, Launch),
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::InvalidWord { object, word } => {
+                write!(f, "{object}: `{word}` is not a hex instruction word")
+            }
+            LinkError::DuplicateSymbol {
+                symbol,
+                first,
+                second,
+            } => write!(f, "{symbol} is exported by both {first} and {second}"),
+            LinkError::UndefinedSymbol { symbol, object } => {
+                write!(
+                    f,
+                    "{object}: undefined symbol {symbol}, no object exports it"
+                )
+            }
+            LinkError::OutOfRange { section, size } => {
+                let section = match section {
+                    Section::Code => "program",
+                    Section::Data => "data",
+                };
+                write!(
+                    f,
+                    "the linked {section} memory is {size} words, more than 8-bit addresses reach"
+                )
+            }
+            LinkError::ConflictingLaunch { first, second } => write!(
+                f,
+                "{} launches {} but {} launches {}",
+                first.0, first.1, second.0, second.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Links `objects`, in order, into the output of test `testname`.
+pub fn link(testname: &str, objects: &[Object]) -> Result<Output, LinkError> {
+    // where each object's code and data start
+    let mut code_base = vec![];
+    let mut data_base = vec![];
+    let (mut code_size, mut data_size) = (0, 0);
+    for object in objects {
+        code_base.push(code_size);
+        data_base.push(data_size);
+        code_size += object.program_memory.len();
+        data_size += object.initial_data.len();
+    }
+    for (section, size) in [(Section::Code, code_size), (Section::Data, data_size)] {
+        if size > 256 {
+            return Err(LinkError::OutOfRange { section, size });
+        }
+    }
+
+    let mut exported: Vec<(&Symbol, usize)> = vec![];
+    for (index, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            if let Some((_, first)) = exported.iter().find(|(s, _)| s.name == symbol.name) {
+                return Err(LinkError::DuplicateSymbol {
+                    symbol: symbol.name.clone(),
+                    first: objects[*first].name.clone(),
+                    second: object.name.clone(),
+                });
+            }
+            exported.push((symbol, index));
+        }
+    }
+
+    let mut program = vec![];
+    let mut launch: Option<(&String, Launch)> = None;
+    for (index, object) in objects.iter().enumerate() {
+        let mut words = object
+            .program_memory
+            .iter()
+            .map(|word| {
+                word.strip_prefix("0x")
+                    .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| LinkError::InvalidWord {
+                        object: object.name.clone(),
+                        word: word.clone(),
+                    })
+            })
+            .collect::<Result<Vec<u16>, LinkError>>()?;
+
+        for relocation in &object.relocations {
+            let (symbol, owner) = object
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == relocation.symbol)
+                .map(|symbol| (symbol, index))
+                .or_else(|| {
+                    exported
+                        .iter()
+                        .find(|(symbol, _)| symbol.name == relocation.symbol)
+                        .copied()
+                })
+                .ok_or_else(|| LinkError::UndefinedSymbol {
+                    symbol: relocation.symbol.clone(),
+                    object: object.name.clone(),
+                })?;
+            let base = match symbol.section {
+                Section::Code => code_base[owner],
+                Section::Data => data_base[owner],
+            };
+            let address = (base + symbol.offset as usize) as u16;
+            if let Some(word) = words.get_mut(relocation.offset as usize) {
+                *word = (*word & 0xFF00) | (address & 0xFF);
+            }
+        }
+        program.extend(words);
+
+        if let Some(object_launch) = object.launch {
+            match launch {
+                Some((first, first_launch)) if first_launch != object_launch => {
+                    return Err(LinkError::ConflictingLaunch {
+                        first: (first.clone(), first_launch),
+                        second: (object.name.clone(), object_launch),
+                    })
+                }
+                _ => launch = Some((&object.name, object_launch)),
+            }
+        }
+    }
+
+    Ok(Output {
+        testname: testname.to_string(),
+        memory_delay: DEFAULT_MEMORY_DELAY,
+        launch: launch.map_or_else(Launch::default, |(_, launch)| launch),
+        hardware: Hardware::default(),
+        program_memory: program
+            .into_iter()
+            .map(|word| format!("0x{:04x}", word))
+            .collect(),
+        initial_data: objects
+            .iter()
+            .flat_map(|object| object.initial_data.iter().copied())
+            .collect(),
+        expected_data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_relocatable;
+    use crate::instruction::Instruction;
+    use crate::optimize::optimize;
+    use crate::Register;
+
+    fn object(name: &str, source: &str) -> Object {
+        let assembly = assemble_relocatable(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        build_object(name, &assembly).unwrap()
+    }
+
+    #[test]
+    fn relocates_local_and_numeric_labels_at_a_nonzero_base() {
+        let kernel = object("kernel", ".global MAIN\nMAIN: NOP\nBRnzp LIB\nRET\n");
+        let library = object(
+            "library",
+            ".global LIB\nLIB: CONST R0, #2\n.loop: CMP R0, R1\nBRn .loop\n1: CMP R0, R1\nBRn 1b\nRET\n",
+        );
+        assert_eq!(library.relocations.len(), 2);
+
+        let output = link("test", &[kernel, library]).unwrap();
+        let decoded: Vec<Option<Instruction>> = output
+            .program_memory
+            .iter()
+            .map(|word| Instruction::decode(u16::from_str_radix(&word[2..], 16).unwrap()))
+            .collect();
+        let target = |address: usize| match decoded[address] {
+            Some(Instruction::Branch { target, .. }) => target,
+            ref other => panic!("expected a branch, found {other:?}"),
+        };
+        assert_eq!(target(1), 3); // LIB
+        assert_eq!(target(5), 4); // .loop
+        assert_eq!(target(7), 6); // 1b
+    }
+
+    #[test]
+    fn optimized_objects_keep_their_relocations() {
+        // R2 = BUF + 0 would fold to a CONST of the address BUF has before linking
+        let mut assembly = assemble_relocatable(
+            "CONST R1, #0\nCONST R0, BUF\nADD R2, R0, R1\nADD R2, R2, R1\nCONST R3, END\nSTR R3, R2\nRET\nEND: .data 0\n",
+        );
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let rewrites = optimize(&mut assembly);
+        assert_eq!(rewrites.len(), 1, "{rewrites:?}");
+        let kernel = build_object("kernel", &assembly).unwrap();
+        assert_eq!(kernel.relocations.len(), 2);
+        let library = object("library", ".global BUF\nPAD: .data 1 2\nBUF: .data 3\n");
+
+        let output = link("test", &[library, kernel]).unwrap();
+        let decoded: Vec<Option<Instruction>> = output
+            .program_memory
+            .iter()
+            .map(|word| Instruction::decode(u16::from_str_radix(&word[2..], 16).unwrap()))
+            .collect();
+        let loads = |rd: Register, imm: u8| decoded.contains(&Some(Instruction::Const { rd, imm }));
+        assert!(loads(Register::R0, 2)); // BUF
+        assert!(loads(Register::R3, 3)); // END, after the library's data
+    }
+
+    #[test]
+    fn imports_resolve_against_exported_symbols_only() {
+        let kernel = object("kernel", "BRnzp HIDDEN\nRET\n");
+        let library = object("library", "HIDDEN: RET\n");
+        assert!(matches!(
+            link("test", &[kernel, library]),
+            Err(LinkError::UndefinedSymbol { .. })
+        ));
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::analysis::{constant_registers, falls_off_end, flags_set, reachable};
+use crate::assembler::{resolve_labels, split_labels, symbol_reference};
+use crate::instruction::Instruction;
+use crate::{LexError, MachineLine, ParsedLine};
+
+/// Lints
+/// ---
+/// Checks for common kernel mistakes that still assemble. Each lint has a name and a level:
+/// `allow` silences it, `warn` reports it, `deny` reports it and fails the assembly.
+///
+/// Levels are set per lint from the command line (`--allow NAME`, `--warn NAME`, `--deny NAME`,
+/// where NAME may be `all`), and findings on one line are suppressed with a comment:
+/// `; lint: allow(unreachable-code)` at the end of the line, or on a comment-only line directly
+/// above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl fmt::Display for LintLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintLevel::Allow => write!(f, "allow"),
+            LintLevel::Warn => write!(f, "warning"),
+            LintLevel::Deny => write!(f, "error"),
+        }
+    }
+}
+
+pub struct Lint {
+    pub name: &'static str,
+    pub default_level: LintLevel,
+    pub description: &'static str,
+}
+
+pub const FLAGS_NOT_SET: &str = "flags-not-set";
+pub const SPECIAL_REGISTER_WRITE: &str = "special-register-write";
+pub const DIV_BY_ZERO: &str = "div-by-zero";
+pub const UNREACHABLE_CODE: &str = "unreachable-code";
+pub const MISSING_RET: &str = "missing-ret";
+pub const UNUSED_LABEL: &str = "unused-label";
+
+pub const LINTS: [Lint; 6] = [
+    Lint {
+        name: FLAGS_NOT_SET,
+        default_level: LintLevel::Warn,
+        description: "a branch can run before any CMP has set the NZP flags",
+    },
+    Lint {
+        name: SPECIAL_REGISTER_WRITE,
+        default_level: LintLevel::Deny,
+        description: "an instruction writes to read-only %blockIdx, %blockDim or %threadIdx",
+    },
+    Lint {
+        name: DIV_BY_ZERO,
+        default_level: LintLevel::Deny,
+        description: "DIV by a register that is always zero at that point",
+    },
+    Lint {
+        name: UNREACHABLE_CODE,
+        default_level: LintLevel::Warn,
+        description: "instructions that no path from the start of the program reaches",
+    },
+    Lint {
+        name: MISSING_RET,
+        default_level: LintLevel::Warn,
+        description: "execution can run past the last instruction without a RET",
+    },
+    Lint {
+        name: UNUSED_LABEL,
+        default_level: LintLevel::Warn,
+        description: "a label that no branch jumps to",
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct LintConfig {
+    levels: HashMap<&'static str, LintLevel>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            levels: LINTS
+                .iter()
+                .map(|lint| (lint.name, lint.default_level))
+                .collect(),
+        }
+    }
+}
+
+impl LintConfig {
+    /// Sets the level of the lint `name`, or of every lint for `all`.
+    pub fn set(&mut self, name: &str, level: LintLevel) -> Result<(), LexError> {
+        if name == "all" {
+            for lint in &LINTS {
+                self.levels.insert(lint.name, level);
+            }
+            return Ok(());
+        }
+
+        match LINTS.iter().find(|lint| lint.name == name) {
+            Some(lint) => {
+                self.levels.insert(lint.name, level);
+                Ok(())
+            }
+            None => Err(LexError::InvalidArgument(format!(
+                "unknown lint `{}`, expected one of: {}",
+                name,
+                LINTS.map(|lint| lint.name).join(", ")
+            ))),
+        }
+    }
+
+    pub fn level(&self, name: &str) -> LintLevel {
+        self.levels.get(name).copied().unwrap_or(LintLevel::Allow)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LintDiagnostic {
+    pub lint: &'static str,
+    pub level: LintLevel,
+    pub line_num: u32,
+    pub message: String,
+}
+
+impl fmt::Display for LintDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}]: line {}: {}",
+            self.level,
+            self.lint,
+            self.line_num + 1,
+            self.message
+        )
+    }
+}
+
+/// Runs every lint that is not allowed over the assembled operations. `source` is the parsed
+/// source, read for suppression comments.
+pub fn lint_program(
+    source: &[ParsedLine],
+    operations: &[MachineLine],
+    config: &LintConfig,
+) -> Vec<LintDiagnostic> {
+    let program: Vec<Instruction> = operations
+        .iter()
+        .filter_map(|line| line.bin.as_deref().and_then(Instruction::from_bin))
+        .collect();
+    if program.len() != operations.len() || program.is_empty() {
+        return vec![];
+    }
+
+    let line_of = |addr: usize| operations[addr].line_num;
+    let mut findings: Vec<(&'static str, u32, String)> = vec![];
+
+    let seen = reachable(&program);
+    let flags = flags_set(&program);
+    let states = constant_registers(&program);
+
+    for (addr, instruction) in program.iter().enumerate() {
+        if let Instruction::Branch { .. } = instruction {
+            if seen[addr] && !flags[addr] {
+                findings.push((
+                    FLAGS_NOT_SET,
+                    line_of(addr),
+                    format!("{instruction} can run before any CMP has set the NZP flags"),
+                ));
+            }
+        }
+
+        if let Some(rd) = instruction.dest().filter(|rd| rd.is_special()) {
+            findings.push((
+                SPECIAL_REGISTER_WRITE,
+                line_of(addr),
+                format!(
+                    "{instruction} writes to {}, which is read-only and keeps its value",
+                    rd.name()
+                ),
+            ));
+        }
+
+        if let (Instruction::Div { rt, .. }, Some(state)) = (instruction, states[addr]) {
+            if state[rt.index()].as_const() == Some(0) {
+                findings.push((
+                    DIV_BY_ZERO,
+                    line_of(addr),
+                    format!("DIV divides by {}, which is always 0 here", rt.name()),
+                ));
+            }
+        }
+
+        // report each run of unreachable instructions once, at its start
+        if !seen[addr] && (addr == 0 || seen[addr - 1]) {
+            findings.push((
+                UNREACHABLE_CODE,
+                line_of(addr),
+                format!("{instruction} is unreachable"),
+            ));
+        }
+    }
+
+    for addr in falls_off_end(&program) {
+        findings.push((
+            MISSING_RET,
+            line_of(addr),
+            "execution can continue past the end of the program, end it with RET".into(),
+        ));
+    }
+
+    for (label, line_num) in unused_labels(source) {
+        findings.push((UNUSED_LABEL, line_num, format!("{label} is never used")));
+    }
+
+    let suppressions = suppressions(source);
+    findings
+        .into_iter()
+        .filter_map(|(lint, line_num, message)| {
+            let level = config.level(lint);
+            let suppressed = suppressions
+                .get(&line_num)
+                .is_some_and(|names| names.iter().any(|name| name == lint || name == "all"));
+
+            (level != LintLevel::Allow && !suppressed).then_some(LintDiagnostic {
+                lint,
+                level,
+                line_num,
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Labels defined in the source that no branch names, with the line defining them
+fn unused_labels(source: &[ParsedLine]) -> Vec<(String, u32)> {
+    // scoped and numeric labels renamed, so references match definitions
+    let written = split_labels(source.to_vec());
+    let lines = resolve_labels(written.clone());
+    // branch targets, data labels loaded with CONST, and labels exported to the linker
+    let targets: Vec<&str> = lines
+        .iter()
+        .flat_map(|line| match line.tokens.first().map(|t| t.as_str()) {
+            Some(".global") => line.tokens[1..].iter().map(|t| t.as_str()).collect(),
+            _ => symbol_reference(&line.tokens)
+                .into_iter()
+                .collect::<Vec<_>>(),
+        })
+        .collect();
+
+    written
+        .iter()
+        .zip(&lines)
+        .filter_map(|(written, line)| {
+            let label = line.tokens.first()?.strip_suffix(':')?;
+            (!targets.contains(&label)).then(|| {
+                (
+                    written.tokens[0].trim_end_matches(':').to_string(),
+                    line.line_num,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Maps source lines to the lints allowed on them by `lint: allow(...)` comments
+fn suppressions(source: &[ParsedLine]) -> HashMap<u32, Vec<String>> {
+    let mut allowed: HashMap<u32, Vec<String>> = HashMap::new();
+    let mut pending: Vec<String> = vec![];
+
+    for line in source {
+        let names = line
+            .comment
+            .as_deref()
+            .map(allowed_in_comment)
+            .unwrap_or_default();
+
+        if line.tokens.is_empty() {
+            // a comment-only line applies to the next line with code
+            pending.extend(names);
+        } else {
+            let entry = allowed.entry(line.line_num).or_default();
+            entry.append(&mut pending);
+            entry.extend(names);
+        }
+    }
+
+    allowed
+}
+
+fn allowed_in_comment(comment: &str) -> Vec<String> {
+    let Some(pos) = comment.find("lint:") else {
+        return vec![];
+    };
+    let rest = comment[pos + "lint:".len()..].trim_start();
+
+    rest.strip_prefix("allow(")
+        .and_then(|rest| rest.split_once(')'))
+        .map(|(names, _)| {
+            names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::instruction::NZP_Z;
+    use crate::{parse_line, LineType, Register};
+
+    /// The lint and (0-based) line of every finding in a program given one source line at a
+    /// time, along with the instruction that line assembles to, if any
+    fn findings(
+        program: &[(&str, Option<Instruction>)],
+        config: &LintConfig,
+    ) -> Vec<(&'static str, u32)> {
+        let source: Vec<ParsedLine> = program
+            .iter()
+            .enumerate()
+            .map(|(line_num, (text, _))| parse_line(line_num, text).unwrap())
+            .collect();
+        let operations: Vec<MachineLine> = program
+            .iter()
+            .zip(&source)
+            .filter_map(|((_, instruction), parsed)| {
+                instruction.map(|instruction| MachineLine {
+                    line_type: LineType::Operation,
+                    parsed_line: parsed.clone(),
+                    bin: Some(format!("{:016b}", instruction.encode())),
+                    comment: parsed.comment.clone(),
+                    line_num: parsed.line_num,
+                })
+            })
+            .collect();
+        lint_program(&source, &operations, config)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.lint, diagnostic.line_num))
+            .collect()
+    }
+
+    const NOP: Option<Instruction> = Some(Instruction::Nop);
+    const RET: Option<Instruction> = Some(Instruction::Ret);
+
+    fn branch(nzp: u8, target: u8) -> Option<Instruction> {
+        Some(Instruction::Branch { nzp, target })
+    }
+
+    fn cmp() -> Option<Instruction> {
+        Some(Instruction::Cmp {
+            rs: Register::R0,
+            rt: Register::R0,
+        })
+    }
+
+    fn constant(rd: Register, imm: u8) -> Option<Instruction> {
+        Some(Instruction::Const { rd, imm })
+    }
+
+    #[test]
+    fn each_lint_finds_its_mistake() {
+        let config = LintConfig::default();
+        let div = Some(Instruction::Div {
+            rd: Register::R0,
+            rs: Register::R0,
+            rt: Register::R1,
+        });
+        for (program, expected) in [
+            (
+                vec![("BRz END", branch(NZP_Z, 1)), ("END: RET", RET)],
+                (FLAGS_NOT_SET, 0),
+            ),
+            (
+                vec![
+                    ("CONST %threadIdx, #1", constant(Register::ThreadIdx, 1)),
+                    ("RET", RET),
+                ],
+                (SPECIAL_REGISTER_WRITE, 0),
+            ),
+            (
+                vec![
+                    ("CONST R1, #0", constant(Register::R1, 0)),
+                    ("DIV R0, R0, R1", div),
+                    ("RET", RET),
+                ],
+                (DIV_BY_ZERO, 1),
+            ),
+            (
+                vec![("RET", RET), ("NOP", NOP), ("NOP", NOP), ("RET", RET)],
+                (UNREACHABLE_CODE, 1),
+            ),
+            (
+                vec![("CONST R0, #1", constant(Register::R0, 1))],
+                (MISSING_RET, 0),
+            ),
+        ] {
+            assert_eq!(findings(&program, &config), [expected], "{program:?}");
+        }
+        let program = [
+            ("CONST R0, #1", constant(Register::R0, 1)),
+            ("CMP R0, R0", cmp()),
+            ("L: BRz L", branch(NZP_Z, 2)),
+            ("RET", RET),
+        ];
+        assert_eq!(findings(&program, &config), []);
+    }
+
+    #[test]
+    fn levels_are_set_per_lint_or_for_all() {
+        let program = [
+            ("CMP R0, R0", cmp()),
+            ("BRz L", branch(NZP_Z, 4)),
+            ("RET", RET),
+            ("NOP", NOP),
+            ("L: NOP", NOP),
+        ];
+        let mut config = LintConfig::default();
+        assert_eq!(config.level(DIV_BY_ZERO), LintLevel::Deny);
+        config.set(UNREACHABLE_CODE, LintLevel::Allow).unwrap();
+        assert_eq!(findings(&program, &config), [(MISSING_RET, 4)]);
+        config.set("all", LintLevel::Allow).unwrap();
+        assert_eq!(findings(&program, &config), []);
+        assert!(config.set("no-such-lint", LintLevel::Warn).is_err());
+    }
+
+    #[test]
+    fn comments_suppress_lints_on_their_line_or_the_next() {
+        let config = LintConfig::default();
+        let program = [
+            ("RET", RET),
+            ("NOP ; lint: allow(unreachable-code, missing-ret)", NOP),
+            ("RET", RET),
+            ("; lint: allow(all)", None),
+            ("A: NOP", NOP),
+        ];
+        assert_eq!(findings(&program, &config), []);
+        let program = [
+            ("RET", RET),
+            ("NOP ; lint: allow(missing-ret)", NOP),
+            ("RET", RET),
+        ];
+        assert_eq!(findings(&program, &config), [(UNREACHABLE_CODE, 1)]);
+    }
+
+    fn unused(source: &str) -> Vec<String> {
+        let assembly = assemble(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        lint_program(
+            &assembly.source_lines,
+            &assembly.operations,
+            &LintConfig::default(),
+        )
+        .into_iter()
+        .filter(|diagnostic| diagnostic.lint == UNUSED_LABEL)
+        .map(|diagnostic| diagnostic.message)
+        .collect()
+    }
+
+    #[test]
+    fn numeric_labels_used_by_a_branch_are_not_unused() {
+        let source =
+            "CONST R0, #3\nCONST R1, #1\nCMP R0, R0\n1: SUB R0, R0, R1\nCMP R0, R1\nBRp 1b\nRET\n";
+        assert_eq!(unused(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn local_labels_are_matched_within_their_scope() {
+        let source = "CMP R0, R0\nA: NOP\n.inner: BRn .inner\nB: NOP\n.inner: NOP\nBRnzp A\nRET\n";
+        assert_eq!(unused(source), ["B is never used", ".inner is never used"]);
+    }
+}
@@ -0,0 +1,744 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use crate::assembler::{assemble, resolve_labels, split_labels, symbol_reference, Assembly};
+use crate::diagnostic::{error_diagnostic, lint_diagnostic, span, Severity, Span};
+use crate::immediate::parse_immediate;
+use crate::lint::{lint_program, LintConfig, LintDiagnostic};
+use crate::operation::Operation;
+use crate::{parse_line, ParsedLine, Register, DIRECTIVES};
+
+/// Language Server
+/// ---
+/// A Language Server Protocol server for TinyGPU assembly, speaking JSON-RPC over any reader and
+/// writer (stdin/stdout for the `tiny-gpu-lsp` binary). Documents are synced in full and
+/// reassembled on every change, which keeps diagnostics exactly what the assembler reports.
+///
+/// Supported: diagnostics (errors and lints), hover, go-to-definition and find-references for
+/// labels, completion, and document symbols. Positions count UTF-16 code units, as LSP does by
+/// default.
+pub fn run(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+
+    while let Some(body) = read_message(&mut input)? {
+        // a body that is not JSON has no id to reply to, so the error goes out with a null one
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": PARSE_ERROR, "message": format!("Parse error: {err}")},
+                });
+                write_message(&mut output, &response)?;
+                continue;
+            }
+        };
+        let method = message
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let (reply, notifications) = server.handle(method, &params);
+
+        // notifications (no id) never get a reply
+        if let Some(id) = message.get("id") {
+            let response = match reply {
+                Reply::Result(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Reply::Error(code, message) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": code, "message": message},
+                }),
+            };
+            write_message(&mut output, &response)?;
+        }
+
+        for notification in notifications {
+            write_message(&mut output, &notification)?;
+        }
+    }
+
+    Ok(())
+}
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+enum Reply {
+    Result(Value),
+    Error(i64, String),
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    fn handle(&mut self, method: &str, params: &Value) -> (Reply, Vec<Value>) {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        match method {
+            "initialize" => (Reply::Result(capabilities()), vec![]),
+            "shutdown" => (Reply::Result(Value::Null), vec![]),
+
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), text.to_string());
+                (
+                    Reply::Result(Value::Null),
+                    vec![publish_diagnostics(&uri, text)],
+                )
+            }
+            "textDocument/didChange" => {
+                // full sync: the last change holds the whole document
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                let text = self.documents.get(&uri).cloned().unwrap_or_default();
+                (
+                    Reply::Result(Value::Null),
+                    vec![publish_diagnostics(&uri, &text)],
+                )
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                let cleared = json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": {"uri": uri, "diagnostics": []},
+                });
+                (Reply::Result(Value::Null), vec![cleared])
+            }
+
+            "textDocument/hover"
+            | "textDocument/definition"
+            | "textDocument/references"
+            | "textDocument/completion"
+            | "textDocument/documentSymbol" => {
+                let Some(text) = self.documents.get(&uri) else {
+                    return (
+                        Reply::Error(INVALID_PARAMS, format!("unknown document {uri}")),
+                        vec![],
+                    );
+                };
+                let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+                let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+                let position = (line, from_utf16(text, line, character));
+
+                let result = match method {
+                    "textDocument/hover" => hover(text, position),
+                    "textDocument/definition" => definition(&uri, text, position),
+                    "textDocument/references" => {
+                        let include_declaration = params["context"]["includeDeclaration"]
+                            .as_bool()
+                            .unwrap_or(true);
+                        references(&uri, text, position, include_declaration)
+                    }
+                    "textDocument/completion" => completion(text),
+                    _ => document_symbols(text),
+                };
+                (Reply::Result(result), vec![])
+            }
+
+            _ => (
+                Reply::Error(METHOD_NOT_FOUND, format!("unsupported method {method}")),
+                vec![],
+            ),
+        }
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": 1,
+            "hoverProvider": true,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "documentSymbolProvider": true,
+            "completionProvider": {"triggerCharacters": ["%", "."]},
+        },
+        "serverInfo": {"name": "tiny-gpu-lsp", "version": env!("CARGO_PKG_VERSION")},
+    })
+}
+
+/// Assembles and lints the document
+fn analyse(text: &str) -> (Assembly, Vec<LintDiagnostic>) {
+    let assembly = assemble(text);
+    // lints only make sense for a program that assembled
+    let lints = if assembly.errors.is_empty() {
+        lint_program(
+            &assembly.source_lines,
+            &assembly.operations,
+            &LintConfig::default(),
+        )
+    } else {
+        vec![]
+    };
+    (assembly, lints)
+}
+
+fn publish_diagnostics(uri: &str, text: &str) -> Value {
+    let (assembly, lints) = analyse(text);
+    let diagnostics: Vec<Value> = assembly
+        .errors
+        .iter()
+        .map(|err| error_diagnostic(uri, text, Some(&assembly), err))
+        .chain(lints.iter().map(|lint| lint_diagnostic(uri, text, lint)))
+        .map(|diagnostic| {
+            let span_range = |line: u32, span: Span| {
+                range(text, line as usize - 1, span.start - 1, span.end - 1)
+            };
+            json!({
+                "range": span_range(diagnostic.line, diagnostic.span),
+                "severity": if diagnostic.severity == Severity::Error { 1 } else { 2 },
+                "code": diagnostic.code,
+                "source": "tiny-gpu-assembler",
+                "message": diagnostic.message,
+                "relatedInformation": diagnostic.related.iter().map(|related| json!({
+                    "location": {"uri": uri, "range": span_range(related.line, related.span)},
+                    "message": related.message,
+                })).collect::<Vec<Value>>(),
+            })
+        })
+        .collect();
+
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+/// The LSP range of the characters `start..end` of a line of `text`
+fn range(text: &str, line: usize, start: usize, end: usize) -> Value {
+    let chars = text.lines().nth(line).unwrap_or_default().chars();
+    let utf16 = |column: usize| -> usize { chars.clone().take(column).map(char::len_utf16).sum() };
+    json!({
+        "start": {"line": line, "character": utf16(start)},
+        "end": {"line": line, "character": utf16(end)},
+    })
+}
+
+/// The character of a line of `text` at `units` UTF-16 code units into it
+fn from_utf16(text: &str, line: usize, units: usize) -> usize {
+    let mut counted = 0;
+    text.lines()
+        .nth(line)
+        .unwrap_or_default()
+        .chars()
+        .take_while(|c| {
+            counted += c.len_utf16();
+            counted <= units
+        })
+        .count()
+}
+
+/// A label where it is defined or referenced: as written, the name the assembler resolves it
+/// to (`OUTER.inner`, `1@N`), and the characters it covers
+struct Symbol {
+    written: String,
+    resolved: String,
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Symbol {
+    fn range(&self, text: &str) -> Value {
+        range(text, self.line, self.start, self.end)
+    }
+}
+
+/// Label definitions, and branch targets and data labels loaded with CONST. Local and numeric
+/// labels are resolved as the assembler resolves them, so each is found within its scope.
+struct Symbols {
+    definitions: Vec<Symbol>,
+    references: Vec<Symbol>,
+}
+
+impl Symbols {
+    /// The definition or reference under the cursor
+    fn at(&self, (line, character): (usize, usize)) -> Option<&Symbol> {
+        self.definitions
+            .iter()
+            .chain(&self.references)
+            .find(|symbol| symbol.line == line && (symbol.start..=symbol.end).contains(&character))
+    }
+}
+
+fn symbols(text: &str) -> Symbols {
+    let lines: Vec<&str> = text.lines().collect();
+    let parsed: Vec<ParsedLine> = lines
+        .iter()
+        .enumerate()
+        .map(|(line_num, line)| {
+            parse_line(line_num, line).unwrap_or(ParsedLine {
+                tokens: vec![],
+                comment: None,
+                line_num: line_num as u32,
+            })
+        })
+        .collect();
+    let written = split_labels(parsed);
+    let resolved = resolve_labels(written.clone());
+
+    let symbol = |written: &str, resolved: &str, line_num: u32, reference: bool| {
+        let line = line_num as usize;
+        // a reference is never the label the line starts with (`.inner: BRn .inner`)
+        let text = match lines[line]
+            .split_whitespace()
+            .next()
+            .filter(|word| reference && word.ends_with(':'))
+        {
+            Some(label) => lines[line].replacen(label, &" ".repeat(label.chars().count()), 1),
+            None => lines[line].to_string(),
+        };
+        let span = span(&text, Some(written));
+        Symbol {
+            written: written.to_string(),
+            resolved: resolved.to_string(),
+            line,
+            start: span.start - 1,
+            end: span.end - 1,
+        }
+    };
+
+    let mut symbols = Symbols {
+        definitions: vec![],
+        references: vec![],
+    };
+    for (written, resolved) in written.iter().zip(&resolved) {
+        if let Some(label) = resolved.tokens.first().and_then(|t| t.strip_suffix(':')) {
+            let name = written.tokens[0].trim_end_matches(':');
+            symbols
+                .definitions
+                .push(symbol(name, label, resolved.line_num, false));
+        } else if let Some(target) = symbol_reference(&resolved.tokens) {
+            // the target is the last operand of both branches and CONST
+            let name = written.tokens.last().map_or("", |t| t.as_str());
+            symbols
+                .references
+                .push(symbol(name, target, resolved.line_num, true));
+        }
+    }
+
+    symbols
+}
+
+/// The word under the cursor, with labels, registers, directives and immediates as one word
+fn word_at(text: &str, (line, character): (usize, usize)) -> Option<String> {
+    let line = text.lines().nth(line)?;
+    let is_word = |c: char| c.is_ascii_alphanumeric() || "_%.#-'".contains(c);
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = character.min(chars.len());
+    while end < chars.len() && is_word(chars[end]) {
+        end += 1;
+    }
+
+    (start < end).then(|| chars[start..end].iter().collect())
+}
+
+fn hover(text: &str, position: (usize, usize)) -> Value {
+    let Some(word) = word_at(text, position) else {
+        return Value::Null;
+    };
+
+    let contents = if let Ok(op) = Operation::from_str(&word) {
+        let mut contents = format!(
+            "**{}** `{}`\n\n{}\n\nEncoding: `{}`",
+            op.name(),
+            op.syntax(),
+            op.description(),
+            op.encoding()
+        );
+        // the words this line actually assembled to
+        let words: Vec<String> = assemble(text)
+            .operations
+            .iter()
+            .filter(|line| line.line_num as usize == position.0)
+            .filter_map(|line| line.bin.as_deref())
+            .filter_map(|bin| u16::from_str_radix(bin, 2).ok())
+            .map(|word| format!("`0x{word:04x}` (`{word:016b}`)"))
+            .collect();
+        if !words.is_empty() {
+            contents += &format!("\n\nThis line: {}", words.join(", "));
+        }
+        contents
+    } else if word == "LI" {
+        "**LI** `LI Rd, #value`\n\nPseudoinstruction: loads a constant of any size that fits the register width, using the `.scratch` register for values a single CONST cannot hold.".to_string()
+    } else if let Ok(register) = Register::from_str(&word) {
+        let kind = if register.is_special() {
+            "special-purpose, read-only register"
+        } else {
+            "general-purpose register"
+        };
+        format!(
+            "**{}**: {kind}, encoded as `{}`",
+            register.name(),
+            register.bits()
+        )
+    } else if let Some((_, description)) = DIRECTIVES.iter().find(|(name, _)| *name == word) {
+        format!("`{description}`")
+    } else if let Some(value) = word
+        .starts_with('#')
+        .then(|| parse_immediate(&word).ok())
+        .flatten()
+    {
+        format!("`{value}` = `0x{:x}` = `0b{:b}`", value as u8, value as u8)
+    } else if let Some(symbol) = symbols(text).at(position) {
+        let assembly = assemble(text);
+        let find = |labels: &[(String, u16)]| {
+            labels
+                .iter()
+                .find(|(label, _)| *label == symbol.resolved)
+                .map(|(_, address)| *address)
+        };
+        let address = (find(&assembly.label_addresses).map(|address| ("program", address)))
+            .or_else(|| find(&assembly.data_labels).map(|address| ("data", address)));
+        match address {
+            Some((memory, address)) => {
+                format!("label `{}` at {memory} address {address}", symbol.written)
+            }
+            None => format!("label `{}`", symbol.written),
+        }
+    } else {
+        return Value::Null;
+    };
+
+    json!({"contents": {"kind": "markdown", "value": contents}})
+}
+
+fn definition(uri: &str, text: &str, position: (usize, usize)) -> Value {
+    let symbols = symbols(text);
+    let Some(symbol) = symbols.at(position) else {
+        return Value::Null;
+    };
+
+    symbols
+        .definitions
+        .iter()
+        .find(|definition| definition.resolved == symbol.resolved)
+        .map_or(
+            Value::Null,
+            |definition| json!({"uri": uri, "range": definition.range(text)}),
+        )
+}
+
+fn references(uri: &str, text: &str, position: (usize, usize), include_declaration: bool) -> Value {
+    let symbols = symbols(text);
+    let Some(symbol) = symbols.at(position) else {
+        return json!([]);
+    };
+
+    let declarations = symbols.definitions.iter().filter(|_| include_declaration);
+
+    let locations: Vec<Value> = declarations
+        .chain(&symbols.references)
+        .filter(|other| other.resolved == symbol.resolved)
+        .map(|other| json!({"uri": uri, "range": other.range(text)}))
+        .collect();
+    json!(locations)
+}
+
+// LSP CompletionItemKind and SymbolKind values
+const COMPLETION_KEYWORD: u32 = 14;
+const COMPLETION_VARIABLE: u32 = 6;
+const COMPLETION_REFERENCE: u32 = 18;
+const SYMBOL_FUNCTION: u32 = 12;
+
+fn completion(text: &str) -> Value {
+    let mut items: Vec<Value> = Operation::ALL
+        .iter()
+        .map(|op| {
+            json!({
+                "label": op.name(),
+                "kind": COMPLETION_KEYWORD,
+                "detail": op.syntax(),
+                "documentation": op.description(),
+            })
+        })
+        .collect();
+
+    items.push(json!({
+        "label": "LI",
+        "kind": COMPLETION_KEYWORD,
+        "detail": "LI Rd, #value",
+        "documentation": "Pseudoinstruction: loads a constant of any size",
+    }));
+
+    items.extend(Register::ALL.iter().map(|register| {
+        json!({
+            "label": register.name(),
+            "kind": COMPLETION_VARIABLE,
+        })
+    }));
+
+    items.extend(DIRECTIVES.iter().map(|(name, description)| {
+        json!({
+            "label": name,
+            "kind": COMPLETION_KEYWORD,
+            "detail": description,
+        })
+    }));
+
+    // numeric labels may be defined many times, local ones once per scope
+    let mut labels: Vec<String> = vec![];
+    for symbol in symbols(text).definitions {
+        if !labels.contains(&symbol.written) {
+            labels.push(symbol.written);
+        }
+    }
+    items.extend(labels.into_iter().map(|label| {
+        json!({
+            "label": label,
+            "kind": COMPLETION_REFERENCE,
+            "detail": "label",
+        })
+    }));
+
+    json!(items)
+}
+
+fn document_symbols(text: &str) -> Value {
+    let symbols: Vec<Value> = symbols(text)
+        .definitions
+        .iter()
+        .map(|symbol| {
+            json!({
+                "name": symbol.written,
+                "kind": SYMBOL_FUNCTION,
+                "range": symbol.range(text),
+                "selectionRange": symbol.range(text),
+            })
+        })
+        .collect();
+    json!(symbols)
+}
+
+/// The body of the next message, None once the input ends
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///kernel.asm";
+
+    /// Runs the server over framed `requests` (ids from 1) and returns everything it wrote
+    fn session(text: &str, requests: &[(&str, Value)]) -> Vec<Value> {
+        let mut input = vec![];
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": URI, "text": text}},
+        });
+        write_message(&mut input, &open).unwrap();
+        for (id, (method, params)) in requests.iter().enumerate() {
+            let request =
+                json!({"jsonrpc": "2.0", "id": id + 1, "method": method, "params": params});
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let mut output = vec![];
+        run(Cursor::new(input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut output).unwrap())
+            .map(|body| serde_json::from_slice(&body).unwrap())
+            .collect()
+    }
+
+    /// The result of the request with `id`
+    fn result(messages: &[Value], id: u64) -> &Value {
+        &messages
+            .iter()
+            .find(|message| message["id"] == id)
+            .expect("no reply")["result"]
+    }
+
+    /// A range as the client sees it, in UTF-16 code units
+    fn lsp_range(line: usize, start: usize, end: usize) -> Value {
+        json!({
+            "start": {"line": line, "character": start},
+            "end": {"line": line, "character": end},
+        })
+    }
+
+    fn at(line: usize, character: usize) -> Value {
+        json!({"textDocument": {"uri": URI}, "position": {"line": line, "character": character}})
+    }
+
+    const SOURCE: &str =
+        "START: CONST R0, #1\nCMP R0, R0\nLOOP: SUB R0, R0, R0 ; count\nBRp LOOP\nRET\n";
+
+    #[test]
+    fn initialize_reports_capabilities() {
+        let messages = session(SOURCE, &[("initialize", json!({}))]);
+        assert_eq!(
+            result(&messages, 1)["capabilities"]["definitionProvider"],
+            true
+        );
+        // didOpen published the diagnostics of the document
+        assert!(messages
+            .iter()
+            .any(|message| message["method"] == "textDocument/publishDiagnostics"));
+    }
+
+    #[test]
+    fn definition_and_references_of_a_label() {
+        let messages = session(
+            SOURCE,
+            &[
+                ("textDocument/definition", at(3, 5)),
+                ("textDocument/references", at(2, 1)),
+            ],
+        );
+        assert_eq!(result(&messages, 1)["range"], lsp_range(2, 0, 4));
+        assert_eq!(
+            result(&messages, 2),
+            &json!([
+                {"uri": URI, "range": lsp_range(2, 0, 4)},
+                {"uri": URI, "range": lsp_range(3, 4, 8)},
+            ])
+        );
+    }
+
+    #[test]
+    fn hover_shows_label_addresses_and_encodings() {
+        let messages = session(
+            SOURCE,
+            &[
+                ("textDocument/hover", at(3, 5)),
+                ("textDocument/hover", at(1, 1)),
+            ],
+        );
+        let hover = |id| {
+            result(&messages, id)["contents"]["value"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(hover(1), "label `LOOP` at program address 2");
+        assert!(hover(2).contains("This line: `0x2000`"), "{}", hover(2));
+    }
+
+    #[test]
+    fn trailing_whitespace_after_a_comment_is_not_code() {
+        // whitespace after the comment once shifted the slice into the middle of `é`
+        let text = "LOOP: NOP ; é\u{2003}\nBRnzp LOOP\n";
+        let messages = session(text, &[("textDocument/definition", at(1, 7))]);
+        assert_eq!(result(&messages, 1)["range"], lsp_range(0, 0, 4));
+    }
+
+    const SCOPED: &str = "CMP R0, R0\nA: NOP\n.inner: BRn .inner\nB: NOP\n.inner: BRn .inner\n1: NOP\nBRz 1b\nBRnzp A\nRET\n";
+
+    #[test]
+    fn local_and_numeric_labels_resolve_within_their_scope() {
+        let messages = session(
+            SCOPED,
+            &[
+                ("textDocument/definition", at(4, 13)),
+                ("textDocument/references", at(4, 1)),
+                ("textDocument/definition", at(6, 5)),
+                ("textDocument/hover", at(4, 13)),
+            ],
+        );
+        assert_eq!(result(&messages, 1)["range"], lsp_range(4, 0, 6));
+        assert_eq!(
+            result(&messages, 2),
+            &json!([
+                {"uri": URI, "range": lsp_range(4, 0, 6)},
+                {"uri": URI, "range": lsp_range(4, 12, 18)},
+            ])
+        );
+        assert_eq!(result(&messages, 3)["range"], lsp_range(5, 0, 1));
+        assert_eq!(
+            result(&messages, 4)["contents"]["value"],
+            "label `.inner` at program address 4"
+        );
+    }
+
+    #[test]
+    fn malformed_bodies_get_a_parse_error_and_the_server_carries_on() {
+        let mut input = vec![];
+        write!(input, "Content-Length: 5\r\n\r\n{{oops").unwrap();
+        write_message(
+            &mut input,
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"}),
+        )
+        .unwrap();
+
+        let mut output = vec![];
+        run(Cursor::new(input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        let messages: Vec<Value> = std::iter::from_fn(|| read_message(&mut output).unwrap())
+            .map(|body| serde_json::from_slice(&body).unwrap())
+            .collect();
+        assert_eq!(messages[0]["id"], Value::Null);
+        assert_eq!(messages[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(messages[1]["id"], 1);
+    }
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        // 😀 is one char but two UTF-16 code units, so the line ends at 15 rather than 14
+        let text = "CONST R0, #'😀' ; x\nRET\n";
+        let messages = session(text, &[]);
+        let diagnostic = &messages[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["code"], "invalid-immediate");
+        assert_eq!(diagnostic["range"], lsp_range(0, 0, 15));
+        // and the cursor after it is one character earlier
+        assert_eq!(from_utf16(text, 0, 17), 16);
+    }
+}
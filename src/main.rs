@@ -1,429 +1,560 @@
+use lib::assembler::{assemble, assemble_with, AssembleOptions, Assembly};
+use lib::batch::{assemble_suite, load_suite, summary, Suite};
+use lib::conditional::parse_define;
+use lib::debugger::Debugger;
+use lib::diagnostic::{error_diagnostic, file_diagnostic, lint_diagnostic};
+use lib::format::format_source;
+use lib::lang::compile;
+use lib::link::{build_object, link, Object};
+use lib::lint::{lint_program, LintConfig, LintLevel};
+use lib::memmap::memory_map;
+use lib::optimize::optimize;
+use lib::output::{build_output, Hardware, HardwareOverrides, DEFAULT_MEMORY_DELAY};
+use lib::report::resource_report;
+use lib::schedule::{schedule, LatencyModel};
+use lib::simulator::Simulator;
+use lib::sweep::{parse_parameter, sweep_kernels, Parameter};
+use lib::trace::{first_divergence, parse_cocotb_log, simulated_visits, trace};
+use lib::*;
 use std::env;
 use std::fs;
-use std::ops::Index;
-use std::str::FromStr;
-
-use lib::operation::Operation;
-use lib::operation::Operation::*;
-use lib::*;
-use serde::Serialize;
 use std::path::Path;
 
-fn parse_line(line_num: usize, line: &str) -> Option<ParsedLine> {
-    // remove trailing/leading whitespace
-    let line = line.trim();
-
-    // Split the string into two parts: before and after the semicolon
-    let (comment, rest) = if let Some(pos) = line.find(';') {
-        let rest = &line[..pos]; // The part before the semicolon (trimmed)
-        let comment = Some(String::from(&line[pos + 1..])); // The part after the semicolon (trimmed)
-        (comment, rest)
-    } else {
-        // If no semicolon, return no comment
-        (None, line) // Return the line as "rest"
-    };
+/// `format [--check] files...` rewrites each file in canonical form, or with --check only reports
+/// the files that are not formatted and exits with an error if there are any.
+fn format_main(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
 
-    let tokens: Vec<&str> = rest.split_whitespace().collect::<Vec<&str>>();
-    let a: Vec<String> = tokens.into_iter().map(|a| a.to_owned()).collect();
+    if paths.is_empty() {
+        eprintln!("Error: expected at least one file to format");
+        std::process::exit(1);
+    }
 
-    Some(ParsedLine {
-        tokens: a,
-        comment,
-        line_num: (line_num as u32),
-    })
-}
+    let mut unformatted = 0;
+    for path in paths {
+        let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Error: could not read '{}': {}", path, err);
+            std::process::exit(1);
+        });
+
+        let formatted = format_source(&contents).unwrap_or_else(|err| {
+            eprintln!("Error: could not format '{}': {}", path, err);
+            std::process::exit(1);
+        });
+        if formatted == contents {
+            continue;
+        }
 
-fn operation_conv(
-    mut lexed_line: Box<dyn LexedLine>,
-    label_addresses: Vec<(String, u16)>,
-) -> Result<MachineLine, LexError> {
-    // operation_conv(a, &operation.parsed, label_numbers, &lexed_lines)
-    // OperationType, &parsedline, label_nums, &lexedlines
-
-    if lexed_line
-        .as_any_mut()
-        .downcast_mut::<OperationLine>()
-        .is_none()
-    {
-        // determine the correct type
-        let typ: LineType;
-        if lexed_line.as_any_mut().downcast_mut::<BadLine>().is_some() {
-            typ = LineType::Bad;
-        } else if lexed_line
-            .as_any_mut()
-            .downcast_mut::<HumanLine>()
-            .is_some()
-        {
-            typ = LineType::Human;
-        } else if lexed_line
-            .as_any_mut()
-            .downcast_mut::<OperationLine>()
-            .is_some()
-        {
-            typ = LineType::Operation;
-        } else if lexed_line
-            .as_any_mut()
-            .downcast_mut::<MemoryLine>()
-            .is_some()
-        {
-            typ = LineType::Memory;
-        } else if lexed_line
-            .as_any_mut()
-            .downcast_mut::<LabelLine>()
-            .is_some()
-        {
-            typ = LineType::Label;
+        if check {
+            println!("would reformat {}", path);
+            unformatted += 1;
         } else {
-            return Err(LexError::InvalidArgument("terrible, man".to_owned()));
+            fs::write(path, formatted).unwrap_or_else(|err| {
+                eprintln!("Error: could not write '{}': {}", path, err);
+                std::process::exit(1);
+            });
+            println!("formatted {}", path);
         }
-        return {
-            Ok(MachineLine {
-                line_type: typ,
-                parsed_line: lexed_line.parsed().clone(),
-                bin: None,
-                comment: lexed_line.parsed().comment.clone(),
-                line_num: lexed_line.parsed().line_num,
-            })
-        };
-
-        // {Ok (MachineLine {
-        //     bin: None,
-        //     comment: parsed_line.comment,
-        //     line_num: parsed_line.line_num,
-        //     parsed_line: parsed_line,
-        //     } ) };
+    }
 
-        // return Err(LexError::InvalidArgument("".to_string()));
+    if unformatted > 0 {
+        std::process::exit(1);
     }
+}
 
-    let operation_line = lexed_line.as_any().downcast_ref::<OperationLine>().unwrap();
-    let op: Operation = Operation::from_str(operation_line.parsed.tokens.first().unwrap()).unwrap();
-    let parsed_line = operation_line.parsed.clone();
+/// Reads and assembles `path` for the simulator, exiting on any error.
+fn load_kernel(path: &str) -> (String, Assembly, Simulator) {
+    let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Error: could not read '{}': {}", path, err);
+        std::process::exit(1);
+    });
 
-    if op.num_args() != parsed_line.tokens.len() as u8 - 1 {
-        return Err(LexError::InvalidOperation("".to_string()));
+    let assembly = assemble(&contents);
+    if !assembly.errors.is_empty() {
+        for err in &assembly.errors {
+            eprintln!("Error: {}", err);
+        }
+        std::process::exit(1);
     }
+    let initial_data = assembly.initial_data().unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
 
-    let operands = &parsed_line.tokens; // this is actually the operator AND the operands. anyway...
+    let launch = assembly.launch(&Hardware::default()).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+    let sim = Simulator::new(assembly.program(), &initial_data, launch);
+    (contents, assembly, sim)
+}
 
-    // define closure that (semi)gracefully handles errors that should never occur, rather than making the below match statement unreadable
-    let get_operand_from_ind = |index: u16| {
-        operands
-            .get(index as usize)
-            .unwrap_or_else(|| panic!("should have {} args", op.num_args()))
+/// `debug source.asm` assembles the kernel and steps through it in the functional simulator.
+fn debug_main(args: &[String]) {
+    let [path] = args else {
+        eprintln!("Error: usage: tiny-gpu-assembler debug [source.asm]");
+        std::process::exit(1);
     };
 
-    let bin = match op {
-        NOP => Ok(op.as_opcode().to_owned() + "000000000000"),
-
-        BRnzp => {
-            //// NZP Shenanigans
-
-            dbg!("BRnzp binary here");
-            let branch_instr_string = get_operand_from_ind(0);
-            // Extract the "nzp" flags
-            let nzp_flags: String = branch_instr_string
-                .chars()
-                .filter(|&c| c == 'n' || c == 'z' || c == 'p')
-                .collect();
-
-            // dbg!(nzp_flags);
-            // panic!();
-            // Default to "1000" if no flags are found, as per the spec
-            let nzp = if nzp_flags.is_empty() {
-                "0000".to_string() // Default value if no flags are present
-            } else {
-                // Here you could further process or map the flags into the correct string
-                // For simplicity, just use the flags directly
-                format!(
-                    "{:04b}",
-                    nzp_flags.chars().fold(0, |acc, flag| {
-                        (acc << 1)
-                            | match flag {
-                                'n' => 8,
-                                'z' => 4,
-                                'p' => 2,
-                                _ => 0,
-                            }
-                    })
-                )
-            };
-
-            if nzp == "0000" {
-                return Err(LexError::InvalidArgument("Branch instruction with no NZP flags - will never branch. Did you mean to branch in all cases? (BRnzp)".into()));
-            }
+    let (contents, assembly, sim) = load_kernel(path);
+    Debugger::new(&contents, &assembly, sim)
+        .run(std::io::stdin().lock(), std::io::stdout().lock())
+        .unwrap();
+}
 
-            //// end of nzp shenanigans
+/// `trace source.asm [-o trace.jsonl]` runs the kernel in the functional simulator and writes
+/// one JSON record per executed instruction.
+fn trace_main(args: &[String]) {
+    let (path, output_path) = match args {
+        [path] => (path, None),
+        [path, flag, output] if flag == "-o" => (path, Some(output)),
+        _ => {
+            eprintln!("Error: usage: tiny-gpu-assembler trace [source.asm] [-o trace.jsonl]");
+            std::process::exit(1);
+        }
+    };
 
-            // Get the operand (the label or flags part)
-            let req_label = get_operand_from_ind(1); // WARN:
-            dbg!("label is: {}", req_label);
+    let (_, assembly, mut sim) = load_kernel(path);
+    let records = trace(&mut sim, &assembly.operations).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
 
-            if let Some(jump_addr) = label_addresses
-                .iter()
-                .filter_map(|(label, line_num)| {
-                    if *req_label == *label {
-                        Some(*line_num)
-                    } else {
-                        None
-                    }
-                })
-                .next()
-            {
-                let code =
-                    op.as_opcode().to_owned() + &nzp + format!("{:08b}", jump_addr as u16).as_str();
-                Ok(code)
-            } else {
-                Err(LexError::InvalidArgument("Bad Immediate".into()))
-            }
-        }
+    let lines: String = records
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap() + "\n")
+        .collect();
+    match output_path {
+        Some(output_path) => fs::write(output_path, lines).unwrap_or_else(|err| {
+            eprintln!("Error: could not write '{}': {}", output_path, err);
+            std::process::exit(1);
+        }),
+        None => print!("{}", lines),
+    }
+}
 
-        CMP => {
-            // CMP Rs, Rt
-            let rs = Register::from_str(get_operand_from_ind(1))?;
-            let rt = Register::from_str(get_operand_from_ind(2))?;
-            let code = "00100000".to_owned() + rs.bits() + rt.bits();
-            Ok(code)
-        }
+/// `trace-diff source.asm cocotb.log` compares the simulator against a CocoTB run of the same
+/// kernel and reports where they first disagree.
+fn trace_diff_main(args: &[String]) {
+    let [path, log_path] = args else {
+        eprintln!("Error: usage: tiny-gpu-assembler trace-diff [source.asm] [cocotb.log]");
+        std::process::exit(1);
+    };
 
-        ADD | SUB | MUL | DIV => {
-            // ADD Rd, Rs, Rt
-            let rd = Register::from_str(get_operand_from_ind(1))?;
-            let rs = Register::from_str(get_operand_from_ind(2))?;
-            let rt = Register::from_str(get_operand_from_ind(3))?;
-            let code = op.as_opcode().to_owned() + rd.bits() + rs.bits() + rt.bits();
-            Ok(code)
-        }
+    let (contents, assembly, mut sim) = load_kernel(path);
+    let log = fs::read_to_string(log_path).unwrap_or_else(|err| {
+        eprintln!("Error: could not read '{}': {}", log_path, err);
+        std::process::exit(1);
+    });
 
-        Operation::LDR => {
-            let rd = Register::from_str(get_operand_from_ind(1))?;
-            let rs = Register::from_str(get_operand_from_ind(2))?;
-            let code = op.as_opcode().to_owned() + rd.bits() + rs.bits() + "0000";
-            Ok(code)
-        }
+    let expected = simulated_visits(&mut sim).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+    let actual = parse_cocotb_log(&log);
+    if actual.is_empty() {
+        eprintln!("Error: no thread states found in '{}'", log_path);
+        std::process::exit(1);
+    }
 
-        Operation::STR => {
-            let rs = Register::from_str(get_operand_from_ind(1))?;
-            let rt = Register::from_str(get_operand_from_ind(2))?;
-            let code = op.as_opcode().to_owned() + "0000" + rs.bits() + rt.bits();
-            Ok(code)
+    match first_divergence(&expected, &actual) {
+        Some(divergence) => {
+            print!("{}", divergence.report(&contents, &assembly.operations));
+            std::process::exit(1);
         }
-        Operation::CONST => {
-            let rd = Register::from_str(get_operand_from_ind(1))?;
-            let imm8 = get_operand_from_ind(2);
-            let imm8 = imm8.replace("#", "");
-
-            if imm8.parse::<u8>().is_ok() {
-                let code = op.as_opcode().to_owned()
-                    + rd.bits()
-                    + format!("{:08b}", imm8.parse::<u8>().expect("msg")).as_str();
-                Ok(code)
-            } else {
-                Err(LexError::InvalidArgument("Bad Immediate".into()))
-            }
-        }
-        Operation::RET => Ok(op.as_opcode().to_owned() + "000000000000"),
+        None => println!("traces match for all {} threads", expected.len()),
+    }
+}
+
+/// `batch [directory|manifest.toml] -o suite.json` assembles every kernel of a test suite in
+/// parallel into one combined JSON and prints a summary, failing if any kernel failed.
+fn batch_main(args: &[String]) {
+    let [suite_path, flag, output_path] = args else {
+        eprintln!(
+            "Error: usage: tiny-gpu-assembler batch [directory|manifest.toml] -o [suite.json]"
+        );
+        std::process::exit(1);
     };
+    if flag != "-o" {
+        eprintln!("Error: unexpected argument '{}'", flag);
+        std::process::exit(1);
+    }
 
-    //// test assertions that produced binary is accurate
-    assert!(bin.as_ref().expect("msg").len() == 16); //maybe correct?
+    let (hardware, kernels) = load_suite(Path::new(suite_path)).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+    if kernels.is_empty() {
+        eprintln!("Error: no kernels found in '{}'", suite_path);
+        std::process::exit(1);
+    }
 
-    if bin.is_ok() {
-        Ok(MachineLine {
-            line_type: LineType::Operation,
-            bin: bin.ok(),
-            comment: parsed_line.comment.clone(),
-            line_num: parsed_line.line_num,
-            parsed_line,
-        })
-    } else {
-        Err(bin.err().unwrap())
+    let results = assemble_suite(&hardware, &kernels);
+    let suite = Suite {
+        tests: results
+            .iter()
+            .filter_map(|result| result.outcome.as_ref().ok())
+            .collect(),
+    };
+    fs::write(output_path, serde_json::to_string_pretty(&suite).unwrap()).unwrap_or_else(|err| {
+        eprintln!("Error: could not write '{}': {}", output_path, err);
+        std::process::exit(1);
+    });
+
+    print!("{}", summary(&results));
+    if results.iter().any(|result| result.outcome.is_err()) {
+        std::process::exit(1);
     }
 }
 
-fn extract_label_assoc_lines(lexed_lines: &Vec<Box<dyn LexedLine>>) -> Vec<(String, u32)> {
-    // handle Memory and labels (labels must be done prior to operations)
-    let mut labels_lines: Vec<(String, u32)> = vec![]; //maps a Label String to the next line number which is a valid operation
-
-    for line in lexed_lines {
-        if let Some(label_line) = line.as_any().downcast_ref::<LabelLine>() {
-            // handle all label lines
-            if label_line.parsed.tokens.len() != 1 {
-                todo!()
-            } else {
-                let label = label_line
-                    .parsed
-                    .tokens
-                    .first()
-                    .unwrap()
-                    .clone()
-                    .replace(":", "");
-                let num = label_line.parsed.line_num;
-
-                //// go through the parsed lines and find the next operation line
-                let line_number: u32; //a place to store the line number we find in the following loop
-                let mut i = num; //loop variable
-
-                loop {
-                    let check_line = lexed_lines.index(i as usize);
-                    if let Some(a) = check_line.as_any().downcast_ref::<OperationLine>() {
-                        //you found it!
-                        line_number = a.parsed.line_num;
-                        break;
+/// `sweep source.asm -P NAME=V1,V2,... [-P ...] -o directory` assembles one variant of the kernel
+/// per combination of parameter values, writing each to its own JSON named after its testname.
+fn sweep_main(args: &[String]) {
+    let mut source_path: Option<&String> = None;
+    let mut output_dir: Option<&String> = None;
+    let mut parameters: Vec<Parameter> = vec![];
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => output_dir = rest.next(),
+            "-P" => {
+                let parameter = match rest.next() {
+                    Some(parameter) => parse_parameter(parameter),
+                    None => Err(LexError::InvalidArgument(
+                        "-P expects NAME=V1,V2,...".to_string(),
+                    )),
+                };
+                match parameter {
+                    Ok(parameter) if parameters.iter().any(|p| p.name == parameter.name) => {
+                        eprintln!("Error: parameter {} is given twice", parameter.name);
+                        std::process::exit(1);
+                    }
+                    Ok(parameter) => parameters.push(parameter),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        std::process::exit(1);
                     }
-
-                    i += 1;
                 }
-
-                labels_lines.push((label, line_number));
+            }
+            _ if source_path.is_none() => source_path = Some(arg),
+            _ => {
+                eprintln!("Error: unexpected argument '{}'", arg);
+                std::process::exit(1);
             }
         }
     }
 
-    labels_lines
-}
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let input_path = &args[1];
-
-    if "-o" != &args[2] {
+    let (Some(source_path), Some(output_dir)) = (source_path, output_dir) else {
         eprintln!(
-            "Error: expected '-o' as the third argument, got '{}'",
-            &args[2]
+            "Error: usage: tiny-gpu-assembler sweep [source.asm] -P NAME=V1,V2,... -o [directory]"
         );
         std::process::exit(1);
+    };
+    if parameters.is_empty() {
+        eprintln!("Error: expected at least one -P parameter to sweep");
+        std::process::exit(1);
     }
 
-    let output_path = &args[3];
+    let kernels = sweep_kernels(Path::new(source_path), &parameters);
+    let results = assemble_suite(&HardwareOverrides::default(), &kernels);
 
-    let contents = fs::read_to_string(input_path).expect("Should have been able to read the file");
-
-    let lines = contents.lines();
-
-    //start with list of lines (Label/operation/none/error)+comment?
-    //handle all cases with labels/memory
-    //handle all cases with operations
-    //print all lines, exluding those with no comments and no statements
+    fs::create_dir_all(output_dir).unwrap_or_else(|err| {
+        eprintln!("Error: could not create '{}': {}", output_dir, err);
+        std::process::exit(1);
+    });
+    for output in results
+        .iter()
+        .filter_map(|result| result.outcome.as_ref().ok())
+    {
+        let path = Path::new(output_dir).join(format!("{}.json", output.testname));
+        fs::write(&path, serde_json::to_string_pretty(output).unwrap()).unwrap_or_else(|err| {
+            eprintln!("Error: could not write '{}': {}", path.display(), err);
+            std::process::exit(1);
+        });
+    }
 
-    let parsed_lines: Vec<ParsedLine> = lines
-        .enumerate()
-        .filter_map(|(line_num, line)| parse_line(line_num, line))
-        .collect();
-    // dbg!(&parsed_lines);
+    print!("{}", summary(&results));
+    if results.iter().any(|result| result.outcome.is_err()) {
+        std::process::exit(1);
+    }
+}
 
-    let mut lexed_lines: Vec<Box<dyn LexedLine>> = parsed_lines
-        .into_iter()
-        .map(|item| identify_line(item))
-        .collect();
+/// `compile kernel.tgk [-o output.asm]` compiles a kernel language file to assembly, printed
+/// unless an output file is given.
+fn compile_main(args: &[String]) {
+    let (input_path, output_path) = match args {
+        [input] => (input, None),
+        [input, flag, output] if flag == "-o" => (input, Some(output)),
+        _ => {
+            eprintln!("Error: usage: tiny-gpu-assembler compile [kernel.tgk] [-o output.asm]");
+            std::process::exit(1);
+        }
+    };
 
-    // dbg!(&lexed_lines);
+    let assembly = compile_kernel(input_path);
+    match output_path {
+        Some(path) => fs::write(path, assembly).unwrap_or_else(|err| {
+            eprintln!("Error: could not write '{}': {}", path, err);
+            std::process::exit(1);
+        }),
+        None => print!("{}", assembly),
+    }
+}
 
-    let label_lines = extract_label_assoc_lines(&lexed_lines);
+/// Reads and compiles a kernel language file to assembly, exiting on any error.
+fn compile_kernel(path: &str) -> String {
+    let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Error: could not read '{}': {}", path, err);
+        std::process::exit(1);
+    });
+    compile(&contents).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    })
+}
 
-    //// now that the label list is generated, it's possible to statically point branch/jump instructs
-    // number the instructions based on the actual address (is there a better method? almost certainly!)
-    let mut i: u16 = 0;
-    for line in &mut lexed_lines {
-        if let Some(operation_line) = line.as_any_mut().downcast_mut::<OperationLine>() {
-            operation_line.instruct_num = Some(i);
-            i += 1;
+/// `link a.json b.json ... -o output.json [--name TEST]` links relocatable objects, the first
+/// of which holds the entry point at address 0, into one test.
+fn link_main(args: &[String]) {
+    let mut object_paths: Vec<&String> = vec![];
+    let mut output_path: Option<&String> = None;
+    let mut testname: Option<&String> = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => output_path = rest.next(),
+            "--name" => testname = rest.next(),
+            _ => object_paths.push(arg),
         }
     }
 
-    //for u32 in label lines, do lexed_lines.get(u32) convert to operation line fmt and take its instruct number)
-
-    let label_addresses: Vec<(String, u16)> = label_lines
-        .into_iter()
-        .map(|(a, b)| {
-            (
-                a,
-                lexed_lines
-                    .get(b as usize)
-                    .unwrap()
-                    .as_any()
-                    .downcast_ref::<OperationLine>()
-                    .unwrap()
-                    .instruct_num
-                    .unwrap(),
-            )
+    let Some(output_path) = output_path.filter(|_| !object_paths.is_empty()) else {
+        eprintln!(
+            "Error: usage: tiny-gpu-assembler link [object.json]... -o [output.json] [--name TEST]"
+        );
+        std::process::exit(1);
+    };
+
+    let objects: Vec<Object> = object_paths
+        .iter()
+        .map(|path| {
+            let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Error: could not read '{}': {}", path, err);
+                std::process::exit(1);
+            });
+            serde_json::from_str(&contents).unwrap_or_else(|err| {
+                eprintln!("Error: invalid object '{}': {}", path, err);
+                std::process::exit(1);
+            })
         })
-        .collect(); //todo!() complete conversion into address num (baked into struct)
+        .collect();
+
+    let testname = testname.unwrap_or_else(|| &objects[0].name);
+    let output = link(testname, &objects).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+    fs::write(output_path, serde_json::to_string_pretty(&output).unwrap()).unwrap_or_else(|err| {
+        eprintln!("Error: could not write '{}': {}", output_path, err);
+        std::process::exit(1);
+    });
+}
+
+/// Reports a problem with the file at `path` as a whole, as a diagnostic in json mode, and exits
+fn file_error(json: bool, path: &str, code: &'static str, message: String) -> ! {
+    match json {
+        true => println!(
+            "{}",
+            serde_json::to_string(&file_diagnostic(path, code, message)).unwrap()
+        ),
+        false => eprintln!("Error: {}", message),
+    }
+    std::process::exit(1);
+}
 
-    // dbg!(&label_addresses);
+fn main() {
+    let args: Vec<String> = env::args().collect();
 
-    let mut operations = Vec::new();
-    let mut memories = Vec::new();
+    if args.get(1).map(|arg| arg.as_str()) == Some("format") {
+        format_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("compile") {
+        compile_main(&args[2..]);
+        return;
+    }
 
-    for line in lexed_lines {
-        match operation_conv(line, label_addresses.clone()) {
-            Ok(line) => match line.line_type {
-                LineType::Operation => operations.push(line),
-                LineType::Memory => memories.push(line),
-                _ => {}
+    if args.get(1).map(|arg| arg.as_str()) == Some("debug") {
+        debug_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("batch") {
+        batch_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("sweep") {
+        sweep_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("link") {
+        link_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("trace") {
+        trace_main(&args[2..]);
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_str()) == Some("trace-diff") {
+        trace_diff_main(&args[2..]);
+        return;
+    }
+
+    // source.asm -o output.json, with lint level flags anywhere
+    let mut input_path: Option<&String> = None;
+    let mut output_path: Option<&String> = None;
+    let mut lint_config = LintConfig::default();
+    let mut report = false;
+    let mut map = false;
+    let mut optimize_program = false;
+    let mut schedule_program = false;
+    let mut object = false;
+    let mut defines = vec![];
+    let mut json = false;
+
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => output_path = rest.next(),
+            "--report" => report = true,
+            "--memory-map" => map = true,
+            "-O" | "--optimize" => optimize_program = true,
+            "--schedule" => schedule_program = true,
+            "--object" => object = true,
+            "--message-format" => match rest.next().map(|format| format.as_str()) {
+                Some("human") => json = false,
+                Some("json") => json = true,
+                _ => {
+                    eprintln!("Error: --message-format expects human or json");
+                    std::process::exit(1);
+                }
             },
-            Err(err) => {
-                eprintln!("Error assembling line: {:?}", err);
+            "-D" => {
+                let define = match rest.next() {
+                    Some(define) => parse_define(define),
+                    None => Err(LexError::InvalidArgument(
+                        "-D expects NAME=VALUE".to_string(),
+                    )),
+                };
+                match define {
+                    Ok(define) => defines.push(define),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--allow" | "--warn" | "--deny" => {
+                let level = match arg.as_str() {
+                    "--allow" => LintLevel::Allow,
+                    "--warn" => LintLevel::Warn,
+                    _ => LintLevel::Deny,
+                };
+                let set = match rest.next() {
+                    Some(name) => lint_config.set(name, level),
+                    None => Err(LexError::InvalidArgument(format!(
+                        "{arg} expects a lint name"
+                    ))),
+                };
+                if let Err(err) = set {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            _ if input_path.is_none() => input_path = Some(arg),
+            _ => {
+                eprintln!("Error: unexpected argument '{}'", arg);
                 std::process::exit(1);
             }
         }
     }
 
-    dbg!(&memories);
-    dbg!(&operations);
-
-    // Extract threads count (from .threads directive)
-    let threads = memories
-        .iter()
-        .find(|m| m.parsed_line.tokens.first().unwrap() == ".threads")
-        .and_then(|m| m.parsed_line.tokens.get(1))
-        .and_then(|s| s.parse::<u32>().ok())
-        .unwrap_or(1);
-
-    // Extract .data values as a vector of u8
+    let (Some(input_path), Some(output_path)) = (input_path, output_path) else {
+        eprintln!("Error: usage: tiny-gpu-assembler [source.asm] -o [output.json] [-D NAME=VALUE]... [-O] [--schedule] [--object] [--allow|--warn|--deny LINT]... [--report] [--memory-map] [--message-format human|json]");
+        std::process::exit(1);
+    };
 
-    let initial_data: Vec<u8> = memories
-        .iter()
-        .filter(|m| m.parsed_line.tokens.first().map(|t| t.as_str()) == Some(".data"))
-        .flat_map(|m| {
-            m.parsed_line
-                .tokens
-                .iter()
-                .skip(1) // skip ".data"
-                .map(|tok| tok.parse::<u8>().expect("Invalid u8 in .data"))
+    // diagnostics go to stderr for people, or to stdout as one JSON object per line for tools
+    let source = fs::read_to_string(input_path).unwrap_or_else(|err| {
+        let message = format!("could not read '{}': {}", input_path, err);
+        file_error(json, input_path, "unreadable-file", message)
+    });
+
+    // kernel language files are compiled to assembly first
+    let contents = if input_path.ends_with(".tgk") {
+        compile(&source).unwrap_or_else(|err| {
+            match json {
+                true => {
+                    let diagnostic = error_diagnostic(input_path, &source, None, &err);
+                    println!("{}", serde_json::to_string(&diagnostic).unwrap())
+                }
+                false => eprintln!("Error: {}", err),
+            }
+            std::process::exit(1);
         })
-        .collect();
+    } else {
+        source
+    };
 
-    // Convert operations to hex strings
-    let program_memory: Vec<String> = operations
-        .into_iter()
-        .map(|m| {
-            let bin = m.bin.unwrap();
-            let value = u16::from_str_radix(&bin, 2).unwrap();
-            format!("0x{:04x}", value)
-        })
-        .collect();
+    // objects leave the symbols they do not define for the linker
+    let mut assembly = assemble_with(
+        &contents,
+        &AssembleOptions {
+            relocatable: object,
+            defines,
+        },
+    );
+
+    let report_error = |assembly: &Assembly, err: &LineError| match json {
+        true => {
+            let diagnostic = error_diagnostic(input_path, &contents, Some(assembly), err);
+            println!("{}", serde_json::to_string(&diagnostic).unwrap())
+        }
+        false => eprintln!("Error: {}", err),
+    };
+
+    if !assembly.errors.is_empty() {
+        for err in &assembly.errors {
+            report_error(&assembly, err);
+        }
+        std::process::exit(1);
+    }
 
-    //dbg!(&initial_data);
+    if optimize_program {
+        for rewrite in optimize(&mut assembly) {
+            eprintln!("optimized {}", rewrite);
+        }
+    }
 
-    #[derive(Serialize)]
-    struct Hardware {
-        program_addr_bits: u32,
-        program_data_bits: u32,
-        program_channels: u32,
-        data_addr_bits: u32,
-        data_data_bits: u32,
-        data_channels: u32,
+    if schedule_program {
+        let model = LatencyModel::new(DEFAULT_MEMORY_DELAY, &Hardware::default());
+        for block in schedule(&mut assembly, &model) {
+            eprintln!("scheduled {}", block);
+        }
     }
 
-    #[derive(Serialize)]
-    struct Output {
-        testname: String,
-        memory_delay: u32,
-        threads: u32,
-        hardware: Hardware,
-        program_memory: Vec<String>,
-        initial_data: Vec<u8>,
+    let diagnostics = lint_program(&assembly.source_lines, &assembly.operations, &lint_config);
+    for diagnostic in &diagnostics {
+        match json {
+            true => println!(
+                "{}",
+                serde_json::to_string(&lint_diagnostic(input_path, &contents, diagnostic)).unwrap()
+            ),
+            false => eprintln!("{}", diagnostic),
+        }
+    }
+    if diagnostics.iter().any(|d| d.level == LintLevel::Deny) {
+        std::process::exit(1);
     }
 
     // Build output
@@ -434,23 +565,39 @@ fn main() {
         .unwrap()
         .to_string();
 
-    let hardware = Hardware {
-        program_addr_bits: 8,
-        program_data_bits: 16,
-        program_channels: 1,
-        data_addr_bits: 8,
-        data_data_bits: 8,
-        data_channels: 4,
-    };
-    let output = Output {
-        testname,
-        memory_delay: 1, // makes for faster tests
-        threads,
-        hardware,
-        program_memory,
-        initial_data,
+    if object {
+        let object = build_object(&testname, &assembly).unwrap_or_else(|err| {
+            report_error(&assembly, &err);
+            std::process::exit(1);
+        });
+        let object = serde_json::to_string_pretty(&object).unwrap();
+        fs::write(output_path, object).unwrap_or_else(|err| {
+            let message = format!("could not write '{}': {}", output_path, err);
+            file_error(json, output_path, "unwritable-file", message)
+        });
+        return;
+    }
+
+    let output = build_output(&testname, &assembly).unwrap_or_else(|err| {
+        report_error(&assembly, &err);
+        std::process::exit(1);
+    });
+
+    // stdout is left to the diagnostics in json mode
+    let print_text = |text: String| match json {
+        true => eprint!("{}", text),
+        false => print!("{}", text),
     };
+    if report {
+        print_text(resource_report(&assembly, &output.hardware).to_string());
+    }
+
+    if map {
+        print_text(memory_map(&assembly, &output.launch).to_string());
+    }
 
-    // Print JSON to stdout
-    std::fs::write(output_path, serde_json::to_string_pretty(&output).unwrap()).unwrap();
+    fs::write(output_path, serde_json::to_string_pretty(&output).unwrap()).unwrap_or_else(|err| {
+        let message = format!("could not write '{}': {}", output_path, err);
+        file_error(json, output_path, "unwritable-file", message)
+    });
 }
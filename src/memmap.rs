@@ -0,0 +1,291 @@
+use std::fmt;
+
+use crate::analysis::{register_ranges, ValueRange};
+use crate::assembler::Assembly;
+use crate::instruction::Instruction;
+use crate::output::Launch;
+use crate::simulator::{Simulator, DATA_MEMORY_SIZE};
+
+/// Memory Map
+/// ---
+/// Where a kernel's data lives: the address range, label and size of each `.data` block, the
+/// ranges of addresses each STR can write given the launch configuration, and hex dumps of data
+/// memory before the kernel runs and after it has run in the functional simulator.
+///
+/// Write ranges come from range analysis of the STR address registers, with `%blockIdx`,
+/// `%blockDim` and `%threadIdx` bounded by the launch. A STR whose address depends on a loaded
+/// value or on a loop counter can write anywhere.
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    pub blocks: Vec<DataBlock>,
+    pub writes: Vec<WriteRange>,
+    pub initial: Vec<u8>,
+    pub simulated: Result<Vec<u8>, String>, // data memory after the run, or why it failed
+}
+
+/// The bytes of one `.data` directive
+#[derive(Debug, Clone)]
+pub struct DataBlock {
+    pub start: usize,
+    pub size: usize,
+    pub labels: Vec<String>,
+    pub line: u32, // 1-based source line
+}
+
+/// The addresses one STR may write
+#[derive(Debug, Clone)]
+pub struct WriteRange {
+    pub range: ValueRange,
+    pub address: usize, // of the STR
+    pub line: u32,      // 1-based source line
+}
+
+pub fn memory_map(assembly: &Assembly, launch: &Launch) -> MemoryMap {
+    let mut blocks = vec![];
+    let mut start = 0;
+    for line in &assembly.memories {
+        let tokens = &line.parsed_line.tokens;
+        if tokens[0] != ".data" {
+            continue;
+        }
+        let size = tokens.len() - 1;
+        if size == 0 {
+            continue;
+        }
+        blocks.push(DataBlock {
+            start,
+            size,
+            labels: assembly
+                .data_labels
+                .iter()
+                .filter(|(_, address)| *address as usize == start)
+                .map(|(label, _)| label.clone())
+                .collect(),
+            line: line.line_num + 1,
+        });
+        start += size;
+    }
+
+    // one instruction per operation, so an address indexes both
+    let program: Vec<Instruction> = assembly
+        .operations
+        .iter()
+        .map(|line| {
+            line.bin
+                .as_deref()
+                .and_then(Instruction::from_bin)
+                .expect("every operation of an assembled program decodes")
+        })
+        .collect();
+    let ranges = register_ranges(&program, launch);
+    let writes = program
+        .iter()
+        .enumerate()
+        .filter_map(|(address, instruction)| match *instruction {
+            Instruction::Str { rs, .. } => ranges[address].map(|state| WriteRange {
+                range: state[rs.index()],
+                address,
+                line: assembly.operations[address].line_num + 1,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    let initial = assembly.initial_data().unwrap_or_default();
+    let mut sim = Simulator::new(assembly.program(), &initial, *launch);
+    let simulated = sim
+        .run()
+        .map(|()| sim.memory.clone())
+        .map_err(|err| err.to_string());
+
+    MemoryMap {
+        blocks,
+        writes,
+        initial,
+        simulated,
+    }
+}
+
+/// 16 bytes per row as hex and ASCII, rows of zeros past the last nonzero byte left out
+fn hex_dump(f: &mut fmt::Formatter<'_>, memory: &[u8]) -> fmt::Result {
+    let used = memory
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    writeln!(
+        f,
+        "       {}",
+        (0..16)
+            .map(|column| format!("{column:2x}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    )?;
+    for (row, bytes) in memory[..used].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                true => byte as char,
+                false => '.',
+            })
+            .collect();
+        writeln!(f, "  0x{:02x} {:47}  |{}|", row * 16, hex.join(" "), ascii)?;
+    }
+    if used < DATA_MEMORY_SIZE {
+        writeln!(f, "  (zero from 0x{used:02x})")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "data blocks:")?;
+        if self.blocks.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for block in &self.blocks {
+            writeln!(
+                f,
+                "  {:>3}-{:<3}  {:3} bytes  {:12}  line {}",
+                block.start,
+                block.start + block.size - 1,
+                block.size,
+                block.labels.join(" "),
+                block.line
+            )?;
+        }
+
+        writeln!(f, "writes:")?;
+        if self.writes.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for write in &self.writes {
+            let (lo, hi) = write.range;
+            let range = match (lo, hi) {
+                (0, u8::MAX) => "anywhere".to_string(),
+                _ => format!("{lo}-{hi}"),
+            };
+            // the data blocks a write lands in
+            let overlaps: Vec<String> = self
+                .blocks
+                .iter()
+                .filter(|block| {
+                    block.start <= hi as usize && (lo as usize) < block.start + block.size
+                })
+                .map(|block| match block.labels.first() {
+                    Some(label) => label.clone(),
+                    None => format!("line {}", block.line),
+                })
+                .collect();
+            let overlaps = match overlaps.is_empty() || (lo, hi) == (0, u8::MAX) {
+                true => String::new(),
+                false => format!(", over {}", overlaps.join(", ")),
+            };
+            writeln!(
+                f,
+                "  {:>9}  STR at address {} (line {}){}",
+                range, write.address, write.line, overlaps
+            )?;
+        }
+
+        writeln!(f, "initial data memory:")?;
+        hex_dump(f, &self.initial)?;
+        match &self.simulated {
+            Ok(memory) => {
+                writeln!(f, "final data memory (simulated):")?;
+                hex_dump(f, memory)
+            }
+            Err(err) => writeln!(f, "final data memory: simulation failed: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::output::Hardware;
+
+    const KERNEL: &str = "\
+.threads 8
+A: .data 1 2 3 4
+B: .data 0x41 0x42
+MUL R0, %blockIdx, %blockDim
+ADD R0, R0, %threadIdx
+CONST R1, #8
+ADD R2, R1, R0
+STR R2, R0
+LDR R3, R0
+STR R3, R1
+RET
+";
+
+    fn map(source: &str) -> MemoryMap {
+        let assembly = assemble(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        memory_map(&assembly, &assembly.launch(&Hardware::default()).unwrap())
+    }
+
+    #[test]
+    fn lists_data_blocks_and_write_ranges() {
+        let map = map(KERNEL);
+        let blocks: Vec<(usize, usize, &[String], u32)> = map
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.size, &b.labels[..], b.line))
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0, 4, &["A".to_string()][..], 2),
+                (4, 2, &["B".to_string()][..], 3)
+            ]
+        );
+        let writes: Vec<(ValueRange, usize, u32)> = map
+            .writes
+            .iter()
+            .map(|w| (w.range, w.address, w.line))
+            .collect();
+        // R2 = 8 + the global thread index, R3 was loaded from memory
+        assert_eq!(writes, [((8, 15), 4, 8), ((0, 255), 6, 10)]);
+        assert_eq!(map.initial, [1, 2, 3, 4, 0x41, 0x42]);
+        assert_eq!(
+            map.simulated.as_ref().unwrap()[8..16],
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn prints_blocks_writes_and_dumps() {
+        let text = map(KERNEL).to_string();
+        for expected in [
+            "    0-3      4 bytes  A             line 2\n",
+            "       8-15  STR at address 4 (line 8)\n",
+            "   anywhere  STR at address 6 (line 10)\n",
+            "  0x00 01 02 03 04 41 42",
+            "|....AB|\n  (zero from 0x06)",
+        ] {
+            assert!(text.contains(expected), "{expected}\n{text}");
+        }
+    }
+
+    #[test]
+    fn writes_over_data_name_the_block() {
+        let text =
+            map(".threads 4\nA: .data 1 2 3 4\nSTR %threadIdx, %threadIdx\nRET\n").to_string();
+        assert!(
+            text.contains("0-3  STR at address 0 (line 3), over A"),
+            "{text}"
+        );
+
+        let text = map(".threads 1\nDIV R0, R0, R0\nRET\n").to_string();
+        assert!(
+            text.contains("data blocks:\n  none\nwrites:\n  none\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("simulation failed: thread 0: division by zero"),
+            "{text}"
+        );
+    }
+}
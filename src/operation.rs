@@ -1,6 +1,6 @@
 use std::fmt;
 use std::str::FromStr;
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Operation {
     NOP,   // No operation
     BRnzp, // Branch if the condition codes are non-zero (in ARM-like assembly)
@@ -62,7 +62,7 @@ pub fn remove_trailing_br_flags(s: &str) -> &str {
     // Check if the string starts with "BR"
     if s.starts_with("BR") {
         // Remove trailing 'n', 'z', or 'p'
-        let trimmed = s.trim_end_matches(|c| c == 'n' || c == 'z' || c == 'p');
+        let trimmed = s.trim_end_matches(['n', 'z', 'p']);
         return trimmed;
     }
     s // Return the string as is if it doesn't start with "BR"
@@ -102,6 +102,69 @@ impl Operation {
         }
     }
 
+    // Bit layout of the instruction word (d = Rd, s = Rs, t = Rt, i = immediate, nzp = branch flags)
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Operation::NOP => "0000 xxxx xxxx xxxx",
+            Operation::BRnzp => "0001 nzpx iiii iiii",
+            Operation::CMP => "0010 xxxx ssss tttt",
+            Operation::ADD => "0011 dddd ssss tttt",
+            Operation::SUB => "0100 dddd ssss tttt",
+            Operation::MUL => "0101 dddd ssss tttt",
+            Operation::DIV => "0110 dddd ssss tttt",
+            Operation::LDR => "0111 dddd ssss xxxx",
+            Operation::STR => "1000 xxxx ssss tttt",
+            Operation::CONST => "1001 dddd iiii iiii",
+            Operation::RET => "1111 xxxx xxxx xxxx",
+        }
+    }
+
+    pub fn syntax(&self) -> &'static str {
+        match self {
+            Operation::NOP => "NOP",
+            Operation::BRnzp => "BRnzp LABEL",
+            Operation::CMP => "CMP Rs, Rt",
+            Operation::ADD => "ADD Rd, Rs, Rt",
+            Operation::SUB => "SUB Rd, Rs, Rt",
+            Operation::MUL => "MUL Rd, Rs, Rt",
+            Operation::DIV => "DIV Rd, Rs, Rt",
+            Operation::LDR => "LDR Rd, Rs",
+            Operation::STR => "STR Rs, Rt",
+            Operation::CONST => "CONST Rd, #imm8",
+            Operation::RET => "RET",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Operation::NOP => "Does nothing for one instruction.",
+            Operation::BRnzp => "Branches to LABEL if any of the given n/z/p flags is set by the last CMP.",
+            Operation::CMP => "Compares Rs with Rt and sets the NZP flags to Rs - Rt being negative, zero or positive.",
+            Operation::ADD => "Rd = Rs + Rt",
+            Operation::SUB => "Rd = Rs - Rt",
+            Operation::MUL => "Rd = Rs * Rt",
+            Operation::DIV => "Rd = Rs / Rt",
+            Operation::LDR => "Loads data memory at the address in Rs into Rd.",
+            Operation::STR => "Stores Rt to data memory at the address in Rs.",
+            Operation::CONST => "Loads the 8-bit immediate into Rd.",
+            Operation::RET => "Ends the thread.",
+        }
+    }
+
+    pub const ALL: [Operation; 11] = [
+        Operation::NOP,
+        Operation::BRnzp,
+        Operation::CMP,
+        Operation::ADD,
+        Operation::SUB,
+        Operation::MUL,
+        Operation::DIV,
+        Operation::LDR,
+        Operation::STR,
+        Operation::CONST,
+        Operation::RET,
+    ];
+
     pub fn num_args(&self) -> u8 {
         match self {
             Operation::NOP => 0,
@@ -0,0 +1,381 @@
+use std::fmt;
+
+use crate::analysis::{constant_registers_with, live_registers, reachable, transfer, RegValue};
+use crate::assembler::{symbol_reference, Assembly};
+use crate::instruction::Instruction;
+use crate::{LineType, MachineLine, Register};
+
+/// Peephole Optimizer
+/// ---
+/// An opt-in pass over the assembled instructions that repeats these rewrites until none apply:
+///
+/// - constant folding: an ALU instruction whose operands are known becomes a CONST, and a CONST
+///   (or folded instruction) that loads the value the register already holds is removed
+/// - algebraic simplification: adding or subtracting 0, or multiplying or dividing by 1, in place
+/// - redundant computation: an instruction recomputing a value its destination still holds
+///   within a basic block
+/// - dead code: unreachable instructions, and writes to registers that are never read
+///
+/// Removed instructions shift the ones after them down, and every branch target and label is
+/// moved with them, so a branch to a removed instruction lands on the next one kept.
+///
+/// A CONST of a label loads an address the linker may still move, so its value counts as unknown
+/// and the CONST is never folded away. A program that branches to an imported symbol is left as
+/// it is, since where that branch lands is only known once linked.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pub line_num: u32,
+    pub before: Instruction,
+    pub after: Option<Instruction>,
+    pub reason: String,
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.after {
+            Some(after) => write!(
+                f,
+                "line {}: `{}` -> `{}`: {}",
+                self.line_num + 1,
+                self.before,
+                after,
+                self.reason
+            ),
+            None => write!(
+                f,
+                "line {}: removed `{}`: {}",
+                self.line_num + 1,
+                self.before,
+                self.reason
+            ),
+        }
+    }
+}
+
+enum Edit {
+    Remove(String),
+    Replace(Instruction, String),
+}
+
+type Pass = fn(&[Instruction], &[bool]) -> Vec<Option<Edit>>;
+
+/// Optimizes the operations of `assembly` in place and returns every rewrite made.
+pub fn optimize(assembly: &mut Assembly) -> Vec<Rewrite> {
+    let Some(mut program) = assembly
+        .operations
+        .iter()
+        .map(|line| line.bin.as_deref().and_then(Instruction::from_bin))
+        .collect::<Option<Vec<Instruction>>>()
+    else {
+        return vec![];
+    };
+
+    let symbols: Vec<Option<&str>> = assembly
+        .operations
+        .iter()
+        .map(|line| symbol_reference(&line.parsed_line.tokens))
+        .collect();
+    let imported_branch = program.iter().zip(&symbols).any(|(instruction, symbol)| {
+        matches!(instruction, Instruction::Branch { .. })
+            && symbol.is_some_and(|symbol| assembly.imports.iter().any(|name| name == symbol))
+    });
+    if imported_branch {
+        return vec![];
+    }
+    // symbol references by operation, since rewriting and removing instructions moves them
+    let symbolic: Vec<bool> = symbols.iter().map(Option::is_some).collect();
+
+    // the operation each instruction came from, and whether it was rewritten
+    let mut origin: Vec<(usize, bool)> = (0..program.len()).map(|i| (i, false)).collect();
+    let mut rewrites = vec![];
+
+    let passes: [Pass; 4] = [
+        fold_constants,
+        remove_recomputation,
+        remove_unreachable,
+        remove_dead_writes,
+    ];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pass in passes {
+            let opaque: Vec<bool> = origin.iter().map(|&(op, _)| symbolic[op]).collect();
+            let edits = pass(&program, &opaque);
+            if edits.iter().all(Option::is_none) {
+                continue;
+            }
+            changed = true;
+
+            let mut kept = Vec::with_capacity(program.len());
+            let mut kept_origin = Vec::with_capacity(program.len());
+            // new address of every old address, and of the end of the program
+            let mut new_addr = Vec::with_capacity(program.len() + 1);
+
+            for ((instruction, (op, rewritten)), edit) in program.iter().zip(&origin).zip(edits) {
+                new_addr.push(kept.len());
+                let line_num = assembly.operations[*op].line_num;
+                match edit {
+                    None => {
+                        kept.push(*instruction);
+                        kept_origin.push((*op, *rewritten));
+                    }
+                    Some(Edit::Replace(after, reason)) => {
+                        rewrites.push(Rewrite {
+                            line_num,
+                            before: *instruction,
+                            after: Some(after),
+                            reason,
+                        });
+                        kept.push(after);
+                        kept_origin.push((*op, true));
+                    }
+                    Some(Edit::Remove(reason)) => rewrites.push(Rewrite {
+                        line_num,
+                        before: *instruction,
+                        after: None,
+                        reason,
+                    }),
+                }
+            }
+            new_addr.push(kept.len());
+
+            let remap = |addr: usize| new_addr.get(addr).copied().unwrap_or(addr);
+            for instruction in &mut kept {
+                if let Instruction::Branch { target, .. } = instruction {
+                    *target = remap(*target as usize) as u8;
+                }
+            }
+            for (_, addr) in &mut assembly.label_addresses {
+                *addr = remap(*addr as usize) as u16;
+            }
+
+            program = kept;
+            origin = kept_origin;
+        }
+    }
+
+    let mut operations: Vec<Option<MachineLine>> = std::mem::take(&mut assembly.operations)
+        .into_iter()
+        .map(Some)
+        .collect();
+    assembly.operations = program
+        .iter()
+        .zip(origin)
+        .map(|(instruction, (op, rewritten))| {
+            // every kept instruction has a distinct origin
+            let mut line = operations[op].take().unwrap();
+            if rewritten {
+                line.parsed_line.tokens = instruction
+                    .to_string()
+                    .split([' ', ','])
+                    .filter(|token| !token.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            line.line_type = LineType::Operation;
+            line.bin = Some(format!("{:016b}", instruction.encode()));
+            line
+        })
+        .collect();
+
+    rewrites.sort_by_key(|rewrite| rewrite.line_num);
+    rewrites
+}
+
+fn fold_constants(program: &[Instruction], opaque: &[bool]) -> Vec<Option<Edit>> {
+    let states = constant_registers_with(program, |addr| opaque[addr]);
+
+    program
+        .iter()
+        .zip(states)
+        .zip(opaque)
+        .map(|((instruction, state), &opaque)| {
+            let state = state.filter(|_| !opaque)?;
+            let rd = instruction.dest().filter(|rd| !rd.is_special())?;
+            if let Instruction::Ldr { .. } = instruction {
+                return None;
+            }
+            let known = |r: Register| state[r.index()].as_const();
+
+            if let Some(value) = transfer(instruction, &state)[rd.index()].as_const() {
+                return if state[rd.index()] == RegValue::Const(value) {
+                    Some(Edit::Remove(format!(
+                        "{} already holds {}",
+                        rd.name(),
+                        value
+                    )))
+                } else if let Instruction::Const { .. } = instruction {
+                    None
+                } else {
+                    Some(Edit::Replace(
+                        Instruction::Const { rd, imm: value },
+                        "operands are constant".into(),
+                    ))
+                };
+            }
+
+            let identity = match *instruction {
+                Instruction::Add { rd, rs, rt }
+                    if (rd == rs && known(rt) == Some(0)) || (rd == rt && known(rs) == Some(0)) =>
+                {
+                    Some("adds 0")
+                }
+                Instruction::Sub { rd, rs, rt } if rd == rs && known(rt) == Some(0) => {
+                    Some("subtracts 0")
+                }
+                Instruction::Mul { rd, rs, rt }
+                    if (rd == rs && known(rt) == Some(1)) || (rd == rt && known(rs) == Some(1)) =>
+                {
+                    Some("multiplies by 1")
+                }
+                Instruction::Div { rd, rs, rt } if rd == rs && known(rt) == Some(1) => {
+                    Some("divides by 1")
+                }
+                _ => None,
+            };
+            identity.map(|reason| Edit::Remove(reason.into()))
+        })
+        .collect()
+}
+
+/// Removes instructions whose destination still holds what they compute, within a basic block
+fn remove_recomputation(program: &[Instruction], opaque: &[bool]) -> Vec<Option<Edit>> {
+    let mut block_start = vec![false; program.len()];
+    for (addr, instruction) in program.iter().enumerate() {
+        if let Instruction::Branch { target, .. } = *instruction {
+            for start in [target as usize, addr + 1] {
+                if let Some(flag) = block_start.get_mut(start) {
+                    *flag = true;
+                }
+            }
+        }
+    }
+
+    // pure instructions whose destination still holds their result
+    let mut available: Vec<Instruction> = vec![];
+    program
+        .iter()
+        .enumerate()
+        .map(|(addr, instruction)| {
+            if block_start[addr] {
+                available.clear();
+            }
+            if !opaque[addr] && available.contains(instruction) {
+                let rd = instruction.dest().unwrap();
+                return Some(Edit::Remove(format!(
+                    "{} already holds this value",
+                    rd.name()
+                )));
+            }
+
+            if let Some(written) = instruction.dest() {
+                available.retain(|earlier| {
+                    earlier.dest() != Some(written) && !earlier.sources().contains(&written)
+                });
+                let pure = !matches!(instruction, Instruction::Ldr { .. }) && !opaque[addr];
+                if pure && !written.is_special() && !instruction.sources().contains(&written) {
+                    available.push(*instruction);
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+fn remove_unreachable(program: &[Instruction], _: &[bool]) -> Vec<Option<Edit>> {
+    reachable(program)
+        .into_iter()
+        .map(|seen| (!seen).then(|| Edit::Remove("unreachable".into())))
+        .collect()
+}
+
+fn remove_dead_writes(program: &[Instruction], _: &[bool]) -> Vec<Option<Edit>> {
+    let live = live_registers(program);
+
+    program
+        .iter()
+        .zip(live)
+        .map(|(instruction, live_out)| {
+            let rd = instruction.dest().filter(|rd| !rd.is_special())?;
+            (live_out & (1 << rd.index()) == 0)
+                .then(|| Edit::Remove(format!("{} is never read", rd.name())))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn optimized(source: &str) -> (Vec<Instruction>, Vec<Rewrite>) {
+        let mut assembly = assemble(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let rewrites = optimize(&mut assembly);
+        let program = assembly
+            .program()
+            .into_iter()
+            .filter_map(Instruction::decode)
+            .collect();
+        (program, rewrites)
+    }
+
+    #[test]
+    fn brnzp_before_any_cmp_falls_through() {
+        // NZP is 0 until a CMP runs, so the STR executes
+        let source = "CONST R1, #5\nBRnzp END\nSTR R1, R1\nEND:\nRET\n";
+        let (program, rewrites) = optimized(source);
+        assert!(rewrites.is_empty(), "{rewrites:?}");
+        assert!(program.contains(&Instruction::Str {
+            rs: Register::R1,
+            rt: Register::R1
+        }));
+    }
+
+    #[test]
+    fn brnzp_after_a_cmp_always_branches() {
+        let source = "CONST R1, #5\nCMP R1, R1\nBRnzp END\nSTR R1, R1\nEND:\nRET\n";
+        let (program, rewrites) = optimized(source);
+        assert_eq!(rewrites.len(), 1);
+        assert_eq!(rewrites[0].reason, "unreachable");
+        assert_eq!(program.len(), 4);
+        assert_eq!(
+            program[2],
+            Instruction::Branch {
+                nzp: 0b111,
+                target: 3
+            }
+        );
+    }
+
+    #[test]
+    fn folds_constants_and_removes_dead_writes() {
+        let source = "CONST R1, #2\nCONST R2, #3\nADD R3, R1, R2\nSTR R3, R3\nRET\n";
+        let (program, _) = optimized(source);
+        assert_eq!(
+            program,
+            [
+                Instruction::Const {
+                    rd: Register::R3,
+                    imm: 5
+                },
+                Instruction::Str {
+                    rs: Register::R3,
+                    rt: Register::R3
+                },
+                Instruction::Ret,
+            ]
+        );
+    }
+
+    #[test]
+    fn programs_branching_to_imports_are_left_alone() {
+        // the imported branch is encoded with target 0, which would make the STR unreachable
+        let mut assembly = crate::assembler::assemble_relocatable(
+            "CONST R1, #5\nCMP R1, R1\nBRnzp LIB\nSTR R1, R1\nRET\n",
+        );
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        assert!(optimize(&mut assembly).is_empty());
+        assert_eq!(assembly.operations.len(), 5);
+    }
+}
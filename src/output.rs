@@ -0,0 +1,148 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::Assembly;
+use crate::simulator::{CORES, THREADS_PER_BLOCK};
+use crate::LineError;
+
+/// Test Output
+/// ---
+/// The JSON the TinyGPU CocoTB tests load: the assembled program, initial data and the hardware
+/// parameters to build the GPU with.
+#[derive(Debug, Clone, Serialize)]
+pub struct Hardware {
+    pub program_addr_bits: u32,
+    pub program_data_bits: u32,
+    pub program_channels: u32,
+    pub data_addr_bits: u32,
+    pub data_data_bits: u32,
+    pub data_channels: u32,
+}
+
+impl Default for Hardware {
+    fn default() -> Self {
+        Hardware {
+            program_addr_bits: 8,
+            program_data_bits: 16,
+            program_channels: 1,
+            data_addr_bits: 8,
+            data_data_bits: 8,
+            data_channels: 4,
+        }
+    }
+}
+
+/// Hardware parameters to change from the defaults, any left out keep their value
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HardwareOverrides {
+    pub program_addr_bits: Option<u32>,
+    pub program_data_bits: Option<u32>,
+    pub program_channels: Option<u32>,
+    pub data_addr_bits: Option<u32>,
+    pub data_data_bits: Option<u32>,
+    pub data_channels: Option<u32>,
+}
+
+impl Hardware {
+    pub fn apply(&mut self, overrides: &HardwareOverrides) {
+        let fields = [
+            (&mut self.program_addr_bits, overrides.program_addr_bits),
+            (&mut self.program_data_bits, overrides.program_data_bits),
+            (&mut self.program_channels, overrides.program_channels),
+            (&mut self.data_addr_bits, overrides.data_addr_bits),
+            (&mut self.data_data_bits, overrides.data_data_bits),
+            (&mut self.data_channels, overrides.data_channels),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+    }
+}
+
+/// Data memory contents a kernel should leave behind, starting at `address`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedData {
+    #[serde(default)]
+    pub address: u8,
+    pub data: Vec<u8>,
+}
+
+/// How a kernel is launched: `threads` threads in `blocks` blocks of `block_dim` (`%blockDim`)
+/// threads, the last of which may be partial, dealt out in turn to `cores` cores
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Launch {
+    pub threads: u32,
+    pub blocks: u32,
+    pub block_dim: u32,
+    pub cores: u32,
+}
+
+impl Launch {
+    pub fn new(threads: u32, block_dim: u32, cores: u32) -> Launch {
+        Launch {
+            threads,
+            blocks: threads.div_ceil(block_dim),
+            block_dim,
+            cores,
+        }
+    }
+}
+
+impl fmt::Display for Launch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} threads in {} blocks of {} on {} cores",
+            self.threads, self.blocks, self.block_dim, self.cores
+        )
+    }
+}
+
+impl Default for Launch {
+    fn default() -> Self {
+        Launch::new(1, THREADS_PER_BLOCK, CORES)
+    }
+}
+
+/// Cycles a data memory access takes, kept low since it makes for faster tests
+pub const DEFAULT_MEMORY_DELAY: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Output {
+    pub testname: String,
+    pub memory_delay: u32,
+    #[serde(flatten)]
+    pub launch: Launch,
+    pub hardware: Hardware,
+    pub program_memory: Vec<String>,
+    pub initial_data: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_data: Option<ExpectedData>,
+}
+
+/// Builds the test output of an assembled kernel with the default hardware.
+pub fn build_output(testname: &str, assembly: &Assembly) -> Result<Output, LineError> {
+    // Convert operations to hex strings
+    let program_memory = assembly
+        .program()
+        .into_iter()
+        .map(|value| format!("0x{:04x}", value))
+        .collect();
+
+    let hardware = Hardware::default();
+    Ok(Output {
+        testname: testname.to_string(),
+        memory_delay: DEFAULT_MEMORY_DELAY,
+        launch: assembly.launch(&hardware)?,
+        hardware,
+        program_memory,
+        initial_data: assembly.initial_data()?,
+        expected_data: None,
+    })
+}
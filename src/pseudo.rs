@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+
+use crate::immediate::{fit_immediate, parse_immediate};
+use crate::regalloc::is_virtual_register;
+use crate::{LexError, LineError, ParsedLine, Register};
+
+/// Pseudoinstructions
+/// ---
+/// Pseudoinstructions are expanded into real operations before lines are identified,
+/// so every expanded line keeps the source line number of the pseudoinstruction.
+///
+/// - `LI Rd, #value` loads a constant of any size that fits the register width.
+///   Values that do not fit a `CONST` are built from `CONST`/`MUL`/`ADD`/`SUB` sequences,
+///   which may clobber the register declared with `.scratch Rn`.
+///
+/// Related directives:
+/// - `.scratch Rn` declares the register LI may clobber
+/// - `.register_width N` sets the register width LI synthesizes for (default 8)
+#[derive(Debug, Clone, Copy)]
+pub struct PseudoConfig {
+    pub scratch: Option<Register>,
+    pub register_width: u32,
+}
+
+pub const DEFAULT_REGISTER_WIDTH: u32 = 8;
+const MAX_REGISTER_WIDTH: u32 = 32;
+
+impl Default for PseudoConfig {
+    fn default() -> Self {
+        PseudoConfig {
+            scratch: None,
+            register_width: DEFAULT_REGISTER_WIDTH,
+        }
+    }
+}
+
+/// Reads the `.scratch` and `.register_width` directives from the parsed source.
+pub fn pseudo_config(lines: &[ParsedLine]) -> Result<PseudoConfig, LineError> {
+    let mut config = PseudoConfig::default();
+
+    for line in lines {
+        apply_directive(&mut config, line).map_err(|error| LineError {
+            line_num: line.line_num,
+            error,
+        })?;
+    }
+
+    Ok(config)
+}
+
+fn apply_directive(config: &mut PseudoConfig, line: &ParsedLine) -> Result<(), LexError> {
+    match line.tokens.first().map(|t| t.as_str()) {
+        Some(".scratch") => {
+            let reg = match line.tokens.get(1..) {
+                Some([reg]) => Register::from_str(reg)?,
+                _ => {
+                    return Err(LexError::InvalidArgument(
+                        ".scratch takes exactly one register".into(),
+                    ))
+                }
+            };
+            if reg.is_special() {
+                return Err(LexError::InvalidArgument(format!(
+                    "{} is read-only and cannot be used as the .scratch register",
+                    reg.name()
+                )));
+            }
+            config.scratch = Some(reg);
+        }
+        Some(".register_width") => {
+            let width = match line.tokens.get(1..) {
+                Some([width]) => width.parse::<u32>().ok(),
+                _ => None,
+            };
+            match width {
+                Some(width) if (DEFAULT_REGISTER_WIDTH..=MAX_REGISTER_WIDTH).contains(&width) => {
+                    config.register_width = width
+                }
+                _ => {
+                    return Err(LexError::InvalidArgument(format!(
+                        ".register_width takes a width between {} and {} bits",
+                        DEFAULT_REGISTER_WIDTH, MAX_REGISTER_WIDTH
+                    )))
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Expands one line if it is a pseudoinstruction, otherwise returns it as is.
+pub fn expand_line(line: &ParsedLine, config: &PseudoConfig) -> Result<Vec<ParsedLine>, LexError> {
+    match line.tokens.first().map(|t| t.as_str()) {
+        Some("LI") => expand_li(line, config),
+        _ => Ok(vec![line.clone()]),
+    }
+}
+
+fn expand_li(line: &ParsedLine, config: &PseudoConfig) -> Result<Vec<ParsedLine>, LexError> {
+    let (d, literal) = match line.tokens.get(1..) {
+        Some([rd, literal]) => (rd.as_str(), literal),
+        _ => {
+            return Err(LexError::InvalidArgument(
+                "LI takes a destination register and an immediate (LI Rd, #value)".into(),
+            ))
+        }
+    };
+
+    // a virtual register is allocated later, and never onto the scratch register
+    let rd = match is_virtual_register(d) {
+        true => None,
+        false => Some(Register::from_str(d)?),
+    };
+    if let Some(rd) = rd.filter(|rd| rd.is_special()) {
+        return Err(LexError::InvalidArgument(format!(
+            "{} is read-only and cannot be the destination of LI",
+            rd.name()
+        )));
+    }
+
+    let value = parse_immediate(literal)?;
+    let value = fit_immediate(literal, value, config.register_width)?;
+
+    let scratch = match config.scratch {
+        Some(scratch) if Some(scratch) == rd => {
+            return Err(LexError::InvalidArgument(format!(
+                "LI destination {} is also the .scratch register",
+                d
+            )))
+        }
+        scratch => scratch,
+    };
+
+    let steps = synthesize_constant(value, config.register_width, scratch.is_some()).ok_or_else(|| {
+        LexError::InvalidArgument(format!(
+            "LI {}, {} does not fit a single CONST and needs a scratch register, declare one with `.scratch Rn`",
+            d,
+            literal
+        ))
+    })?;
+
+    let s = scratch.map(|s| s.name()).unwrap_or_default();
+    let to_tokens = |step: &Step| -> Vec<String> {
+        let tokens: Vec<String> = match *step {
+            Step::Const(c) => vec!["CONST".into(), d.into(), format!("#{c}")],
+            Step::ConstScratch(c) => vec!["CONST".into(), s.into(), format!("#{c}")],
+            Step::Square => vec!["MUL".into(), d.into(), d.into(), d.into()],
+            Step::Double => vec!["ADD".into(), d.into(), d.into(), d.into()],
+            Step::MulScratch => vec!["MUL".into(), d.into(), d.into(), s.into()],
+            Step::AddScratch => vec!["ADD".into(), d.into(), d.into(), s.into()],
+            Step::SubScratch => vec!["SUB".into(), d.into(), d.into(), s.into()],
+            Step::NegateFromScratch => vec!["SUB".into(), d.into(), s.into(), d.into()],
+        };
+        tokens
+    };
+
+    Ok(steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| ParsedLine {
+            tokens: to_tokens(step),
+            // only the first expanded line carries the source comment
+            comment: if i == 0 { line.comment.clone() } else { None },
+            line_num: line.line_num,
+        })
+        .collect())
+}
+
+/// A single instruction of a synthesized constant, with D the destination and S the scratch register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Const(u8),         // CONST D, #c
+    ConstScratch(u8),  // CONST S, #c
+    Square,            // MUL D, D, D
+    Double,            // ADD D, D, D
+    MulScratch,        // MUL D, D, S
+    AddScratch,        // ADD D, D, S
+    SubScratch,        // SUB D, D, S
+    NegateFromScratch, // SUB D, S, D (with S = 0)
+}
+
+/// How a value is built on top of a smaller one, searched by `synthesize_constant`.
+#[derive(Debug, Clone, Copy)]
+enum Plan {
+    Const(u8),
+    Square(u64),
+    Double(u64),
+    Scale(u64, u8),
+    ScaleOffset(u64, u8, u8, bool), // x * b + c, or x * b - c when the flag is set
+    SquareOffset(u64, u8, bool),    // x * x + c, or x * x - c when the flag is set
+    DoubleInc(u64),                 // x + x + 1
+}
+
+/// Finds the shortest instruction sequence that leaves `value` in the destination register,
+/// using arithmetic modulo 2^`width`. Returns None if the value needs a scratch register and
+/// `has_scratch` is false.
+///
+/// The search covers constants, squaring, doubling, and scaling by an 8-bit factor with an
+/// optional 8-bit offset, applied recursively, plus negation of the whole value. It is exact for
+/// values up to 16 bits; wider values that have no short sequence are split into base 255 digits
+/// on top of an exactly searched 16-bit prefix.
+pub fn synthesize_constant(value: u64, width: u32, has_scratch: bool) -> Option<Vec<Step>> {
+    let modulus = 1u64 << width;
+    let value = value % modulus;
+    let negated = (value != 0 && has_scratch).then_some(modulus - value);
+
+    let mut search = Search {
+        has_scratch,
+        memo: HashMap::new(),
+    };
+
+    if !has_scratch || value <= EXACT_SEARCH_MAX {
+        return search.deepen(value, negated, MAX_SEQUENCE_LEN);
+    }
+
+    if let Some(steps) = search.deepen(value, negated, WIDE_SEARCH_LEN) {
+        return Some(steps);
+    }
+
+    // value * 255 + digit for every digit above the exactly searched prefix
+    let mut digits = vec![];
+    let mut prefix = value;
+    while prefix > EXACT_SEARCH_MAX {
+        digits.push((prefix % 255) as u8);
+        prefix /= 255;
+    }
+
+    let mut steps = search.deepen(prefix, None, MAX_SEQUENCE_LEN)?;
+    for digit in digits.into_iter().rev() {
+        steps.extend([Step::ConstScratch(255), Step::MulScratch]);
+        if digit != 0 {
+            steps.extend([Step::ConstScratch(digit), Step::AddScratch]);
+        }
+    }
+
+    Some(steps)
+}
+
+/// Longest sequence the search will try.
+const MAX_SEQUENCE_LEN: u32 = 24;
+/// Values the search always answers exactly.
+const EXACT_SEARCH_MAX: u64 = u16::MAX as u64;
+/// Longest sequence searched exactly for values above `EXACT_SEARCH_MAX`.
+const WIDE_SEARCH_LEN: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    Exact(u32, Plan),
+    AtLeast(u32),
+}
+
+struct Search {
+    has_scratch: bool,
+    memo: HashMap<u64, Entry>,
+}
+
+impl Search {
+    /// Iterative deepening: the first budget that fits a sequence gives the shortest one.
+    /// `negated` is the modular negation of `value`, built as 0 - x when that is shorter.
+    fn deepen(&mut self, value: u64, negated: Option<u64>, max_budget: u32) -> Option<Vec<Step>> {
+        for budget in 1..=max_budget {
+            if self.cost(value, budget).is_some() {
+                return Some(self.steps(value));
+            }
+
+            if let Some(negated) = negated.filter(|&negated| negated < value && budget > 2) {
+                if self.cost(negated, budget - 2).is_some() {
+                    let mut steps = self.steps(negated);
+                    steps.extend([Step::ConstScratch(0), Step::NegateFromScratch]);
+                    return Some(steps);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The exact cost of building `value`, if it can be done within `budget` instructions.
+    fn cost(&mut self, value: u64, budget: u32) -> Option<u32> {
+        match self.memo.get(&value) {
+            Some(Entry::Exact(cost, _)) => return (*cost <= budget).then_some(*cost),
+            Some(Entry::AtLeast(bound)) if *bound > budget => return None,
+            _ => {}
+        }
+
+        let entry = match self.best_plan(value, budget) {
+            Some((cost, plan)) => Entry::Exact(cost, plan),
+            None => Entry::AtLeast(budget + 1),
+        };
+        self.memo.insert(value, entry);
+
+        match entry {
+            Entry::Exact(cost, _) => Some(cost),
+            Entry::AtLeast(_) => None,
+        }
+    }
+
+    /// A lower bound on the cost of `value` without searching it.
+    fn lower_bound(&self, value: u64) -> u32 {
+        match self.memo.get(&value) {
+            Some(Entry::Exact(cost, _)) => *cost,
+            Some(Entry::AtLeast(bound)) => *bound,
+            // anything above a CONST takes at least two instructions
+            None if value > u8::MAX as u64 => 2,
+            None => 1,
+        }
+    }
+
+    fn best_plan(&mut self, value: u64, budget: u32) -> Option<(u32, Plan)> {
+        if value <= u8::MAX as u64 {
+            return Some((1, Plan::Const(value as u8)));
+        }
+
+        // (smaller value, instructions added on top of it, plan)
+        let mut candidates: Vec<(u64, u32, Plan)> = vec![];
+
+        let root = value.isqrt();
+        if root * root == value {
+            candidates.push((root, 1, Plan::Square(root)));
+        }
+        if value.is_multiple_of(2) {
+            candidates.push((value / 2, 1, Plan::Double(value / 2)));
+        }
+
+        if self.has_scratch {
+            if !value.is_multiple_of(2) {
+                candidates.push((value / 2, 3, Plan::DoubleInc(value / 2)));
+            }
+
+            let below = value - root * root;
+            if below <= u8::MAX as u64 && below != 0 {
+                candidates.push((root, 3, Plan::SquareOffset(root, below as u8, false)));
+            }
+            let above = (root + 1) * (root + 1) - value;
+            if above <= u8::MAX as u64 {
+                candidates.push((root + 1, 3, Plan::SquareOffset(root + 1, above as u8, true)));
+            }
+
+            for factor in 2..=u8::MAX {
+                let b = factor as u64;
+                let (quotient, remainder) = (value / b, value % b);
+                if quotient == 0 {
+                    break;
+                }
+
+                if remainder == 0 {
+                    candidates.push((quotient, 2, Plan::Scale(quotient, factor)));
+                } else {
+                    candidates.push((
+                        quotient,
+                        4,
+                        Plan::ScaleOffset(quotient, factor, remainder as u8, false),
+                    ));
+                    candidates.push((
+                        quotient + 1,
+                        4,
+                        Plan::ScaleOffset(quotient + 1, factor, (b - remainder) as u8, true),
+                    ));
+                }
+            }
+        }
+
+        // cheapest additions first, so later candidates can be skipped by their lower bound alone
+        candidates.sort_by_key(|&(_, extra, _)| extra);
+
+        let mut best: Option<(u32, Plan)> = None;
+        for (smaller, extra, plan) in candidates {
+            // only sequences strictly shorter than the best so far (and within budget) are useful
+            let limit = best.map_or(budget, |(best_cost, _)| best_cost - 1);
+            if extra + self.lower_bound(smaller) > limit {
+                continue;
+            }
+
+            if let Some(cost) = self.cost(smaller, limit - extra) {
+                best = Some((cost + extra, plan));
+            }
+        }
+
+        best
+    }
+
+    fn steps(&mut self, value: u64) -> Vec<Step> {
+        let plan = match self.memo[&value] {
+            Entry::Exact(_, plan) => plan,
+            Entry::AtLeast(_) => unreachable!("steps requested for a value that was not built"),
+        };
+
+        let mut steps = match plan {
+            Plan::Const(_) => vec![],
+            Plan::Square(x) | Plan::Double(x) | Plan::DoubleInc(x) => self.steps(x),
+            Plan::Scale(x, _) | Plan::ScaleOffset(x, _, _, _) | Plan::SquareOffset(x, _, _) => {
+                self.steps(x)
+            }
+        };
+
+        match plan {
+            Plan::Const(c) => steps.push(Step::Const(c)),
+            Plan::Square(_) => steps.push(Step::Square),
+            Plan::Double(_) => steps.push(Step::Double),
+            Plan::DoubleInc(_) => {
+                steps.extend([Step::Double, Step::ConstScratch(1), Step::AddScratch])
+            }
+            Plan::Scale(_, b) => steps.extend([Step::ConstScratch(b), Step::MulScratch]),
+            Plan::ScaleOffset(_, b, c, subtract) => {
+                steps.extend([
+                    Step::ConstScratch(b),
+                    Step::MulScratch,
+                    Step::ConstScratch(c),
+                ]);
+                steps.push(if subtract {
+                    Step::SubScratch
+                } else {
+                    Step::AddScratch
+                });
+            }
+            Plan::SquareOffset(_, c, subtract) => {
+                steps.extend([Step::Square, Step::ConstScratch(c)]);
+                steps.push(if subtract {
+                    Step::SubScratch
+                } else {
+                    Step::AddScratch
+                });
+            }
+        }
+
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `steps` modulo 2^`width` and returns the destination register
+    fn evaluate(steps: &[Step], width: u32) -> u64 {
+        let mask = (1u64 << width) - 1;
+        let (mut d, mut s) = (0u64, 0u64);
+        for step in steps {
+            match *step {
+                Step::Const(c) => d = c as u64,
+                Step::ConstScratch(c) => s = c as u64,
+                Step::Square => d = d.wrapping_mul(d),
+                Step::Double => d = d.wrapping_add(d),
+                Step::MulScratch => d = d.wrapping_mul(s),
+                Step::AddScratch => d = d.wrapping_add(s),
+                Step::SubScratch => d = d.wrapping_sub(s),
+                Step::NegateFromScratch => d = s.wrapping_sub(d),
+            }
+            d &= mask;
+        }
+        d
+    }
+
+    fn line(source: &str) -> ParsedLine {
+        ParsedLine {
+            tokens: source
+                .split([' ', ','])
+                .filter(|t| !t.is_empty())
+                .map(String::from)
+                .collect(),
+            comment: Some("; load".into()),
+            line_num: 7,
+        }
+    }
+
+    #[test]
+    fn synthesized_constants_evaluate_to_their_value() {
+        let cases: [(u32, &[u64]); 4] = [
+            (8, &[0, 1, 127, 255]),
+            (12, &[256, 257, 1000, 2049, 4095]),
+            (16, &[256, 4096, 12345, 40000, 65521, 65535]),
+            (32, &[65536, 1_000_003, 0xDEAD_BEEF, u32::MAX as u64]),
+        ];
+        for (width, values) in cases {
+            for &value in values {
+                let steps = synthesize_constant(value, width, true)
+                    .unwrap_or_else(|| panic!("{value} at {width} bits"));
+                assert_eq!(evaluate(&steps, width), value, "{value} at {width} bits");
+                assert!(steps.len() as u32 <= MAX_SEQUENCE_LEN, "{value}: {steps:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn small_values_take_one_const() {
+        for value in [0, 42, 255] {
+            assert_eq!(
+                synthesize_constant(value, 16, false),
+                Some(vec![Step::Const(value as u8)])
+            );
+        }
+        // values wrap around the register width
+        assert_eq!(
+            synthesize_constant(0x1_0005, 16, false),
+            Some(vec![Step::Const(5)])
+        );
+    }
+
+    #[test]
+    fn without_scratch_only_squares_and_doubles_are_used() {
+        // 1024 = 32 * 32, 510 = 255 + 255
+        for value in [1024, 510, 65025] {
+            let steps = synthesize_constant(value, 16, false).unwrap();
+            assert_eq!(evaluate(&steps, 16), value);
+            assert!(steps
+                .iter()
+                .all(|step| matches!(step, Step::Const(_) | Step::Square | Step::Double)));
+        }
+        // an odd prime above 255 is neither a square nor a double
+        assert_eq!(synthesize_constant(257, 16, false), None);
+    }
+
+    #[test]
+    fn negation_is_used_when_shorter() {
+        // 65535 = 0 - 1 modulo 2^16
+        let steps = synthesize_constant(65535, 16, true).unwrap();
+        assert_eq!(
+            steps,
+            [
+                Step::Const(1),
+                Step::ConstScratch(0),
+                Step::NegateFromScratch
+            ]
+        );
+    }
+
+    #[test]
+    fn li_expands_onto_the_destination_and_scratch() {
+        let config = PseudoConfig {
+            scratch: Some(Register::from_str("R12").unwrap()),
+            register_width: 16,
+        };
+        let lines = expand_line(&line("LI R3, #1000"), &config).unwrap();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.line_num == 7));
+        assert_eq!(lines[0].comment.as_deref(), Some("; load"));
+        assert!(lines[1..].iter().all(|l| l.comment.is_none()));
+        for line in &lines {
+            assert!(
+                line.tokens[1] == "R3" || line.tokens[..2] == ["CONST", "R12"],
+                "{:?}",
+                line.tokens
+            );
+        }
+    }
+
+    #[test]
+    fn li_without_scratch_reports_the_missing_directive() {
+        let config = PseudoConfig {
+            scratch: None,
+            register_width: 16,
+        };
+        let lines = expand_line(&line("LI R0, #1024"), &config).unwrap();
+        let tokens: Vec<&[String]> = lines.iter().map(|l| &l.tokens[..]).collect();
+        assert_eq!(
+            tokens,
+            [&["CONST", "R0", "#32"][..], &["MUL", "R0", "R0", "R0"]]
+        );
+        let err = expand_line(&line("LI R0, #257"), &config).unwrap_err();
+        assert!(err.to_string().contains(".scratch Rn"), "{err}");
+    }
+
+    #[test]
+    fn li_rejects_bad_destinations_and_values() {
+        let config = PseudoConfig {
+            scratch: Some(Register::from_str("R1").unwrap()),
+            register_width: 8,
+        };
+        for (source, expected) in [
+            ("LI R1, #300", "out of range"),
+            ("LI R1, #3", "also the .scratch register"),
+            ("LI %threadIdx, #3", "read-only"),
+            ("LI R0", "LI takes"),
+        ] {
+            let err = expand_line(&line(source), &config).unwrap_err();
+            assert!(err.to_string().contains(expected), "{source}: {err}");
+        }
+    }
+
+    #[test]
+    fn directives_set_the_config() {
+        let config = pseudo_config(&[line(".scratch R9"), line(".register_width 24")]).unwrap();
+        assert_eq!(config.scratch.map(|r| r.name()), Some("R9"));
+        assert_eq!(config.register_width, 24);
+
+        let err = pseudo_config(&[line(".register_width 64")]).unwrap_err();
+        assert_eq!(err.line_num, 7);
+        assert!(pseudo_config(&[line(".scratch %blockIdx")]).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use crate::analysis::flags_set_on;
+use crate::operation::Operation;
+use crate::{LexError, LineError, ParsedLine, Register};
+
+/// Virtual Registers
+/// ---
+/// Any `%name` operand that is not a special register, such as `%v0` or `%acc`, is a virtual
+/// register. Before unrolling, virtual registers are mapped onto the general purpose
+/// registers the source does not name itself, by liveness analysis and graph colouring: two
+/// virtual registers share a register only if neither is live where the other is written.
+/// The read-only special registers are never allocated.
+///
+/// Allocation fails with the live ranges involved when more virtual registers are live at once
+/// than there are free registers, and when a virtual register may be read before it is written,
+/// which is usually a misspelled special register.
+pub fn is_virtual_register(token: &str) -> bool {
+    token
+        .strip_prefix('%')
+        .is_some_and(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        && Register::from_str(token).is_err()
+}
+
+/// The virtual registers an instruction writes and reads
+fn defs_uses(tokens: &[String]) -> (Option<&str>, Vec<&str>) {
+    let operand = |position: usize| {
+        tokens
+            .get(position)
+            .map(|t| t.as_str())
+            .filter(|t| is_virtual_register(t))
+    };
+    let Some(op) = tokens.first().and_then(|t| Operation::from_str(t).ok()) else {
+        return (None, vec![]);
+    };
+    let (def, uses) = match op {
+        Operation::ADD | Operation::SUB | Operation::MUL | Operation::DIV => {
+            (operand(1), vec![operand(2), operand(3)])
+        }
+        Operation::LDR => (operand(1), vec![operand(2)]),
+        Operation::CONST => (operand(1), vec![]),
+        Operation::CMP | Operation::STR => (None, vec![operand(1), operand(2)]),
+        _ => (None, vec![]),
+    };
+    (def, uses.into_iter().flatten().collect())
+}
+
+/// The index of a virtual register in `names`, adding it if it is new
+fn index_of(name: &str, names: &mut Vec<String>) -> usize {
+    match names.iter().position(|n| n == name) {
+        Some(index) => index,
+        None => {
+            names.push(name.to_string());
+            names.len() - 1
+        }
+    }
+}
+
+struct Instr {
+    line: usize, // index into the lines
+    successors: Vec<usize>,
+    def: Option<usize>,
+    uses: Vec<usize>,
+}
+
+/// Rewrites every virtual register of `lines` to a general purpose register.
+pub fn allocate_registers(lines: &[ParsedLine]) -> Result<Vec<ParsedLine>, LineError> {
+    let label_of = |line: &ParsedLine| {
+        line.tokens
+            .first()
+            .and_then(|t| t.strip_suffix(':'))
+            .map(String::from)
+    };
+    let instruction_of = |line: &ParsedLine| -> Vec<String> {
+        let start = usize::from(label_of(line).is_some());
+        line.tokens[start..].to_vec()
+    };
+    let is_instruction = |tokens: &[String]| {
+        tokens
+            .first()
+            .is_some_and(|t| Operation::from_str(t).is_ok())
+    };
+
+    let mut names: Vec<String> = vec![];
+    // labels point at the next instruction
+    let mut labels: BTreeMap<String, usize> = BTreeMap::new();
+    let mut pending = vec![];
+    let mut instructions: Vec<Instr> = vec![];
+    // general purpose registers the source names itself
+    let mut reserved = [false; 16];
+    for (line_index, line) in lines.iter().enumerate() {
+        pending.extend(label_of(line));
+        let tokens = instruction_of(line);
+        if !is_instruction(&tokens) {
+            continue;
+        }
+        for label in pending.drain(..) {
+            labels.insert(label, instructions.len());
+        }
+
+        for register in tokens[1..]
+            .iter()
+            .filter_map(|t| Register::from_str(t).ok())
+        {
+            reserved[register.index()] = true;
+        }
+        let (def, uses) = defs_uses(&tokens);
+        instructions.push(Instr {
+            line: line_index,
+            successors: vec![],
+            def: def.map(|name| index_of(name, &mut names)),
+            uses: uses
+                .into_iter()
+                .map(|name| index_of(name, &mut names))
+                .collect(),
+        });
+    }
+
+    if names.is_empty() {
+        return Ok(lines.to_vec());
+    }
+
+    // every branch may fall through until the flags are known to be set, as in the analysis
+    // of assembled programs
+    let count = instructions.len();
+    for (addr, instruction) in instructions.iter_mut().enumerate() {
+        let tokens = instruction_of(&lines[instruction.line]);
+        instruction.successors = match tokens[0].as_str() {
+            "RET" => vec![],
+            branch if branch.starts_with("BR") => {
+                let target = tokens.get(1).and_then(|label| labels.get(label)).copied();
+                target.into_iter().chain([addr + 1]).collect()
+            }
+            _ => vec![addr + 1],
+        }
+        .into_iter()
+        .filter(|&next| next < count)
+        .collect();
+    }
+    let conservative: Vec<Vec<usize>> = instructions
+        .iter()
+        .map(|instruction| instruction.successors.clone())
+        .collect();
+    let flags = flags_set_on(&conservative, |addr| {
+        instruction_of(&lines[instructions[addr].line])[0] == "CMP"
+    });
+    for (addr, instruction) in instructions.iter_mut().enumerate() {
+        let tokens = instruction_of(&lines[instruction.line]);
+        let always_taken = tokens[0]
+            .strip_prefix("BR")
+            .is_some_and(|nzp| ['n', 'z', 'p'].iter().all(|flag| nzp.contains(*flag)));
+        let target = tokens.get(1).and_then(|label| labels.get(label)).copied();
+        if let (true, true, Some(target)) = (always_taken, flags[addr], target) {
+            // a BRnzp after a CMP never falls through
+            instruction.successors = vec![target]
+                .into_iter()
+                .filter(|&next| next < count)
+                .collect();
+        }
+    }
+
+    // live-in sets, iterated backwards to a fixpoint
+    let mut live_in: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); count];
+    let live_out = |live_in: &[BTreeSet<usize>], instruction: &Instr| -> BTreeSet<usize> {
+        instruction
+            .successors
+            .iter()
+            .flat_map(|&next| live_in[next].iter().copied())
+            .collect()
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for addr in (0..count).rev() {
+            let instruction = &instructions[addr];
+            let mut live = live_out(&live_in, instruction);
+            if let Some(def) = instruction.def {
+                live.remove(&def);
+            }
+            live.extend(instruction.uses.iter().copied());
+            if live != live_in[addr] {
+                live_in[addr] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let line_error = |addr: usize, error: LexError| LineError {
+        line_num: lines[instructions[addr].line].line_num,
+        error,
+    };
+
+    if let Some(&register) = live_in.first().and_then(|live| live.first()) {
+        let addr = instructions
+            .iter()
+            .position(|instruction| instruction.uses.contains(&register))
+            .unwrap();
+        return Err(line_error(
+            addr,
+            LexError::InvalidArgument(format!(
+                "virtual register {} may be read before it is written",
+                names[register]
+            )),
+        ));
+    }
+
+    // the source lines each virtual register is live on, for errors
+    let mut live_lines: Vec<BTreeSet<u32>> = vec![BTreeSet::new(); names.len()];
+    let mut interference: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); names.len()];
+    for (addr, instruction) in instructions.iter().enumerate() {
+        let line_num = lines[instruction.line].line_num;
+        let out = live_out(&live_in, instruction);
+        for &register in live_in[addr].iter().chain(&out).chain(&instruction.def) {
+            live_lines[register].insert(line_num);
+        }
+        if let Some(def) = instruction.def {
+            for &other in out.iter().filter(|&&other| other != def) {
+                interference[def].insert(other);
+                interference[other].insert(def);
+            }
+        }
+    }
+
+    let free: Vec<Register> = Register::ALL
+        .into_iter()
+        .filter(|r| !r.is_special() && !reserved[r.index()])
+        .collect();
+    let pressure_error = |registers: &mut dyn Iterator<Item = usize>| {
+        let live = registers
+            .map(|register| format!("{} ({})", names[register], ranges(&live_lines[register])))
+            .collect::<Vec<_>>();
+        LexError::RegisterPressure {
+            available: free.len(),
+            live,
+        }
+    };
+
+    // a write with more registers live after it than there are free registers
+    for (addr, instruction) in instructions.iter().enumerate() {
+        let mut live = live_out(&live_in, instruction);
+        live.extend(instruction.def);
+        if live.len() > free.len() {
+            return Err(line_error(addr, pressure_error(&mut live.into_iter())));
+        }
+    }
+
+    // simplify: take out a register with fewer neighbours than colours until none are left,
+    // or the one with the most neighbours when none has, and colour them in reverse
+    let mut remaining: BTreeSet<usize> = (0..names.len()).collect();
+    let mut stack = vec![];
+    while !remaining.is_empty() {
+        let degree = |register: usize| interference[register].intersection(&remaining).count();
+        let next = remaining
+            .iter()
+            .copied()
+            .find(|&register| degree(register) < free.len())
+            .unwrap_or_else(|| {
+                remaining
+                    .iter()
+                    .copied()
+                    .max_by_key(|&r| degree(r))
+                    .unwrap()
+            });
+        remaining.remove(&next);
+        stack.push(next);
+    }
+
+    let mut colour: Vec<Option<Register>> = vec![None; names.len()];
+    while let Some(register) = stack.pop() {
+        let taken: Vec<Register> = interference[register]
+            .iter()
+            .filter_map(|&other| colour[other])
+            .collect();
+        match free.iter().find(|r| !taken.contains(r)) {
+            Some(&physical) => colour[register] = Some(physical),
+            None => {
+                let addr = instructions
+                    .iter()
+                    .position(|instruction| instruction.def == Some(register))
+                    .unwrap();
+                let mut involved = std::iter::once(register).chain(
+                    interference[register]
+                        .iter()
+                        .copied()
+                        .filter(|&other| colour[other].is_some()),
+                );
+                return Err(line_error(addr, pressure_error(&mut involved)));
+            }
+        }
+    }
+
+    Ok(lines
+        .iter()
+        .map(|line| ParsedLine {
+            tokens: line
+                .tokens
+                .iter()
+                .map(|token| match names.iter().position(|name| name == token) {
+                    Some(register) => colour[register].unwrap().name().to_string(),
+                    None => token.clone(),
+                })
+                .collect(),
+            ..line.clone()
+        })
+        .collect())
+}
+
+/// Replaces every virtual register with R0, so lines that failed allocation assemble without
+/// an error for each virtual register on top of the allocation error.
+pub fn placeholder_registers(lines: &[ParsedLine]) -> Vec<ParsedLine> {
+    lines
+        .iter()
+        .map(|line| ParsedLine {
+            tokens: line
+                .tokens
+                .iter()
+                .map(|token| match is_virtual_register(token) {
+                    true => Register::R0.name().to_string(),
+                    false => token.clone(),
+                })
+                .collect(),
+            ..line.clone()
+        })
+        .collect()
+}
+
+/// Formats 0-based line numbers as 1-based ranges: `lines 3-5, 9`
+fn ranges(lines: &BTreeSet<u32>) -> String {
+    let mut spans: Vec<(u32, u32)> = vec![];
+    for &line in lines {
+        match spans.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => spans.push((line, line)),
+        }
+    }
+    let spans: Vec<String> = spans
+        .into_iter()
+        .map(|(start, end)| match start == end {
+            true => format!("{}", start + 1),
+            false => format!("{}-{}", start + 1, end + 1),
+        })
+        .collect();
+    format!(
+        "{} {}",
+        if lines.len() == 1 { "line" } else { "lines" },
+        spans.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_source;
+
+    fn allocate(source: &str) -> Result<Vec<ParsedLine>, LineError> {
+        allocate_registers(&parse_source(source).unwrap())
+    }
+
+    /// The register each line's first operand was allocated
+    fn first_operands(lines: &[ParsedLine]) -> Vec<String> {
+        lines
+            .iter()
+            .filter(|line| line.tokens.len() > 1 && !line.tokens[0].ends_with(':'))
+            .map(|line| line.tokens[1].clone())
+            .collect()
+    }
+
+    #[test]
+    fn registers_live_at_once_do_not_share() {
+        let lines =
+            allocate("CONST %a, #1\nCONST %b, #2\nADD %c, %a, %b\nSTR %c, %c\nRET\n").unwrap();
+        let [a, b, c] = &first_operands(&lines)[..3] else {
+            unreachable!()
+        };
+        assert_ne!(a, b);
+        // %a and %b are dead once %c is written, so %c may reuse either
+        assert!(c == a || c == b, "{c} {a} {b}");
+    }
+
+    #[test]
+    fn registers_named_by_the_source_are_not_allocated() {
+        let lines = allocate("CONST R0, #1\nCONST %a, #2\nSTR R0, %a\nRET\n").unwrap();
+        assert_eq!(lines[1].tokens[1], "R1");
+    }
+
+    #[test]
+    fn too_many_live_registers_report_their_live_ranges() {
+        let defs: String = (0..14).map(|i| format!("CONST %a{i}, #{i}\n")).collect();
+        let uses: String = (0..14).map(|i| format!("STR %a{i}, %a{i}\n")).collect();
+        let err = allocate(&format!("{defs}{uses}RET\n")).unwrap_err();
+        assert_eq!(err.line_num, 13);
+        let LexError::RegisterPressure { available, live } = err.error else {
+            panic!("expected a register pressure error, found {:?}", err.error);
+        };
+        assert_eq!(available, 13);
+        assert_eq!(live.len(), 14);
+        assert_eq!(live[0], "%a0 (lines 1-15)");
+    }
+
+    #[test]
+    fn reading_before_writing_is_an_error() {
+        let err = allocate("CONST %a, #1\nADD %a, %a, %threadidx\nRET\n").unwrap_err();
+        assert_eq!(err.line_num, 1);
+        assert_eq!(
+            err.error.to_string(),
+            "Invalid argument: virtual register %threadidx may be read before it is written"
+        );
+    }
+
+    #[test]
+    fn brnzp_before_any_cmp_may_fall_through() {
+        // NZP is 0 until a CMP runs, so the STR of %a executes after %b is written
+        let source = "CONST %a, #1\nCONST %b, #2\nBRnzp SKIP\nSTR %a, %a\nSKIP: STR %b, %b\nRET\n";
+        let operands = first_operands(&allocate(source).unwrap());
+        assert_ne!(operands[0], operands[1]);
+    }
+
+    #[test]
+    fn brnzp_after_a_cmp_never_falls_through() {
+        let source =
+            "CONST %a, #1\nCMP %a, %a\nCONST %b, #2\nBRnzp SKIP\nSTR %a, %a\nSKIP: STR %b, %b\nRET\n";
+        let operands = first_operands(&allocate(source).unwrap());
+        assert_eq!(operands[0], operands[2]);
+    }
+}
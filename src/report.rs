@@ -0,0 +1,270 @@
+use std::fmt;
+
+use crate::assembler::Assembly;
+use crate::instruction::Instruction;
+use crate::operation::Operation;
+use crate::output::Hardware;
+use crate::Register;
+
+/// Resource Report
+/// ---
+/// How much of the GPU an assembled kernel uses: program and data memory against the capacity
+/// the hardware parameters give, the registers it touches, its instruction mix, and the memory
+/// operations in each loop body, since those decide whether an unrolled loop is worth it.
+#[derive(Debug, Clone)]
+pub struct ResourceReport {
+    pub instructions: usize,
+    pub program_capacity: usize,
+    pub data_bytes: usize,
+    pub data_capacity: usize,
+    pub registers: Vec<Register>,
+    pub mix: Vec<(Operation, usize)>,
+    pub loops: Vec<LoopReport>,
+}
+
+/// A loop closed by a backward branch
+#[derive(Debug, Clone)]
+pub struct LoopReport {
+    pub name: String, // the label at its head, or its address
+    pub start: usize,
+    pub end: usize, // address of the backward branch
+    pub line: u32,  // 1-based source line of the head
+    pub loads: usize,
+    pub stores: usize,
+}
+
+pub fn resource_report(assembly: &Assembly, hardware: &Hardware) -> ResourceReport {
+    // one instruction per operation, so an address indexes both
+    let program: Vec<Instruction> = assembly
+        .operations
+        .iter()
+        .map(|line| {
+            line.bin
+                .as_deref()
+                .and_then(Instruction::from_bin)
+                .expect("every operation of an assembled program decodes")
+        })
+        .collect();
+
+    let registers = Register::ALL
+        .into_iter()
+        .filter(|register| {
+            program.iter().any(|instruction| {
+                instruction.dest() == Some(*register) || instruction.sources().contains(register)
+            })
+        })
+        .collect();
+
+    let mix = Operation::ALL
+        .into_iter()
+        .map(|op| {
+            let count = program.iter().filter(|i| i.operation() == op).count();
+            (op, count)
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect();
+
+    let loops = program
+        .iter()
+        .enumerate()
+        .filter_map(|(end, instruction)| match *instruction {
+            Instruction::Branch { target, .. } if target as usize <= end => {
+                Some((target as usize, end))
+            }
+            _ => None,
+        })
+        .map(|(start, end)| {
+            let body = &program[start..=end];
+            let name = assembly
+                .label_addresses
+                .iter()
+                .find(|(_, addr)| *addr as usize == start)
+                .map_or_else(|| format!("#{start}"), |(label, _)| label.clone());
+            LoopReport {
+                name,
+                start,
+                end,
+                line: assembly.operations[start].line_num + 1,
+                loads: body
+                    .iter()
+                    .filter(|i| matches!(i, Instruction::Ldr { .. }))
+                    .count(),
+                stores: body
+                    .iter()
+                    .filter(|i| matches!(i, Instruction::Str { .. }))
+                    .count(),
+            }
+        })
+        .collect();
+
+    ResourceReport {
+        instructions: program.len(),
+        program_capacity: 1 << hardware.program_addr_bits,
+        data_bytes: assembly.initial_data().map_or(0, |data| data.len()),
+        data_capacity: 1 << hardware.data_addr_bits,
+        registers,
+        mix,
+        loops,
+    }
+}
+
+/// Columns of the longest bar in the instruction mix
+const BAR_WIDTH: usize = 30;
+
+fn percent(used: usize, capacity: usize) -> usize {
+    (used * 100).div_ceil(capacity.max(1))
+}
+
+impl fmt::Display for ResourceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "program memory: {} / {} instructions ({}%)",
+            self.instructions,
+            self.program_capacity,
+            percent(self.instructions, self.program_capacity)
+        )?;
+        writeln!(
+            f,
+            "data memory:    {} / {} bytes of initial data ({}%)",
+            self.data_bytes,
+            self.data_capacity,
+            percent(self.data_bytes, self.data_capacity)
+        )?;
+
+        let general: Vec<&str> = self
+            .registers
+            .iter()
+            .filter(|r| !r.is_special())
+            .map(|r| r.name())
+            .collect();
+        writeln!(
+            f,
+            "registers:      {} of 13 general purpose ({})",
+            general.len(),
+            general.join(" ")
+        )?;
+
+        // bars are scaled so the most used operation fills the width
+        writeln!(f, "instruction mix:")?;
+        let most = self.mix.iter().map(|(_, count)| *count).max().unwrap_or(0);
+        for (op, count) in &self.mix {
+            let bar = (count * BAR_WIDTH).div_ceil(most);
+            writeln!(f, "  {:6} {:4}  {}", op.name(), count, "#".repeat(bar))?;
+        }
+
+        if !self.loops.is_empty() {
+            writeln!(f, "loops:")?;
+        }
+        for body in &self.loops {
+            writeln!(
+                f,
+                "  {} (line {}, addresses {}-{}): {} instructions, {} LDR, {} STR",
+                body.name,
+                body.line,
+                body.start,
+                body.end,
+                body.end - body.start + 1,
+                body.loads,
+                body.stores
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const KERNEL: &str = "\
+.data 1 2 3
+CONST R0, #0
+CONST R1, #3
+CONST R2, #1
+LOOP:
+LDR R3, R0
+ADD R3, R3, R3
+STR R0, R3
+ADD R0, R0, R2
+CMP R0, R1
+BRn LOOP
+RET
+";
+
+    #[test]
+    fn counts_memory_registers_and_instructions() {
+        let assembly = assemble(KERNEL);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let report = resource_report(&assembly, &Hardware::default());
+
+        assert_eq!((report.instructions, report.program_capacity), (10, 256));
+        assert_eq!((report.data_bytes, report.data_capacity), (3, 256));
+        assert_eq!(
+            report.registers,
+            [Register::R0, Register::R1, Register::R2, Register::R3]
+        );
+        assert_eq!(
+            report.mix,
+            [
+                (Operation::BRnzp, 1),
+                (Operation::CMP, 1),
+                (Operation::ADD, 2),
+                (Operation::LDR, 1),
+                (Operation::STR, 1),
+                (Operation::CONST, 3),
+                (Operation::RET, 1),
+            ]
+        );
+
+        let [body] = &report.loops[..] else {
+            panic!("expected one loop: {:?}", report.loops);
+        };
+        assert_eq!(
+            (body.name.as_str(), body.start, body.end, body.line),
+            ("LOOP", 3, 8, 6)
+        );
+        assert_eq!((body.loads, body.stores), (1, 1));
+    }
+
+    #[test]
+    fn prints_usage_against_capacity() {
+        let assembly = assemble(KERNEL);
+        let hardware = Hardware {
+            program_addr_bits: 4,
+            ..Hardware::default()
+        };
+        let report = resource_report(&assembly, &hardware).to_string();
+        assert!(
+            report.starts_with("program memory: 10 / 16 instructions (63%)\n"),
+            "{report}"
+        );
+        assert!(
+            report.contains("registers:      4 of 13 general purpose (R0 R1 R2 R3)"),
+            "{report}"
+        );
+        assert!(
+            report.contains(&format!("  CONST     3  {}\n", "#".repeat(BAR_WIDTH))),
+            "{report}"
+        );
+        assert!(
+            report.contains(&format!("  ADD       2  {}\n", "#".repeat(20))),
+            "{report}"
+        );
+        assert!(
+            report.contains("  LOOP (line 6, addresses 3-8): 6 instructions, 1 LDR, 1 STR"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn lists_only_general_purpose_registers() {
+        let assembly = assemble("CONST R0, #1\nADD R1, %threadIdx, R0\nSTR R1, R0\nRET\n");
+        let report = resource_report(&assembly, &Hardware::default()).to_string();
+        assert!(
+            report.contains("registers:      2 of 13 general purpose (R0 R1)\n"),
+            "{report}"
+        );
+    }
+}
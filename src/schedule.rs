@@ -0,0 +1,380 @@
+use std::fmt;
+
+use crate::assembler::Assembly;
+use crate::instruction::Instruction;
+use crate::output::Hardware;
+use crate::simulator::THREADS_PER_BLOCK;
+use crate::MachineLine;
+
+/// Instruction Scheduler
+/// ---
+/// An opt-in list-scheduling pass that reorders the instructions of each basic block so work
+/// independent of an LDR is issued while the load is in flight, instead of stalling on its
+/// result. Within a block the order of
+///
+/// - a register's write and every read or write of it after (and reads before a write)
+/// - a STR and any other LDR or STR, since addresses are not known when assembling
+/// - a CMP and the branch reading its flags, or another CMP
+///
+/// is kept, and the branch or RET ending a block stays last. Blocks start at every branch
+/// target and label, so no address anything jumps to changes. A block keeps its order unless
+/// the new one is estimated to take fewer cycles.
+#[derive(Debug, Clone)]
+pub struct BlockSchedule {
+    pub line_num: u32, // source line of the block's first instruction before scheduling
+    pub start: usize,
+    pub end: usize, // address of the block's last instruction
+    pub before: u32,
+    pub after: u32,
+}
+
+impl fmt::Display for BlockSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} (addresses {}-{}): {} -> {} estimated cycles",
+            self.line_num + 1,
+            self.start,
+            self.end,
+            self.before,
+            self.after
+        )
+    }
+}
+
+/// Cycles before the result of an instruction can be used
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyModel {
+    pub memory: u32,
+}
+
+impl LatencyModel {
+    /// The threads of a block share the data memory channels, so a block's memory requests are
+    /// served `data_channels` at a time, each taking `memory_delay` cycles.
+    pub fn new(memory_delay: u32, hardware: &Hardware) -> LatencyModel {
+        let rounds = THREADS_PER_BLOCK.div_ceil(hardware.data_channels.max(1));
+        LatencyModel {
+            memory: memory_delay * rounds + 1,
+        }
+    }
+
+    fn latency(&self, instruction: &Instruction) -> u32 {
+        if instruction.is_memory() {
+            self.memory
+        } else {
+            1
+        }
+    }
+}
+
+/// Reorders the operations of `assembly` in place and returns the blocks that changed.
+pub fn schedule(assembly: &mut Assembly, model: &LatencyModel) -> Vec<BlockSchedule> {
+    let Some(program) = assembly
+        .operations
+        .iter()
+        .map(|line| line.bin.as_deref().and_then(Instruction::from_bin))
+        .collect::<Option<Vec<Instruction>>>()
+    else {
+        return vec![];
+    };
+
+    let mut block_start = vec![false; program.len() + 1];
+    block_start[0] = true;
+    for (addr, instruction) in program.iter().enumerate() {
+        match *instruction {
+            Instruction::Branch { target, .. } => {
+                for start in [target as usize, addr + 1] {
+                    if let Some(flag) = block_start.get_mut(start) {
+                        *flag = true;
+                    }
+                }
+            }
+            Instruction::Ret => block_start[addr + 1] = true,
+            _ => {}
+        }
+    }
+    for (_, addr) in &assembly.label_addresses {
+        if let Some(flag) = block_start.get_mut(*addr as usize) {
+            *flag = true;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..program.len()).collect();
+    let mut changed = vec![];
+    let mut start = 0;
+    while start < program.len() {
+        let end = (start + 1..=program.len())
+            .find(|&addr| block_start[addr])
+            .unwrap();
+
+        let block = &program[start..end];
+        let dependencies = dependencies(block, model);
+        let before = cycles(
+            block,
+            &dependencies,
+            &(0..block.len()).collect::<Vec<_>>(),
+            model,
+        );
+        let scheduled = list_schedule(block, &dependencies, model);
+        let after = cycles(block, &dependencies, &scheduled, model);
+        if after < before {
+            for (slot, local) in scheduled.into_iter().enumerate() {
+                order[start + slot] = start + local;
+            }
+            changed.push(BlockSchedule {
+                line_num: assembly.operations[start].line_num,
+                start,
+                end: end - 1,
+                before,
+                after,
+            });
+        }
+        start = end;
+    }
+
+    let mut operations: Vec<Option<MachineLine>> = std::mem::take(&mut assembly.operations)
+        .into_iter()
+        .map(Some)
+        .collect();
+    // `order` is a permutation, so every operation is taken once
+    assembly.operations = order
+        .into_iter()
+        .map(|addr| operations[addr].take().unwrap())
+        .collect();
+
+    changed
+}
+
+/// The instructions each instruction of a block must follow, with the cycles it must wait
+/// after issuing them
+type Dependencies = Vec<Vec<(usize, u32)>>;
+
+fn dependencies(block: &[Instruction], model: &LatencyModel) -> Dependencies {
+    let terminator = block
+        .last()
+        .filter(|last| matches!(last, Instruction::Branch { .. } | Instruction::Ret))
+        .map(|_| block.len() - 1);
+
+    block
+        .iter()
+        .enumerate()
+        .map(|(j, later)| {
+            let mut after = vec![];
+            for (i, earlier) in block[..j].iter().enumerate() {
+                let latency = model.latency(earlier);
+                let reads =
+                    |instruction: &Instruction, register| instruction.sources().contains(&register);
+
+                let wait = if Some(j) == terminator {
+                    // flags are only ready once the CMP has executed
+                    match earlier {
+                        Instruction::Cmp { .. } => Some(1),
+                        _ => Some(0),
+                    }
+                } else if earlier
+                    .dest()
+                    .is_some_and(|rd| reads(later, rd) || later.dest() == Some(rd))
+                {
+                    Some(latency)
+                } else if later.dest().is_some_and(|rd| reads(earlier, rd)) {
+                    Some(0)
+                } else if earlier.is_memory()
+                    && later.is_memory()
+                    && (matches!(earlier, Instruction::Str { .. })
+                        || matches!(later, Instruction::Str { .. }))
+                {
+                    Some(latency)
+                } else if matches!(earlier, Instruction::Cmp { .. })
+                    && matches!(later, Instruction::Cmp { .. })
+                {
+                    Some(0)
+                } else {
+                    None
+                };
+                if let Some(wait) = wait {
+                    after.push((i, wait));
+                }
+            }
+            after
+        })
+        .collect()
+}
+
+/// The cycle each instruction of `order` issues at, one per cycle, stalling until its
+/// dependencies allow it
+fn issue_cycles(dependencies: &Dependencies, order: &[usize]) -> Vec<u32> {
+    let mut issued = vec![0; order.len()];
+    let mut cycle = 0;
+    for &index in order {
+        let ready = dependencies[index]
+            .iter()
+            .map(|&(before, wait)| issued[before] + wait)
+            .max()
+            .unwrap_or(0);
+        cycle = cycle.max(ready);
+        issued[index] = cycle;
+        cycle += 1;
+    }
+    issued
+}
+
+/// Estimated cycles to run a block in `order`, until every result has been written
+fn cycles(
+    block: &[Instruction],
+    dependencies: &Dependencies,
+    order: &[usize],
+    model: &LatencyModel,
+) -> u32 {
+    let issued = issue_cycles(dependencies, order);
+    order
+        .iter()
+        .map(|&index| issued[index] + model.latency(&block[index]))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Orders a block by always issuing, among the instructions whose dependencies allow it, the
+/// one with the longest chain of waits still behind it, the earliest in the block on a tie.
+fn list_schedule(
+    block: &[Instruction],
+    dependencies: &Dependencies,
+    model: &LatencyModel,
+) -> Vec<usize> {
+    // longest path to the end of the block; a NOP has nothing behind it so it sinks to the end
+    let mut priority = vec![0; block.len()];
+    for index in (0..block.len()).rev() {
+        let own = match block[index] {
+            Instruction::Nop => 0,
+            ref instruction => model.latency(instruction),
+        };
+        priority[index] = priority[index].max(own);
+        for &(before, wait) in &dependencies[index] {
+            priority[before] = priority[before].max(wait + priority[index]);
+        }
+    }
+
+    let mut issued: Vec<Option<u32>> = vec![None; block.len()];
+    let mut order = Vec::with_capacity(block.len());
+    let mut cycle = 0;
+    while order.len() < block.len() {
+        // instructions whose dependencies have all issued, with the cycle they can issue at
+        let candidates: Vec<(usize, u32)> = (0..block.len())
+            .filter(|&index| issued[index].is_none())
+            .filter_map(|index| {
+                dependencies[index]
+                    .iter()
+                    .map(|&(before, wait)| issued[before].map(|at| at + wait))
+                    .try_fold(0, |ready, at| at.map(|at| ready.max(at)))
+                    .map(|ready| (index, ready))
+            })
+            .collect();
+
+        let earliest = candidates.iter().map(|&(_, ready)| ready).min().unwrap();
+        cycle = cycle.max(earliest);
+        let (next, _) = candidates
+            .iter()
+            .filter(|&&(_, ready)| ready <= cycle)
+            .max_by_key(|&&(index, _)| (priority[index], std::cmp::Reverse(index)))
+            .copied()
+            .unwrap();
+
+        issued[next] = Some(cycle);
+        order.push(next);
+        cycle += 1;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::simulator::Simulator;
+
+    const KERNEL: &str = "\
+.threads 4
+.data 5 6 7 8
+LDR R1, %threadIdx
+ADD R1, R1, R1
+CONST R2, #4
+ADD R3, R2, %threadIdx
+CONST R4, #1
+STR R3, R1
+RET
+";
+
+    /// The mnemonic of every instruction, in program order
+    fn mnemonics(assembly: &Assembly) -> Vec<String> {
+        assembly
+            .program()
+            .into_iter()
+            .filter_map(Instruction::decode)
+            .map(|i| i.operation().name().to_string())
+            .collect()
+    }
+
+    fn memory(assembly: &Assembly) -> Vec<u8> {
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        let data = assembly.initial_data().unwrap();
+        let mut sim = Simulator::new(assembly.program(), &data, launch);
+        sim.run().unwrap();
+        sim.memory
+    }
+
+    #[test]
+    fn latency_grows_with_shared_memory_channels() {
+        let hardware = |data_channels| Hardware {
+            data_channels,
+            ..Hardware::default()
+        };
+        assert_eq!(LatencyModel::new(1, &hardware(4)).memory, 2);
+        assert_eq!(LatencyModel::new(3, &hardware(1)).memory, 13);
+    }
+
+    #[test]
+    fn independent_work_fills_the_load_delay() {
+        let mut assembly = assemble(KERNEL);
+        let unscheduled = memory(&assembly);
+        let model = LatencyModel::new(4, &Hardware::default());
+
+        let [block] = &schedule(&mut assembly, &model)[..] else {
+            panic!("expected one scheduled block");
+        };
+        assert!(block.after < block.before, "{block}");
+        assert_eq!((block.start, block.end), (0, 6));
+        assert_eq!(
+            mnemonics(&assembly),
+            ["LDR", "CONST", "ADD", "CONST", "ADD", "STR", "RET"]
+        );
+        assert_eq!(memory(&assembly), unscheduled);
+        assert_eq!(unscheduled[4..8], [10, 12, 14, 16]);
+    }
+
+    #[test]
+    fn keeps_memory_order_block_ends_and_labels() {
+        let source = "\
+.threads 1
+CONST R0, #1
+CMP R0, R0
+LOOP:
+LDR R1, R0
+STR R0, R0
+LDR R2, R0
+ADD R2, R2, R2
+CMP R0, R0
+BRn LOOP
+RET
+";
+        let mut assembly = assemble(source);
+        let model = LatencyModel::new(4, &Hardware::default());
+        assert_eq!(schedule(&mut assembly, &model).len(), 1);
+
+        // the independent CMP moves up, but loads and stores keep their order around the STR
+        // and the branch still ends the loop
+        assert_eq!(
+            mnemonics(&assembly),
+            ["CONST", "CMP", "LDR", "CMP", "STR", "LDR", "ADD", "BRnzp", "RET"]
+        );
+        assert_eq!(assembly.label_addresses, [("LOOP".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt;
+
+use crate::instruction::{Instruction, NZP_N, NZP_P, NZP_Z};
+use crate::output::Launch;
+use crate::Register;
+
+/// Functional Simulator
+/// ---
+/// Executes assembled programs one instruction at a time, without modelling the pipeline or
+/// memory latency of the TinyGPU. Threads are grouped into blocks of the launch's `block_dim`, as
+/// the dispatcher does, and each thread keeps its own PC, registers and NZP flags.
+///
+/// CMP compares unsigned and sets N for `Rs < Rt`, Z for `Rs == Rt` and P for `Rs > Rt`.
+/// Writes to the special registers are dropped, like the register file does.
+pub const THREADS_PER_BLOCK: u32 = 4;
+pub const DATA_MEMORY_SIZE: usize = 256;
+pub const CORES: u32 = 2;
+
+/// Rounds of `step_all` before a kernel that never finishes is given up on
+pub const MAX_ROUNDS: usize = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub block: u32,
+    pub core: u32,   // blocks are dealt out to the cores in turn
+    pub thread: u32, // index within the block
+    pub pc: usize,
+    pub nzp: u8,
+    pub registers: [u8; 16],
+    pub done: bool,
+}
+
+impl Thread {
+    pub fn register(&self, register: Register) -> u8 {
+        self.registers[register.index()]
+    }
+}
+
+/// A state change caused by one executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    RegisterWrite { register: Register, value: u8 },
+    FlagsWrite { nzp: u8 },
+    MemoryRead { addr: u8, value: u8 },
+    MemoryWrite { addr: u8, value: u8 },
+}
+
+/// One instruction executed by one thread
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub thread: usize,
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimError {
+    InvalidInstruction { thread: usize, pc: usize, word: u16 },
+    PcOutOfRange { thread: usize, pc: usize },
+    DivideByZero { thread: usize, pc: usize },
+    StepLimit { rounds: usize },
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::InvalidInstruction { thread, pc, word } => write!(
+                f,
+                "thread {thread}: invalid instruction 0x{word:04x} at address {pc}"
+            ),
+            SimError::PcOutOfRange { thread, pc } => write!(
+                f,
+                "thread {thread}: ran past the end of the program to address {pc}"
+            ),
+            SimError::DivideByZero { thread, pc } => {
+                write!(f, "thread {thread}: division by zero at address {pc}")
+            }
+            SimError::StepLimit { rounds } => {
+                write!(f, "kernel still running after {rounds} rounds")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    pub program: Vec<u16>,
+    pub memory: Vec<u8>,
+    pub threads: Vec<Thread>,
+    pub launch: Launch,
+}
+
+impl Simulator {
+    /// Launches the threads of `launch` over `program` with data memory starting as
+    /// `initial_data`.
+    pub fn new(program: Vec<u16>, initial_data: &[u8], launch: Launch) -> Simulator {
+        let mut memory = vec![0; DATA_MEMORY_SIZE];
+        let len = initial_data.len().min(DATA_MEMORY_SIZE);
+        memory[..len].copy_from_slice(&initial_data[..len]);
+
+        let threads = (0..launch.threads)
+            .map(|global| {
+                let (block, thread) = (global / launch.block_dim, global % launch.block_dim);
+                let mut registers = [0; 16];
+                registers[Register::BlockIdx.index()] = block as u8;
+                registers[Register::BlockDim.index()] = launch.block_dim as u8;
+                registers[Register::ThreadIdx.index()] = thread as u8;
+                Thread {
+                    block,
+                    core: block % launch.cores,
+                    thread,
+                    pc: 0,
+                    nzp: 0,
+                    registers,
+                    done: false,
+                }
+            })
+            .collect();
+
+        Simulator {
+            program,
+            memory,
+            threads,
+            launch,
+        }
+    }
+
+    pub fn blocks(&self) -> u32 {
+        self.launch.blocks
+    }
+
+    /// Global indices of the threads in `block`
+    pub fn block_threads(&self, block: u32) -> impl Iterator<Item = usize> + '_ {
+        (0..self.threads.len()).filter(move |&index| self.threads[index].block == block)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.threads.iter().all(|thread| thread.done)
+    }
+
+    /// The instruction `thread` executes next, None once it has returned
+    pub fn next_instruction(&self, thread: usize) -> Option<Result<Instruction, SimError>> {
+        let state = &self.threads[thread];
+        if state.done {
+            return None;
+        }
+        let pc = state.pc;
+        Some(match self.program.get(pc) {
+            None => Err(SimError::PcOutOfRange { thread, pc }),
+            Some(&word) => {
+                Instruction::decode(word).ok_or(SimError::InvalidInstruction { thread, pc, word })
+            }
+        })
+    }
+
+    /// Executes one instruction on `thread`, None if it has already returned.
+    pub fn step_thread(&mut self, thread: usize) -> Option<Result<Step, SimError>> {
+        let instruction = match self.next_instruction(thread)? {
+            Ok(instruction) => instruction,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let pc = self.threads[thread].pc;
+        let state = &self.threads[thread];
+        let get = |r: Register| state.register(r);
+        let mut events = vec![];
+        let mut next_pc = pc + 1;
+
+        let value = match instruction {
+            Instruction::Nop => None,
+            Instruction::Ret => {
+                self.threads[thread].done = true;
+                return Some(Ok(Step {
+                    thread,
+                    pc,
+                    instruction,
+                    events,
+                }));
+            }
+            Instruction::Branch { nzp, target } => {
+                if state.nzp & nzp != 0 {
+                    next_pc = target as usize;
+                }
+                None
+            }
+            Instruction::Cmp { rs, rt } => {
+                let nzp = match get(rs).cmp(&get(rt)) {
+                    std::cmp::Ordering::Less => NZP_N,
+                    std::cmp::Ordering::Equal => NZP_Z,
+                    std::cmp::Ordering::Greater => NZP_P,
+                };
+                events.push(Event::FlagsWrite { nzp });
+                None
+            }
+            Instruction::Add { rs, rt, .. } => Some(get(rs).wrapping_add(get(rt))),
+            Instruction::Sub { rs, rt, .. } => Some(get(rs).wrapping_sub(get(rt))),
+            Instruction::Mul { rs, rt, .. } => Some(get(rs).wrapping_mul(get(rt))),
+            Instruction::Div { rs, rt, .. } => match get(rt) {
+                0 => return Some(Err(SimError::DivideByZero { thread, pc })),
+                divisor => Some(get(rs) / divisor),
+            },
+            Instruction::Ldr { rs, .. } => {
+                let addr = get(rs);
+                let value = self.memory[addr as usize];
+                events.push(Event::MemoryRead { addr, value });
+                Some(value)
+            }
+            Instruction::Str { rs, rt } => {
+                let (addr, value) = (get(rs), get(rt));
+                events.push(Event::MemoryWrite { addr, value });
+                None
+            }
+            Instruction::Const { imm, .. } => Some(imm),
+        };
+
+        if let (Some(value), Some(register)) = (value, instruction.dest()) {
+            if !register.is_special() {
+                events.push(Event::RegisterWrite { register, value });
+            }
+        }
+
+        for event in &events {
+            match *event {
+                Event::RegisterWrite { register, value } => {
+                    self.threads[thread].registers[register.index()] = value
+                }
+                Event::FlagsWrite { nzp } => self.threads[thread].nzp = nzp,
+                Event::MemoryWrite { addr, value } => self.memory[addr as usize] = value,
+                Event::MemoryRead { .. } => {}
+            }
+        }
+
+        // running past the end is reported when the thread next steps
+        self.threads[thread].pc = next_pc;
+
+        Some(Ok(Step {
+            thread,
+            pc,
+            instruction,
+            events,
+        }))
+    }
+
+    /// Executes one instruction on every running thread of `block`, in thread order.
+    pub fn step_block(&mut self, block: u32) -> Result<Vec<Step>, SimError> {
+        let threads: Vec<usize> = self.block_threads(block).collect();
+        threads
+            .into_iter()
+            .filter_map(|thread| self.step_thread(thread))
+            .collect()
+    }
+
+    /// Executes one instruction on every running thread.
+    pub fn step_all(&mut self) -> Result<Vec<Step>, SimError> {
+        (0..self.threads.len())
+            .filter_map(|thread| self.step_thread(thread))
+            .collect()
+    }
+
+    /// Runs every thread until it returns.
+    pub fn run(&mut self) -> Result<(), SimError> {
+        for _ in 0..MAX_ROUNDS {
+            if self.finished() {
+                return Ok(());
+            }
+            self.step_all()?;
+        }
+        Err(SimError::StepLimit { rounds: MAX_ROUNDS })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::output::Hardware;
+
+    fn simulator(source: &str) -> Simulator {
+        let assembly = assemble(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        Simulator::new(
+            assembly.program(),
+            &assembly.initial_data().unwrap(),
+            launch,
+        )
+    }
+
+    #[test]
+    fn threads_see_their_block_and_index() {
+        let mut sim = simulator(
+            ".threads 6\nMUL R0, %blockIdx, %blockDim\nADD R0, R0, %threadIdx\nCONST R1, #100\nADD R2, R1, R0\nSTR R0, R2\nRET\n",
+        );
+        assert_eq!(sim.blocks(), 2);
+        assert_eq!(sim.block_threads(1).collect::<Vec<_>>(), [4, 5]);
+        assert_eq!(
+            sim.threads.iter().map(|t| t.core).collect::<Vec<_>>(),
+            [0, 0, 0, 0, 1, 1]
+        );
+        sim.run().unwrap();
+        assert!(sim.finished());
+        assert_eq!(sim.memory[..7], [100, 101, 102, 103, 104, 105, 0]);
+    }
+
+    #[test]
+    fn steps_report_their_events() {
+        let mut sim = simulator(".threads 1\n.data 7\nLDR R1, R0\nCMP R1, R0\nRET\n");
+        let step = sim.step_thread(0).unwrap().unwrap();
+        assert_eq!(
+            step.events,
+            [
+                Event::MemoryRead { addr: 0, value: 7 },
+                Event::RegisterWrite {
+                    register: Register::R1,
+                    value: 7
+                }
+            ]
+        );
+        let step = sim.step_thread(0).unwrap().unwrap();
+        assert_eq!(step.events, [Event::FlagsWrite { nzp: NZP_P }]);
+        assert!(sim.step_thread(0).unwrap().is_ok());
+        assert!(sim.step_thread(0).is_none());
+    }
+
+    #[test]
+    fn compares_unsigned_and_branches_on_flags() {
+        // 200 > 1 unsigned, so BRn falls through and BRp is taken
+        let mut sim = simulator(
+            ".threads 1\nCONST R0, #200\nCONST R1, #1\nCMP R0, R1\nBRn NEG\nBRp POS\nNEG:\nCONST R2, #1\nPOS:\nSTR R2, R0\nRET\n",
+        );
+        sim.run().unwrap();
+        assert_eq!(sim.memory[0], 200);
+        assert_eq!(sim.threads[0].nzp, NZP_P);
+    }
+
+    #[test]
+    fn writes_to_special_registers_are_dropped() {
+        let mut sim = simulator(".threads 2\nCONST %threadIdx, #9\nRET\n");
+        sim.run().unwrap();
+        assert_eq!(sim.threads[1].register(Register::ThreadIdx), 1);
+    }
+
+    #[test]
+    fn reports_runtime_errors() {
+        let mut sim = simulator(".threads 1\nCONST R0, #0\nDIV R1, R1, R0\nRET\n");
+        assert_eq!(sim.run(), Err(SimError::DivideByZero { thread: 0, pc: 1 }));
+
+        let mut sim = simulator(".threads 1\nNOP\n");
+        assert_eq!(sim.run(), Err(SimError::PcOutOfRange { thread: 0, pc: 1 }));
+
+        let mut sim = Simulator::new(vec![0b1010_0000_0000_0000], &[], Launch::new(1, 4, 1));
+        assert!(matches!(
+            sim.run(),
+            Err(SimError::InvalidInstruction { pc: 0, .. })
+        ));
+    }
+}
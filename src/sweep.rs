@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::batch::KernelSpec;
+use crate::conditional::parse_define;
+use crate::LexError;
+
+/// Parameter Sweeps
+/// ---
+/// Builds one kernel per combination of parameter values, with each combination given to the
+/// source as defines, as `-D` would. A source written with symbolic parameters
+///
+/// ```text
+/// .threads THREADS
+/// CONST R1, #SPREAD
+/// ```
+///
+/// swept with `-P THREADS=4,8,64 -P SPREAD=1,4` is built six times, as `test_THREADS_4_SPREAD_1`
+/// and so on, each name listing the values in the order the parameters were given.
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub values: Vec<i64>,
+}
+
+/// Parses a `NAME=V1,V2,...` parameter of a sweep.
+pub fn parse_parameter(arg: &str) -> Result<Parameter, LexError> {
+    let Some((name, values)) = arg.split_once('=') else {
+        return Err(LexError::InvalidArgument(format!(
+            "parameter {arg} has no values, expected NAME=V1,V2,..."
+        )));
+    };
+    let values = values
+        .split(',')
+        .map(|value| parse_define(&format!("{name}={value}")).map(|(_, value)| value))
+        .collect::<Result<Vec<i64>, LexError>>()?;
+    Ok(Parameter {
+        name: name.to_string(),
+        values,
+    })
+}
+
+/// Every combination of parameter values, the last parameter changing fastest
+pub fn combinations(parameters: &[Parameter]) -> Vec<Vec<(String, i64)>> {
+    parameters
+        .iter()
+        .fold(vec![vec![]], |combinations, parameter| {
+            combinations
+                .iter()
+                .flat_map(|combination| {
+                    parameter.values.iter().map(move |&value| {
+                        let mut combination = combination.clone();
+                        combination.push((parameter.name.clone(), value));
+                        combination
+                    })
+                })
+                .collect()
+        })
+}
+
+/// The name of a variant: the file stem followed by each parameter and its value
+pub fn variant_name(stem: &str, combination: &[(String, i64)]) -> String {
+    let mut name = stem.to_string();
+    for (parameter, value) in combination {
+        // testnames stay identifiers, so a negative value is written n3
+        let value = match *value < 0 {
+            true => format!("n{}", value.unsigned_abs()),
+            false => value.to_string(),
+        };
+        name += &format!("_{parameter}_{value}");
+    }
+    name
+}
+
+/// The kernels of a sweep over `parameters` of the source at `path`, for `assemble_suite`
+pub fn sweep_kernels(path: &Path, parameters: &[Parameter]) -> Vec<KernelSpec> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    combinations(parameters)
+        .into_iter()
+        .map(|combination| KernelSpec {
+            path: path.to_path_buf(),
+            name: Some(variant_name(&stem, &combination)),
+            defines: combination.into_iter().collect::<BTreeMap<_, _>>(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::assemble_suite;
+    use crate::output::HardwareOverrides;
+
+    fn parameter(arg: &str) -> Parameter {
+        parse_parameter(arg).unwrap()
+    }
+
+    #[test]
+    fn parses_parameter_values() {
+        let threads = parameter("THREADS=4,0x10,-1");
+        assert_eq!(threads.name, "THREADS");
+        assert_eq!(threads.values, [4, 16, -1]);
+
+        for arg in ["THREADS", "THREADS=4,,8", "THREADS=four", "R1=1", "2X=1"] {
+            assert!(parse_parameter(arg).is_err(), "{arg}");
+        }
+    }
+
+    #[test]
+    fn names_every_combination_in_parameter_order() {
+        let names: Vec<String> = combinations(&[parameter("A=1,2"), parameter("B=-3,4")])
+            .iter()
+            .map(|combination| variant_name("test", combination))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "test_A_1_B_n3",
+                "test_A_1_B_4",
+                "test_A_2_B_n3",
+                "test_A_2_B_4"
+            ]
+        );
+        assert_eq!(combinations(&[]), [Vec::<(String, i64)>::new()]);
+    }
+
+    #[test]
+    fn every_variant_is_assembled_with_its_defines() {
+        let path = std::env::temp_dir().join(format!("sweep_{}.asm", std::process::id()));
+        std::fs::write(
+            &path,
+            ".threads THREADS\nCONST R0, #SPREAD\nSTR R0, R0\nRET\n",
+        )
+        .unwrap();
+        let kernels = sweep_kernels(
+            &path,
+            &[parameter("THREADS=4,8"), parameter("SPREAD=1,300")],
+        );
+        let results = assemble_suite(&HardwareOverrides::default(), &kernels);
+        std::fs::remove_file(&path).unwrap();
+
+        let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let outcomes: Vec<(String, Option<u32>)> = results
+            .iter()
+            .map(|result| {
+                let threads = result
+                    .outcome
+                    .as_ref()
+                    .ok()
+                    .map(|output| output.launch.threads);
+                (result.name.replacen(&stem, "test", 1), threads)
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("test_THREADS_4_SPREAD_1".to_string(), Some(4)),
+                ("test_THREADS_4_SPREAD_300".to_string(), None),
+                ("test_THREADS_8_SPREAD_1".to_string(), Some(8)),
+                ("test_THREADS_8_SPREAD_300".to_string(), None),
+            ]
+        );
+    }
+}
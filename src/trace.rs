@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use crate::simulator::{Event, SimError, Simulator, MAX_ROUNDS};
+use crate::{MachineLine, Register};
+
+/// Execution Traces
+/// ---
+/// A trace record for every instruction the functional simulator executes, in the order the
+/// threads run (one instruction per running thread per round), written out as JSON lines.
+///
+/// For checking the simulator against the Verilog, `parse_cocotb_log` reads the per-cycle log the
+/// TinyGPU CocoTB tests write and reduces it to the same per-thread sequence of PCs and register
+/// files, which `first_divergence` compares.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceRecord {
+    pub step: usize,
+    pub core: u32,
+    pub block: u32,
+    pub thread: usize, // global thread index, blockIdx * blockDim + threadIdx
+    pub pc: usize,
+    pub instruction: String,
+    pub line: u32, // 1-based source line
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub register_writes: Vec<RegisterWrite>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nzp: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub memory_reads: Vec<MemoryAccess>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub memory_writes: Vec<MemoryAccess>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisterWrite {
+    pub register: &'static str,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryAccess {
+    pub addr: u8,
+    pub value: u8,
+}
+
+fn format_nzp(nzp: u8) -> String {
+    [(0b100, 'n'), (0b010, 'z'), (0b001, 'p')]
+        .iter()
+        .map(|&(bit, c)| if nzp & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+/// Runs the simulator to completion, recording every executed instruction.
+pub fn trace(
+    sim: &mut Simulator,
+    operations: &[MachineLine],
+) -> Result<Vec<TraceRecord>, SimError> {
+    let mut records = vec![];
+
+    for _ in 0..MAX_ROUNDS {
+        if sim.finished() {
+            return Ok(records);
+        }
+
+        for step in sim.step_all()? {
+            let thread = &sim.threads[step.thread];
+            let mut record = TraceRecord {
+                step: records.len(),
+                core: thread.core,
+                block: thread.block,
+                thread: step.thread,
+                pc: step.pc,
+                instruction: step.instruction.to_string(),
+                line: operations.get(step.pc).map_or(0, |op| op.line_num + 1),
+                register_writes: vec![],
+                nzp: None,
+                memory_reads: vec![],
+                memory_writes: vec![],
+            };
+
+            for event in step.events {
+                match event {
+                    Event::RegisterWrite { register, value } => {
+                        record.register_writes.push(RegisterWrite {
+                            register: register.name(),
+                            value,
+                        })
+                    }
+                    Event::FlagsWrite { nzp } => record.nzp = Some(format_nzp(nzp)),
+                    Event::MemoryRead { addr, value } => {
+                        record.memory_reads.push(MemoryAccess { addr, value })
+                    }
+                    Event::MemoryWrite { addr, value } => {
+                        record.memory_writes.push(MemoryAccess { addr, value })
+                    }
+                }
+            }
+            records.push(record);
+        }
+    }
+
+    Err(SimError::StepLimit { rounds: MAX_ROUNDS })
+}
+
+/// One instruction as seen by one thread: its PC and the registers before it executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Visit {
+    pub pc: usize,
+    pub registers: [u8; 16],
+    pub cycle: Option<u64>, // only known for visits parsed from a log
+}
+
+/// Visits per global thread index
+pub type ThreadTraces = BTreeMap<usize, Vec<Visit>>;
+
+/// The visits of every thread when the simulator runs to completion.
+pub fn simulated_visits(sim: &mut Simulator) -> Result<ThreadTraces, SimError> {
+    let mut traces = ThreadTraces::new();
+
+    for _ in 0..MAX_ROUNDS {
+        if sim.finished() {
+            return Ok(traces);
+        }
+
+        for (index, thread) in sim.threads.iter().enumerate() {
+            if !thread.done {
+                traces.entry(index).or_default().push(Visit {
+                    pc: thread.pc,
+                    registers: thread.registers,
+                    cycle: None,
+                });
+            }
+        }
+        sim.step_all()?;
+    }
+
+    Err(SimError::StepLimit { rounds: MAX_ROUNDS })
+}
+
+/// Reads the per-cycle debug log of the TinyGPU CocoTB tests, which prints for each cycle and
+/// enabled thread its PC and register file:
+///
+/// ```text
+/// =============== Cycle 12 ===============
+/// +-------- Thread 5 --------+
+/// PC:  3
+/// Registers:  R0 = 4, R1 = 0, ... %blockIdx = 1, %blockDim = 4, %threadIdx = 1
+/// ```
+///
+/// A thread spends several cycles on each instruction, so consecutive cycles at the same PC are
+/// collapsed into one visit, keeping the last cycle: the PC and registers are written back
+/// together, so that is the state just before the instruction takes effect. A branch to itself
+/// therefore shows up as a single visit.
+pub fn parse_cocotb_log(log: &str) -> ThreadTraces {
+    let mut traces = ThreadTraces::new();
+    let mut cycle: Option<u64> = None;
+    let mut thread: Option<usize> = None;
+    let mut pc: Option<usize> = None;
+
+    let number_after = |line: &str, key: &str| -> Option<u64> {
+        let rest = &line[line.find(key)? + key.len()..];
+        let digits: String = rest
+            .trim_start()
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        digits.parse().ok()
+    };
+
+    for line in log.lines() {
+        if line.contains("Cycle") {
+            cycle = number_after(line, "Cycle");
+            thread = None;
+        } else if line.contains("Core") && line.contains("+--") {
+            thread = None;
+        } else if line.contains("Thread") && line.contains("+--") {
+            thread = number_after(line, "Thread").map(|n| n as usize);
+            pc = None;
+        } else if line.contains("PC:") {
+            pc = number_after(line, "PC:").map(|n| n as usize);
+        } else if let (Some(index), Some(pc), Some(pos)) = (thread, pc, line.find("Registers:")) {
+            let mut registers = [0u8; 16];
+            for assignment in line[pos + "Registers:".len()..].split(',') {
+                let Some((name, value)) = assignment.split_once('=') else {
+                    continue;
+                };
+                if let (Ok(register), Ok(value)) =
+                    (Register::from_str(name.trim()), value.trim().parse::<u8>())
+                {
+                    registers[register.index()] = value;
+                }
+            }
+
+            let visits = traces.entry(index).or_default();
+            let visit = Visit {
+                pc,
+                registers,
+                cycle,
+            };
+            match visits.last_mut() {
+                Some(last) if last.pc == pc => *last = visit,
+                _ => visits.push(visit),
+            }
+        }
+    }
+
+    traces
+}
+
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub thread: usize,
+    pub index: usize, // instruction number within the thread
+    pub cycle: Option<u64>,
+    pub culprit_pc: Option<usize>, // the instruction whose effect differs
+    pub message: String,
+}
+
+/// Finds the earliest point, by log cycle, where a thread of `actual` (parsed from a log) stops
+/// following `expected` (simulated).
+pub fn first_divergence(expected: &ThreadTraces, actual: &ThreadTraces) -> Option<Divergence> {
+    let empty = vec![];
+    let threads: BTreeSet<usize> = expected.keys().chain(actual.keys()).copied().collect();
+    let mut divergences = vec![];
+
+    for &thread in &threads {
+        let expected = expected.get(&thread).unwrap_or(&empty);
+        let actual = actual.get(&thread).unwrap_or(&empty);
+        let previous_pc = |index: usize| index.checked_sub(1).map(|prev| expected[prev].pc);
+
+        let mismatch = expected
+            .iter()
+            .zip(actual)
+            .enumerate()
+            .find_map(|(index, (e, a))| {
+                let message = if e.pc != a.pc {
+                    format!("expected PC {}, log has PC {}", e.pc, a.pc)
+                } else {
+                    let registers: Vec<String> = Register::ALL
+                        .iter()
+                        .filter(|r| e.registers[r.index()] != a.registers[r.index()])
+                        .map(|r| {
+                            format!(
+                                "{} expected {}, log has {}",
+                                r.name(),
+                                e.registers[r.index()],
+                                a.registers[r.index()]
+                            )
+                        })
+                        .collect();
+                    if registers.is_empty() {
+                        return None;
+                    }
+                    format!("at PC {}: {}", e.pc, registers.join(", "))
+                };
+                Some(Divergence {
+                    thread,
+                    index,
+                    cycle: a.cycle,
+                    culprit_pc: previous_pc(index),
+                    message,
+                })
+            });
+
+        let divergence = mismatch.or_else(|| {
+            let shared = expected.len().min(actual.len());
+            (expected.len() != actual.len()).then(|| Divergence {
+                thread,
+                index: shared,
+                cycle: actual.last().and_then(|visit| visit.cycle),
+                culprit_pc: previous_pc(shared),
+                message: format!(
+                    "simulator executed {} instructions, log has {}",
+                    expected.len(),
+                    actual.len()
+                ),
+            })
+        });
+        divergences.extend(divergence);
+    }
+
+    divergences
+        .into_iter()
+        .min_by_key(|d| (d.cycle.unwrap_or(u64::MAX), d.thread))
+}
+
+impl Divergence {
+    /// Describes the divergence with the source around the instruction that caused it.
+    pub fn report(&self, source: &str, operations: &[MachineLine]) -> String {
+        let mut report = format!(
+            "thread {}: first divergence at instruction {}",
+            self.thread, self.index
+        );
+        if let Some(cycle) = self.cycle {
+            let _ = write!(report, " (cycle {cycle})");
+        }
+        let _ = writeln!(report, ": {}", self.message);
+
+        if let Some(line) = self
+            .culprit_pc
+            .and_then(|pc| operations.get(pc))
+            .map(|op| op.line_num as usize)
+        {
+            let _ = writeln!(report, "after executing:");
+            let first = line.saturating_sub(2);
+            for (context, text) in source
+                .lines()
+                .enumerate()
+                .skip(first)
+                .take(line + 3 - first)
+            {
+                let marker = if context == line { "=>" } else { "  " };
+                let _ = writeln!(report, "{marker} {:4} | {}", context + 1, text);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, Assembly};
+    use crate::output::Hardware;
+
+    const KERNEL: &str = "\
+.threads 2
+CONST R1, #10
+ADD R2, R1, %threadIdx
+STR %threadIdx, R2
+CMP R2, R1
+RET
+";
+
+    fn simulator(assembly: &Assembly) -> Simulator {
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        Simulator::new(assembly.program(), &[], launch)
+    }
+
+    /// A CocoTB style log of `traces`, two cycles per instruction
+    fn log(traces: &ThreadTraces) -> String {
+        let mut log = String::new();
+        let rounds = traces.values().map(Vec::len).max().unwrap_or(0);
+        for round in 0..rounds {
+            for half in 0..2 {
+                let _ = writeln!(
+                    log,
+                    "=============== Cycle {} ===============",
+                    round * 2 + half
+                );
+                let _ = writeln!(log, "+-------- Core 0 --------+");
+                for (thread, visits) in traces {
+                    let Some(visit) = visits.get(round) else {
+                        continue;
+                    };
+                    let registers: Vec<String> = Register::ALL
+                        .iter()
+                        .map(|r| format!("{} = {}", r.name(), visit.registers[r.index()]))
+                        .collect();
+                    let _ = writeln!(log, "+-------- Thread {thread} --------+");
+                    let _ = writeln!(log, "PC:  {}", visit.pc);
+                    let _ = writeln!(log, "Registers:  {}", registers.join(", "));
+                }
+            }
+        }
+        log
+    }
+
+    #[test]
+    fn records_every_executed_instruction() {
+        let assembly = assemble(KERNEL);
+        let records = trace(&mut simulator(&assembly), &assembly.operations).unwrap();
+        assert_eq!(records.len(), 10);
+        // the threads take turns, one instruction per round
+        let order: Vec<(usize, usize)> = records.iter().map(|r| (r.thread, r.pc)).collect();
+        assert_eq!(order[..4], [(0, 0), (1, 0), (0, 1), (1, 1)]);
+
+        let store = &records[5];
+        assert_eq!(
+            (store.instruction.as_str(), store.line),
+            ("STR %threadIdx, R2", 4)
+        );
+        assert_eq!(
+            serde_json::to_string(store).unwrap(),
+            r#"{"step":5,"core":0,"block":0,"thread":1,"pc":2,"instruction":"STR %threadIdx, R2","line":4,"memory_writes":[{"addr":1,"value":11}]}"#
+        );
+        // thread 0 compares 10 with 10, thread 1 compares 11 with 10
+        assert_eq!(records[6].nzp.as_deref(), Some("-z-"));
+        assert_eq!(records[7].nzp.as_deref(), Some("--p"));
+    }
+
+    #[test]
+    fn a_log_of_the_same_run_does_not_diverge() {
+        let assembly = assemble(KERNEL);
+        let expected = simulated_visits(&mut simulator(&assembly)).unwrap();
+        let actual = parse_cocotb_log(&log(&expected));
+        // repeated cycles at one PC collapse into a single visit
+        assert_eq!(actual[&1].len(), 5);
+        assert_eq!(actual[&1][1].cycle, Some(3));
+        assert!(first_divergence(&expected, &actual).is_none());
+    }
+
+    #[test]
+    fn reports_the_instruction_before_the_first_difference() {
+        let assembly = assemble(KERNEL);
+        let expected = simulated_visits(&mut simulator(&assembly)).unwrap();
+        let mut wrong = expected.clone();
+        wrong.get_mut(&1).unwrap()[2].registers[Register::R2.index()] = 99;
+        wrong.get_mut(&0).unwrap().pop();
+
+        let divergence = first_divergence(&expected, &parse_cocotb_log(&log(&wrong))).unwrap();
+        assert_eq!((divergence.thread, divergence.index), (1, 2));
+        assert_eq!(divergence.cycle, Some(5));
+        assert_eq!(divergence.message, "at PC 2: R2 expected 11, log has 99");
+
+        let report = divergence.report(KERNEL, &assembly.operations);
+        assert!(
+            report.contains("=>    3 | ADD R2, R1, %threadIdx"),
+            "{report}"
+        );
+    }
+}
@@ -0,0 +1,537 @@
+use std::str::FromStr;
+
+use crate::immediate::parse_imm8;
+use crate::operation::Operation;
+use crate::{LexError, LineError, ParsedLine, Register};
+
+/// Loop Unrolling
+/// ---
+/// `.unroll N` on the line before a loop label replicates the loop body N times, so only every
+/// Nth iteration pays for the CMP and branch. The loop must have the shape
+///
+/// ```text
+/// CONST Ri, #init         ; before the loop, as are CONST Rs and CONST Rb
+/// .unroll 4
+/// LOOP:
+///     ...                 ; no labels, branches or RET, and no writes to Rs or Rb
+///     ADD Ri, Ri, Rs      ; the only write to Ri
+///     ...
+///     CMP Ri, Rb
+///     BRn LOOP
+/// ```
+///
+/// so the trip count is known when assembling. Iterations left over when N does not divide the
+/// trip count are peeled off in front of the loop, and a loop that runs N times or fewer is
+/// unrolled completely, without its label. Copies keep the source line number of the line they
+/// were copied from.
+///
+/// When nothing in the body but the ADD reads Ri, and nothing in the program but the ADD reads
+/// Rs, the copies share one `ADD Ri, Ri, Rs` after a `CONST Rs, #N*step` in front of the loop.
+/// Otherwise every copy keeps its own ADD: a body that addresses memory through Ri needs its
+/// value in each iteration, and with no register+offset addressing there is no other way to get
+/// it.
+pub fn unroll_loops(lines: &[ParsedLine]) -> Result<Vec<ParsedLine>, LineError> {
+    let mut output = Vec::with_capacity(lines.len());
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = &lines[index];
+        if line.tokens.first().map(|t| t.as_str()) != Some(".unroll") {
+            output.push(line.clone());
+            index += 1;
+            continue;
+        }
+
+        let (unrolled, end) = unroll(lines, index).map_err(|error| LineError {
+            line_num: line.line_num,
+            error,
+        })?;
+        output.extend(unrolled);
+        index = end;
+    }
+
+    Ok(output)
+}
+
+fn label_of(line: &ParsedLine) -> Option<&str> {
+    line.tokens
+        .first()
+        .and_then(|token| token.strip_suffix(':'))
+}
+
+/// The mnemonic and operands of a line, without its label
+fn instruction_of(line: &ParsedLine) -> &[String] {
+    match label_of(line) {
+        Some(_) => &line.tokens[1..],
+        None => &line.tokens,
+    }
+}
+
+fn is_code(line: &ParsedLine) -> bool {
+    !instruction_of(line).is_empty()
+}
+
+/// The registers an instruction reads
+fn read_registers(tokens: &[String]) -> Vec<Register> {
+    let skip = if written_register(tokens).is_some() {
+        2
+    } else {
+        1
+    };
+    tokens
+        .iter()
+        .skip(skip)
+        .filter_map(|token| Register::from_str(token).ok())
+        .collect()
+}
+
+/// The register an instruction writes
+fn written_register(tokens: &[String]) -> Option<Register> {
+    let op = Operation::from_str(tokens.first()?).ok()?;
+    match op {
+        Operation::ADD
+        | Operation::SUB
+        | Operation::MUL
+        | Operation::DIV
+        | Operation::LDR
+        | Operation::CONST => Register::from_str(tokens.get(1)?).ok(),
+        _ => None,
+    }
+}
+
+fn register_operand(tokens: &[String], position: usize) -> Result<Register, LexError> {
+    tokens
+        .get(position)
+        .ok_or_else(|| LexError::InvalidSyntax(format!("{} is missing operands", tokens[0])))
+        .and_then(|token| Register::from_str(token))
+}
+
+/// Unrolls the loop annotated by the `.unroll` at `start`, returning the lines replacing it and
+/// the index of the first line after the loop.
+fn unroll(lines: &[ParsedLine], start: usize) -> Result<(Vec<ParsedLine>, usize), LexError> {
+    let factor = match lines[start].tokens.get(1..) {
+        Some([factor]) => factor.parse::<usize>().ok().filter(|&n| n >= 1),
+        _ => None,
+    }
+    .ok_or_else(|| LexError::InvalidArgument(".unroll takes one factor of at least 1".into()))?;
+
+    let label_index = (start + 1..lines.len())
+        .find(|&i| !lines[i].tokens.is_empty())
+        .filter(|&i| label_of(&lines[i]).is_some())
+        .ok_or_else(|| {
+            LexError::InvalidSyntax(".unroll must be followed by a loop label".into())
+        })?;
+    let label = label_of(&lines[label_index]).unwrap().to_string();
+    let unsupported = |reason: String| LexError::UnsupportedLoop {
+        label: label.clone(),
+        reason,
+    };
+
+    // the branch closing the loop, and the CMP before it
+    let is_branch_to_label = |line: &ParsedLine| {
+        let tokens = instruction_of(line);
+        tokens.first().is_some_and(|t| t.starts_with("BR"))
+            && tokens.get(1).is_some_and(|target| *target == label)
+    };
+    let branch_index = (label_index..lines.len())
+        .find(|&i| is_branch_to_label(&lines[i]))
+        .ok_or_else(|| unsupported(format!("no branch back to {label} closes it")))?;
+    if instruction_of(&lines[branch_index])[0] != "BRn" {
+        return Err(unsupported("it must close with BRn".into()));
+    }
+    let cmp_index = (label_index..branch_index)
+        .rev()
+        .find(|&i| is_code(&lines[i]))
+        .filter(|&i| {
+            instruction_of(&lines[i])
+                .first()
+                .is_some_and(|t| t == "CMP")
+        })
+        .ok_or_else(|| unsupported("the branch must follow a CMP".into()))?;
+    let cmp = instruction_of(&lines[cmp_index]);
+    let (induction, bound) = (register_operand(cmp, 1)?, register_operand(cmp, 2)?);
+
+    if lines
+        .iter()
+        .enumerate()
+        .any(|(i, line)| i != branch_index && is_branch_to_label(line))
+    {
+        return Err(unsupported(format!("other branches jump to {label}")));
+    }
+
+    // the body, with an instruction sharing the label's line as its first line
+    let body_indices: Vec<usize> = (label_index..cmp_index)
+        .filter(|&i| i > label_index || is_code(&lines[i]))
+        .collect();
+    let body: Vec<ParsedLine> = body_indices
+        .iter()
+        .map(|&i| ParsedLine {
+            tokens: instruction_of(&lines[i]).to_vec(),
+            ..lines[i].clone()
+        })
+        .collect();
+
+    let mut step = None;
+    let mut increment_at = 0; // position of the ADD in the body
+    for (position, line) in body.iter().enumerate() {
+        let tokens = instruction_of(line);
+        let mnemonic = tokens.first().map_or("", |t| t.as_str());
+        if label_of(line).is_some() || mnemonic.starts_with("BR") || mnemonic == "RET" {
+            return Err(unsupported(format!(
+                "its body may not contain labels, branches or RET (line {})",
+                line.line_num + 1
+            )));
+        }
+
+        match written_register(tokens) {
+            Some(r) if r == induction => {
+                let operands = match mnemonic {
+                    "ADD" => Some((register_operand(tokens, 2)?, register_operand(tokens, 3)?)),
+                    _ => None,
+                };
+                let increment = match operands {
+                    Some((a, b)) if a == induction && b != induction => b,
+                    Some((a, b)) if b == induction && a != induction => a,
+                    _ => {
+                        return Err(unsupported(format!(
+                            "{} must only be written by ADD {0}, {0}, Rs",
+                            induction.name()
+                        )))
+                    }
+                };
+                increment_at = position;
+                if step.replace(increment).is_some() {
+                    return Err(unsupported(format!(
+                        "{} is incremented more than once",
+                        induction.name()
+                    )));
+                }
+            }
+            Some(r) if r == bound => {
+                return Err(unsupported(format!(
+                    "its bound {} is written in the loop",
+                    bound.name()
+                )))
+            }
+            _ => {}
+        }
+    }
+    let step = step.ok_or_else(|| {
+        unsupported(format!(
+            "{} is compared but never incremented",
+            induction.name()
+        ))
+    })?;
+    if body
+        .iter()
+        .any(|line| written_register(instruction_of(line)) == Some(step))
+    {
+        return Err(unsupported(format!(
+            "its step {} is written in the loop",
+            step.name()
+        )));
+    }
+
+    let known = |register: Register| {
+        constant_before(lines, start, label_index, register).ok_or_else(|| {
+            unsupported(format!(
+                "{} is not set by a CONST before the loop",
+                register.name()
+            ))
+        })
+    };
+    let (init, step_value, bound_value) = (known(induction)?, known(step)?, known(bound)?);
+
+    // run the loop counter like the ALU would: 8 bits, compare unsigned
+    let mut trips = 0;
+    let mut counter = init;
+    loop {
+        counter = counter.wrapping_add(step_value);
+        trips += 1;
+        if counter >= bound_value {
+            break;
+        }
+        if trips > 256 {
+            return Err(unsupported("it never terminates".into()));
+        }
+    }
+
+    let unrolled_trips = trips.min(factor);
+    let reads = |line: &ParsedLine, register: Register| {
+        read_registers(instruction_of(line)).contains(&register)
+    };
+    let shared_increment = unrolled_trips > 1
+        && body
+            .iter()
+            .enumerate()
+            .all(|(position, line)| position == increment_at || !reads(line, induction))
+        && lines
+            .iter()
+            .enumerate()
+            .all(|(i, line)| i == body_indices[increment_at] || !reads(line, step));
+
+    let copy = |first: bool, with_increment: bool| {
+        body.iter()
+            .enumerate()
+            .filter(move |(position, _)| with_increment || *position != increment_at)
+            .map(move |(_, line)| ParsedLine {
+                comment: if first { line.comment.clone() } else { None },
+                ..line.clone()
+            })
+    };
+    // a fully unrolled loop is no longer branched to, so only a comment on the label is left
+    let fully_unrolled = trips <= factor
+        && !lines.iter().enumerate().any(|(i, line)| {
+            i != label_index && i != branch_index && instruction_of(line).contains(&label)
+        });
+    let label_line = ParsedLine {
+        tokens: match fully_unrolled {
+            true => vec![],
+            false => vec![format!("{label}:")],
+        },
+        comment: if is_code(&lines[label_index]) {
+            None
+        } else {
+            lines[label_index].comment.clone()
+        },
+        line_num: lines[label_index].line_num,
+    };
+
+    let mut output = lines[start + 1..label_index].to_vec();
+    let mut copies = 0;
+    let remainder = if trips <= factor { 0 } else { trips % factor };
+    for _ in 0..remainder {
+        output.extend(copy(copies == 0, true));
+        copies += 1;
+    }
+    if shared_increment {
+        let scaled = step_value.wrapping_mul(unrolled_trips as u8);
+        output.push(ParsedLine {
+            tokens: vec!["CONST".into(), step.name().into(), format!("#{scaled}")],
+            comment: None,
+            line_num: lines[start].line_num,
+        });
+    }
+    output.push(label_line);
+    for _ in 0..unrolled_trips {
+        output.extend(copy(copies == 0, !shared_increment));
+        copies += 1;
+    }
+    if shared_increment {
+        output.push(body[increment_at].clone());
+    }
+    // keep the CMP so the flags after the loop are unchanged
+    output.extend(lines[cmp_index..branch_index].iter().cloned());
+    if trips > factor {
+        output.push(lines[branch_index].clone());
+    }
+
+    Ok((output, branch_index + 1))
+}
+
+/// The value `register` is known to hold when entering the loop at `label_index`: the CONST that
+/// last wrote it before the `.unroll` at `start`, if no label lies in between or it is the only
+/// write to the register in the program.
+fn constant_before(
+    lines: &[ParsedLine],
+    start: usize,
+    label_index: usize,
+    register: Register,
+) -> Option<u8> {
+    let writes = |line: &ParsedLine| written_register(instruction_of(line)) == Some(register);
+    let last_write = (0..start).rev().find(|&i| writes(&lines[i]))?;
+
+    let tokens = instruction_of(&lines[last_write]);
+    if tokens[0] != "CONST" {
+        return None;
+    }
+    let value = parse_imm8(tokens.get(2)?).ok()?;
+
+    let straight_line = lines[last_write + 1..label_index]
+        .iter()
+        .all(|line| label_of(line).is_none());
+    let only_write = lines.iter().filter(|line| writes(line)).count() == 1;
+    (straight_line || only_write).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lint::{lint_program, LintConfig};
+    use crate::output::Hardware;
+    use crate::parse_line;
+    use crate::simulator::Simulator;
+
+    const LOOP: &str = "\
+.threads 1
+CONST R0, #0      ; i
+CONST R1, #1      ; step
+CONST R2, #7      ; bound
+CONST R3, #10
+.unroll 3
+LOOP:             ; stores 10, 11, ...
+    STR R0, R3    ; store
+    ADD R3, R3, R1
+    ADD R0, R0, R1
+    CMP R0, R2
+    BRn LOOP
+RET
+";
+
+    fn unrolled(source: &str) -> Result<Vec<ParsedLine>, LineError> {
+        let lines: Vec<ParsedLine> = source
+            .lines()
+            .enumerate()
+            .map(|(line_num, line)| parse_line(line_num, line).unwrap())
+            .collect();
+        unroll_loops(&lines)
+    }
+
+    fn run(source: &str) -> (usize, Vec<u8>) {
+        let assembly = assemble(source);
+        assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let launch = assembly.launch(&Hardware::default()).unwrap();
+        let program = assembly.program();
+        let mut sim = Simulator::new(program.clone(), &[], launch);
+        sim.run().unwrap();
+        (program.len(), sim.memory)
+    }
+
+    #[test]
+    fn unrolled_loops_compute_the_same_memory() {
+        let (rolled_len, rolled) = run(&LOOP.replace(".unroll 3", ""));
+        assert_eq!(rolled[..8], [10, 11, 12, 13, 14, 15, 16, 0]);
+        for factor in [1, 2, 3, 7, 8] {
+            let (len, memory) = run(&LOOP.replace(".unroll 3", &format!(".unroll {factor}")));
+            assert_eq!(memory, rolled, ".unroll {factor}");
+            // 7 trips: peeled copies, then a loop of `factor` copies, or no loop at all
+            let copies = if factor >= 7 { 7 } else { 7 % factor + factor };
+            let closing = if factor >= 7 { 1 } else { 2 };
+            assert_eq!(
+                len,
+                rolled_len - 5 + 3 * copies + closing,
+                ".unroll {factor}"
+            );
+        }
+    }
+
+    #[test]
+    fn copies_keep_line_numbers_and_comment_once() {
+        let lines = unrolled(LOOP).unwrap();
+        let code: Vec<&ParsedLine> = lines.iter().filter(|l| !l.tokens.is_empty()).collect();
+        // CONSTs, one peeled copy, the label, three copies, CMP, BRn, RET
+        assert_eq!(code.len(), 5 + 3 + 1 + 9 + 3);
+        let stores: Vec<u32> = code
+            .iter()
+            .filter(|l| l.tokens[0] == "STR")
+            .map(|l| l.line_num)
+            .collect();
+        assert_eq!(stores, [7; 4]);
+        // the body stores R0, so each copy needs its own value of it
+        let increments = code
+            .iter()
+            .filter(|l| l.tokens == ["ADD", "R0", "R0", "R1"]);
+        assert_eq!(increments.count(), 4);
+        assert_eq!(code[8].tokens, ["LOOP:"]);
+        assert_eq!(code[8].comment.as_deref(), Some(" stores 10, 11, ..."));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|l| l.comment.as_deref() == Some(" store"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn rejects_loops_of_other_shapes() {
+        for (from, to, expected) in [
+            ("    STR R0, R3", "    BRz DONE", "labels, branches or RET"),
+            ("    BRn LOOP", "    BRz LOOP", "must close with BRn"),
+            ("    ADD R0, R0, R1", "    NOP", "never incremented"),
+            (
+                "    STR R0, R3",
+                "    ADD R0, R0, R1",
+                "incremented more than once",
+            ),
+            (
+                "    STR R0, R3",
+                "    MUL R0, R0, R1",
+                "only be written by ADD",
+            ),
+            ("    STR R0, R3", "    CONST R2, #9", "bound R2 is written"),
+            ("    STR R0, R3", "    CONST R1, #2", "step R1 is written"),
+            ("CONST R2, #7 ", "LDR R2, R1 ", "R2 is not set by a CONST"),
+            ("CONST R1, #1 ", "CONST R1, #0 ", "never terminates"),
+            ("    CMP R0, R2", "    NOP", "must follow a CMP"),
+            (".unroll 3", ".unroll 0", "at least 1"),
+        ] {
+            assert!(LOOP.contains(from), "{from}");
+            let err = unrolled(&LOOP.replacen(from, to, 1)).unwrap_err();
+            assert_eq!(err.line_num, 5, "{to}");
+            assert!(err.to_string().contains(expected), "{to}: {err}");
+        }
+    }
+
+    const COUNTER: &str = "\
+.threads 1
+CONST R0, #0
+CONST R1, #1
+CONST R2, #6
+CONST R3, #10
+CONST R4, #1
+.unroll 3
+LOOP:
+    STR R3, R3
+    ADD R3, R3, R4
+    ADD R0, R0, R1 ; count
+    CMP R0, R2
+    BRn LOOP
+RET
+";
+
+    #[test]
+    fn counters_share_one_scaled_increment() {
+        let lines = unrolled(COUNTER).unwrap();
+        let code: Vec<&[String]> = lines
+            .iter()
+            .map(|l| l.tokens.as_slice())
+            .filter(|tokens| !tokens.is_empty())
+            .collect();
+        assert_eq!(code[6], ["CONST", "R1", "#3"]);
+        assert_eq!(code[7], ["LOOP:"]);
+        assert_eq!(code[14], ["ADD", "R0", "R0", "R1"]);
+        assert_eq!(code.iter().filter(|t| t[0] == "ADD").count(), 4);
+
+        let (_, rolled) = run(&COUNTER.replace(".unroll 3", ""));
+        for factor in [2, 3, 4, 6] {
+            let (_, memory) = run(&COUNTER.replace(".unroll 3", &format!(".unroll {factor}")));
+            assert_eq!(memory, rolled, ".unroll {factor}");
+        }
+
+        // another reader of the step keeps the increments apart
+        let shared = COUNTER.replace("ADD R3, R3, R4", "ADD R3, R3, R1");
+        let lines = unrolled(&shared).unwrap();
+        assert!(lines.iter().all(|l| l.tokens != ["CONST", "R1", "#3"]));
+    }
+
+    #[test]
+    fn fully_unrolled_loops_drop_their_label() {
+        let lines = unrolled(&LOOP.replace(".unroll 3", ".unroll 7")).unwrap();
+        assert!(lines
+            .iter()
+            .all(|l| l.tokens.first().map(String::as_str) != Some("LOOP:")));
+        // its comment stays on its line
+        let head = lines.iter().find(|l| l.line_num == 6).unwrap();
+        assert_eq!(head.comment.as_deref(), Some(" stores 10, 11, ..."));
+
+        let assembly = assemble(&COUNTER.replace(".unroll 3", ".unroll 6"));
+        let lints = lint_program(
+            &assembly.source_lines,
+            &assembly.operations,
+            &LintConfig::default(),
+        );
+        assert!(lints.is_empty(), "{lints:?}");
+    }
+}
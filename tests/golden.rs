@@ -0,0 +1,83 @@
+//! Assembles every kernel in `asm_src/` and compares its output JSON with the golden file of the
+//! same name in `tests/golden/`. After an intended change to the output, regenerate the goldens
+//! with `UPDATE_GOLDENS=1 cargo test --test golden` and review the diff.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lib::assembler::assemble;
+use lib::output::build_output;
+
+fn kernels() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("asm_src");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// The output JSON of a kernel, as the assembler writes it
+fn output_json(path: &Path) -> String {
+    let contents = fs::read_to_string(path).unwrap();
+    let assembly = assemble(&contents);
+    assert!(
+        assembly.errors.is_empty(),
+        "{} does not assemble: {:?}",
+        path.display(),
+        assembly.errors
+    );
+    let testname = path.file_stem().unwrap().to_str().unwrap();
+    let output = build_output(testname, &assembly).unwrap();
+    serde_json::to_string_pretty(&output).unwrap() + "\n"
+}
+
+#[test]
+fn asm_src_matches_goldens() {
+    let update = std::env::var_os("UPDATE_GOLDENS").is_some();
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+
+    let mut mismatches = vec![];
+    for path in kernels() {
+        let actual = output_json(&path);
+        let golden_path = golden_dir.join(path.with_extension("json").file_name().unwrap());
+
+        if update {
+            fs::write(&golden_path, &actual).unwrap();
+            continue;
+        }
+        let Ok(expected) = fs::read_to_string(&golden_path) else {
+            mismatches.push(format!("{}: no golden file", golden_path.display()));
+            continue;
+        };
+        if let Some((line, (expected, actual))) = expected
+            .lines()
+            .zip(actual.lines())
+            .enumerate()
+            .find(|(_, (expected, actual))| expected != actual)
+        {
+            mismatches.push(format!(
+                "{}: line {}: expected `{}`, assembled `{}`",
+                golden_path.display(),
+                line + 1,
+                expected.trim(),
+                actual.trim()
+            ));
+        } else if expected.lines().count() != actual.lines().count() {
+            mismatches.push(format!(
+                "{}: expected {} lines, assembled {}",
+                golden_path.display(),
+                expected.lines().count(),
+                actual.lines().count()
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "output differs from the goldens (run with UPDATE_GOLDENS=1 if the change is intended):\n{}",
+        mismatches.join("\n")
+    );
+}
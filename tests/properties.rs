@@ -0,0 +1,219 @@
+//! Property tests of the assembler on generated sources: malformed input must come back as
+//! errors rather than panics, and valid programs must decode back to what was written.
+
+use proptest::prelude::*;
+
+use lib::assembler::assemble;
+use lib::format::format_source;
+use lib::instruction::Instruction;
+use lib::lint::{lint_program, LintConfig};
+use lib::output::build_output;
+use lib::Register;
+
+/// Fragments source lines are built from, valid and not
+const FRAGMENTS: &[&str] = &[
+    "NOP",
+    "BRnzp",
+    "BRn",
+    "BRz",
+    "BRp",
+    "BR",
+    "BRx",
+    "CMP",
+    "ADD",
+    "SUB",
+    "MUL",
+    "DIV",
+    "LDR",
+    "STR",
+    "CONST",
+    "RET",
+    "LI",
+    "R0",
+    "R7",
+    "R12",
+    "R13",
+    "R16",
+    "%blockIdx",
+    "%blockDim",
+    "%threadIdx",
+    "%v0",
+    "%acc",
+    "%",
+    "#0",
+    "#255",
+    "#256",
+    "#-128",
+    "#-129",
+    "#0x",
+    "#0xFF",
+    "#0b",
+    "#'A'",
+    "#'",
+    "'",
+    "#",
+    "LOOP",
+    "LOOP:",
+    ".x",
+    ".x:",
+    "1:",
+    "1b",
+    "1f",
+    "2f",
+    ":",
+    ".data",
+    ".threads",
+    ".blocks",
+    ".block_dim",
+    ".cores",
+    ".scratch",
+    ".register_width",
+    ".unroll",
+    ".global",
+    ".define",
+    ".if",
+    ".elif",
+    ".else",
+    ".endif",
+    ".ifdef",
+    ".ifndef",
+    ".bogus",
+    "N",
+    "N==1",
+    "(",
+    ")",
+    "&&",
+    "!",
+    "0",
+    "1",
+    "4",
+    "255",
+    "65536",
+    "-1",
+    "x",
+    ",",
+    ",,",
+    ";",
+    "; comment",
+    "\t",
+    "é",
+    "\\",
+];
+
+fn fragment() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => prop::sample::select(FRAGMENTS).prop_map(String::from),
+        1 => "\\PC{0,6}",
+    ]
+}
+
+fn source() -> impl Strategy<Value = String> {
+    let line = prop::collection::vec(fragment(), 0..6).prop_map(|tokens| tokens.join(" "));
+    prop::collection::vec(line, 0..24).prop_map(|lines| lines.join("\n"))
+}
+
+fn register() -> impl Strategy<Value = Register> {
+    prop::sample::select(Register::ALL.to_vec())
+}
+
+/// Any instruction, branching to an address below `len`
+fn instruction(len: u8) -> impl Strategy<Value = Instruction> {
+    let writable = || register().prop_filter("writable", |r| !r.is_special());
+    prop_oneof![
+        Just(Instruction::Nop),
+        (1u8..8, 0..len).prop_map(|(nzp, target)| Instruction::Branch { nzp, target }),
+        (register(), register()).prop_map(|(rs, rt)| Instruction::Cmp { rs, rt }),
+        (writable(), register(), register()).prop_map(|(rd, rs, rt)| Instruction::Add {
+            rd,
+            rs,
+            rt
+        }),
+        (writable(), register(), register()).prop_map(|(rd, rs, rt)| Instruction::Sub {
+            rd,
+            rs,
+            rt
+        }),
+        (writable(), register(), register()).prop_map(|(rd, rs, rt)| Instruction::Mul {
+            rd,
+            rs,
+            rt
+        }),
+        (writable(), register(), register()).prop_map(|(rd, rs, rt)| Instruction::Div {
+            rd,
+            rs,
+            rt
+        }),
+        (writable(), register()).prop_map(|(rd, rs)| Instruction::Ldr { rd, rs }),
+        (register(), register()).prop_map(|(rs, rt)| Instruction::Str { rs, rt }),
+        (writable(), any::<u8>()).prop_map(|(rd, imm)| Instruction::Const { rd, imm }),
+        Just(Instruction::Ret),
+    ]
+}
+
+fn program() -> impl Strategy<Value = Vec<Instruction>> {
+    (1u8..=255).prop_flat_map(|len| prop::collection::vec(instruction(len), len as usize))
+}
+
+/// Writes a program out as source, with a label `A<address>` on every instruction for the
+/// branches to jump to
+fn source_of(program: &[Instruction]) -> String {
+    program
+        .iter()
+        .enumerate()
+        .map(|(address, instruction)| {
+            let text = match *instruction {
+                Instruction::Branch { nzp, target } => {
+                    let flags: String = [(0b100, 'n'), (0b010, 'z'), (0b001, 'p')]
+                        .iter()
+                        .filter(|(bit, _)| nzp & bit != 0)
+                        .map(|(_, flag)| *flag)
+                        .collect();
+                    format!("BR{flags} A{target}")
+                }
+                ref other => other.to_string(),
+            };
+            format!("A{address}: {text}\n")
+        })
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn malformed_sources_do_not_panic(source in source()) {
+        let assembly = assemble(&source);
+        let _ = lint_program(&assembly.source_lines, &assembly.operations, &LintConfig::default());
+        let _ = build_output("fuzz", &assembly);
+        let _ = format_source(&source);
+    }
+
+    #[test]
+    fn arbitrary_text_does_not_panic(source in "\\PC{0,200}") {
+        let assembly = assemble(&source);
+        let _ = build_output("fuzz", &assembly);
+        let _ = format_source(&source);
+    }
+
+    #[test]
+    fn long_programs_do_not_panic(padding in 200usize..320, data in 200usize..320) {
+        // branch targets and data labels past what 8 bits address
+        let source = format!(
+            "CONST R0, D\nBRnzp END\n{}END:\nRET\n.data {}\nD: .data 1\n",
+            "NOP\n".repeat(padding),
+            "1 ".repeat(data)
+        );
+        let assembly = assemble(&source);
+        let _ = build_output("fuzz", &assembly);
+    }
+
+    #[test]
+    fn valid_programs_decode_to_their_source(program in program()) {
+        let assembly = assemble(&source_of(&program));
+        prop_assert!(assembly.errors.is_empty(), "{:?}", assembly.errors);
+        let decoded: Vec<Option<Instruction>> =
+            assembly.program().into_iter().map(Instruction::decode).collect();
+        let expected: Vec<Option<Instruction>> = program.into_iter().map(Some).collect();
+        prop_assert_eq!(decoded, expected);
+    }
+}